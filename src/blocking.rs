@@ -0,0 +1,182 @@
+use crate::components::clear_core_io::{AnalogInput, DigitalInput, HBridge, Output};
+use crate::components::clear_core_motor::{ClearCoreMotor, MotorAlert, Status};
+use crate::components::scale::Scale;
+use crate::controllers::clear_core::{Controller, Message};
+use crate::interface::tcp::client;
+use crate::subsystems::node::{DispenseReport, DispensingParameters, Node};
+use crate::util::units::{Revolutions, RevsPerSec};
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::ToSocketAddrs;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{self, Sender};
+
+pub struct Blocking<T> {
+    inner: T,
+    runtime: Arc<Runtime>,
+}
+
+impl<T> Blocking<T> {
+    pub fn run<'a, F: Future>(&'a self, f: impl FnOnce(&'a T) -> F) -> F::Output {
+        self.runtime.block_on(f(&self.inner))
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+pub struct BlockingController {
+    sender: Sender<Message>,
+    runtime: Arc<Runtime>,
+}
+
+impl BlockingController {
+    pub fn connect<A: ToSocketAddrs + Send + 'static>(addr: A) -> Result<Self, Box<dyn Error>> {
+        // The client task runs on the runtime's worker thread while callers block on replies
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let (sender, rx) = mpsc::channel(100);
+        runtime.spawn(client(addr, rx));
+        Ok(Self {
+            sender,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    fn wrap<T>(&self, inner: T) -> Blocking<T> {
+        Blocking {
+            inner,
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    pub fn raw_command(&self, command: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let controller = Controller::new(self.sender.clone());
+        self.runtime.block_on(controller.raw_command(command))
+    }
+
+    pub fn motor(&self, id: u8, scale: isize) -> Blocking<ClearCoreMotor> {
+        self.wrap(ClearCoreMotor::new(id, scale, self.sender.clone()))
+    }
+
+    pub fn digital_input(&self, id: u8) -> Blocking<DigitalInput> {
+        self.wrap(DigitalInput::new(id, self.sender.clone()))
+    }
+
+    pub fn analog_input(&self, id: u8) -> Blocking<AnalogInput> {
+        self.wrap(AnalogInput::new(id, self.sender.clone()))
+    }
+
+    pub fn output(&self, id: u8) -> Blocking<Output> {
+        self.wrap(Output::new(id, self.sender.clone()))
+    }
+
+    pub fn h_bridge(&self, id: u8, power: i16) -> Blocking<HBridge> {
+        self.wrap(HBridge::new(id, power, self.sender.clone()))
+    }
+
+    pub fn node(&self, motor_id: u8, scale: isize) -> Blocking<Node> {
+        self.wrap(Node::new(ClearCoreMotor::new(
+            motor_id,
+            scale,
+            self.sender.clone(),
+        )))
+    }
+}
+
+impl Blocking<ClearCoreMotor> {
+    pub fn enable(&self) -> Result<(), Box<dyn Error>> {
+        self.run(|motor| async move { motor.enable().await.map(|_| ()) })
+    }
+
+    pub fn disable(&self) -> Result<(), Box<dyn Error>> {
+        self.run(|motor| motor.disable())
+    }
+
+    pub fn absolute_move(&self, position: Revolutions) -> Result<(), Box<dyn Error>> {
+        self.run(|motor| motor.absolute_move(position))
+    }
+
+    pub fn relative_move(&self, position: Revolutions) -> Result<(), Box<dyn Error>> {
+        self.run(|motor| motor.relative_move(position))
+    }
+
+    pub fn absolute_move_with_velocity(
+        &self,
+        position: Revolutions,
+        velocity: RevsPerSec,
+    ) -> Result<(), Box<dyn Error>> {
+        self.run(|motor| motor.absolute_move_with_velocity(position, velocity))
+    }
+
+    pub fn relative_move_with_velocity(
+        &self,
+        position: Revolutions,
+        velocity: RevsPerSec,
+    ) -> Result<(), Box<dyn Error>> {
+        self.run(|motor| motor.relative_move_with_velocity(position, velocity))
+    }
+
+    pub fn set_velocity(&self, velocity: RevsPerSec) -> Result<(), Box<dyn Error>> {
+        self.run(|motor| motor.set_velocity(velocity))
+    }
+
+    pub fn get_velocity(&self) -> Result<RevsPerSec, Box<dyn Error>> {
+        self.run(|motor| motor.get_velocity())
+    }
+
+    pub fn get_torque(&self) -> Result<f64, Box<dyn Error>> {
+        self.run(|motor| motor.get_torque())
+    }
+
+    pub fn get_status(&self) -> Result<Status, Box<dyn Error>> {
+        self.run(|motor| motor.get_status())
+    }
+
+    pub fn get_position(&self) -> Result<Revolutions, Box<dyn Error>> {
+        self.run(|motor| motor.get_position())
+    }
+
+    pub fn get_alerts(&self) -> Result<Vec<MotorAlert>, Box<dyn Error>> {
+        self.run(|motor| motor.get_alerts())
+    }
+
+    pub fn recover(&self) -> Result<Vec<MotorAlert>, Box<dyn Error>> {
+        self.run(|motor| motor.recover())
+    }
+
+    pub fn wait_for_move(&self, sampling_rate: Duration) -> Result<(), Box<dyn Error>> {
+        self.run(|motor| motor.wait_for_move(sampling_rate))
+    }
+
+    pub fn wait_for_move_with_timeout(
+        &self,
+        sampling_rate: Duration,
+        timeout: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        self.run(|motor| motor.wait_for_move_with_timeout(sampling_rate, timeout))
+    }
+}
+
+impl Blocking<Node> {
+    pub fn dispense(
+        &self,
+        scale: Scale,
+        parameters: DispensingParameters,
+    ) -> (Scale, DispenseReport) {
+        self.run(|node| node.dispense(scale, parameters))
+    }
+
+    pub fn timed_dispense(
+        &self,
+        scale: Scale,
+        parameters: DispensingParameters,
+    ) -> (Scale, DispenseReport) {
+        self.run(|node| node.timed_dispense(scale, parameters))
+    }
+}
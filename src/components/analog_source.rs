@@ -0,0 +1,6 @@
+use std::error::Error;
+use std::future::Future;
+
+pub trait AnalogSource {
+    fn get_value(&self) -> impl Future<Output = Result<isize, Box<dyn Error>>> + Send;
+}
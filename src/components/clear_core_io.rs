@@ -1,25 +1,67 @@
+use crate::components::analog_source::AnalogSource;
+use crate::components::dry_cycle::DryCycle;
 use crate::components::send_recv::SendRecv;
-use crate::controllers::clear_core::{Message, CR, STX};
-use crate::util::utils::{ascii_to_int, int_to_byte, num_to_bytes};
+use crate::controllers::clear_core::{
+    self, FailsafeState, Failsafes, InputEvents, Message, CR, STX,
+};
+use crate::util::utils::{int_to_byte, make_ccio_prefix, num_to_bytes};
+use serde::Deserialize;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, watch};
 
 pub const CLEAR_CORE_H_BRIDGE_MAX: i16 = 32760;
+pub const CLEAR_CORE_ANALOG_OUT_MAX: u16 = 2047;
+pub const CCIO_MAX_BOARDS: u8 = 8;
+pub const CCIO_PINS_PER_BOARD: u8 = 8;
+
+fn ccio_prefix(board: u8, pin: u8) -> [u8; 4] {
+    assert!(board < CCIO_MAX_BOARDS, "CCIO-8 board {board} out of range");
+    assert!(pin < CCIO_PINS_PER_BOARD, "CCIO-8 pin {pin} out of range");
+    make_ccio_prefix(board, pin)
+}
 
 pub struct DigitalInput {
-    cmd: [u8; 4],
+    prefix: Vec<u8>,
     drive_sender: Sender<Message>,
 }
 
 impl DigitalInput {
     pub fn new(id: u8, drive_sender: Sender<Message>) -> Self {
-        let cmd = [STX, b'I', int_to_byte(id), CR];
-        Self { cmd, drive_sender }
+        let prefix = vec![STX, b'I', int_to_byte(id)];
+        Self {
+            prefix,
+            drive_sender,
+        }
+    }
+
+    pub fn ccio(board: u8, pin: u8, drive_sender: Sender<Message>) -> Self {
+        let prefix = ccio_prefix(board, pin).to_vec();
+        Self {
+            prefix,
+            drive_sender,
+        }
     }
 
     pub async fn get_state(&self) -> Result<bool, Box<dyn Error>> {
-        let res = self.write(self.cmd.as_slice()).await?;
-        Ok(ascii_to_int(&res[3..]) == 1)
+        self.request(self.prefix.as_slice(), &[]).await
+    }
+
+    // Switches the input to pushed change notifications instead of polling. The receiver is
+    // kept current by a client started with `client_with_events`; needs firmware that
+    // supports notifications
+    pub async fn changes(
+        &self,
+        events: &InputEvents,
+    ) -> Result<watch::Receiver<bool>, Box<dyn Error>> {
+        let changes = events.subscribe(self.prefix.as_slice(), false);
+        self.request::<()>(self.prefix.as_slice(), b"NT").await?;
+        let state = self.get_state().await?;
+        events.subscribe(self.prefix.as_slice(), state);
+        Ok(changes)
     }
 }
 
@@ -30,19 +72,21 @@ impl SendRecv for DigitalInput {
 }
 
 pub struct AnalogInput {
-    cmd: [u8; 4],
+    prefix: [u8; 3],
     drive_sender: Sender<Message>,
 }
 
 impl AnalogInput {
     pub fn new(id: u8, drive_sender: Sender<Message>) -> Self {
-        let cmd = [STX, b'I', int_to_byte(id), CR];
-        Self { cmd, drive_sender }
+        let prefix = [STX, b'I', int_to_byte(id)];
+        Self {
+            prefix,
+            drive_sender,
+        }
     }
 
     pub async fn get_state(&self) -> Result<isize, Box<dyn Error>> {
-        let res = self.write(self.cmd.as_slice()).await?;
-        Ok(ascii_to_int(&res[3..]))
+        self.request(self.prefix.as_slice(), &[]).await
     }
 }
 
@@ -52,38 +96,177 @@ impl SendRecv for AnalogInput {
     }
 }
 
+impl AnalogSource for AnalogInput {
+    async fn get_value(&self) -> Result<isize, Box<dyn Error>> {
+        self.get_state().await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OutputState {
     Off,
     On,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum InterlockMode {
+    // Turning one output on turns the others in the group off first
+    #[default]
+    BreakBeforeMake,
+    // Turning one output on fails while another in the group is on
+    Reject,
+}
+
+// Outputs that must never be on together, e.g. the open and close solenoids of a valve or
+// forward and reverse contactors. Clones share the group's state
+#[derive(Clone)]
+pub struct InterlockGroup {
+    name: String,
+    mode: InterlockMode,
+    // Members last commanded on; held across a switch so two members can't race each other
+    on: Arc<tokio::sync::Mutex<Vec<Output>>>,
+}
+
+impl InterlockGroup {
+    pub fn new(name: &str, mode: InterlockMode) -> Self {
+        Self {
+            name: name.to_string(),
+            mode,
+            on: Arc::default(),
+        }
+    }
+}
+
 pub struct Output {
-    on_cmd: [u8; 9],
-    off_cmd: [u8; 9],
+    prefix: Vec<u8>,
+    verify: bool,
     drive_sender: Sender<Message>,
+    interlock: Option<InterlockGroup>,
+    dry_cycle: Option<DryCycle>,
 }
 
 impl Output {
     pub fn new(id: u8, drive_sender: Sender<Message>) -> Self {
-        let on_cmd = [STX, b'O', int_to_byte(id), b'3', b'2', b'7', b'0', b'0', CR];
-        let off_cmd = [STX, b'O', int_to_byte(id), b'0', CR, 0, 0, 0, 0];
+        Self::from_prefix(&[STX, b'O', int_to_byte(id)], drive_sender)
+    }
+
+    pub fn ccio(board: u8, pin: u8, drive_sender: Sender<Message>) -> Self {
+        Self::from_prefix(&ccio_prefix(board, pin), drive_sender)
+    }
+
+    fn from_prefix(prefix: &[u8], drive_sender: Sender<Message>) -> Self {
         Self {
-            on_cmd,
-            off_cmd,
+            prefix: prefix.to_vec(),
+            verify: false,
             drive_sender,
+            interlock: None,
+            dry_cycle: None,
         }
     }
 
-    fn command_builder(&self, state: OutputState) -> [u8; 9] {
+    pub fn with_readback(mut self) -> Self {
+        self.verify = true;
+        self
+    }
+
+    // Only changes made through outputs in the group are tracked
+    pub fn with_interlock(mut self, group: &InterlockGroup) -> Self {
+        self.interlock = Some(group.clone());
+        self
+    }
+
+    // For heaters: the output is held off while the dry cycle runs
+    pub fn with_dry_cycle(mut self, dry_cycle: DryCycle) -> Self {
+        self.dry_cycle = Some(dry_cycle);
+        self
+    }
+
+    fn is_same(&self, other: &Output) -> bool {
+        self.prefix == other.prefix && self.drive_sender.same_channel(&other.drive_sender)
+    }
+
+    pub fn with_failsafe(self, failsafes: &Failsafes, state: FailsafeState) -> Self {
+        failsafes.register(self.prefix.as_slice(), state);
+        self
+    }
+
+    pub(crate) fn off_command(&self) -> Vec<u8> {
+        let mut cmd = self.prefix.clone();
+        cmd.extend_from_slice(self.command_builder(OutputState::Off));
+        cmd.push(CR);
+        cmd
+    }
+
+    fn command_builder(&self, state: OutputState) -> &'static [u8] {
         match state {
-            OutputState::Off => self.off_cmd,
-            OutputState::On => self.on_cmd,
+            OutputState::Off => b"0",
+            OutputState::On => b"32700",
         }
     }
 
     pub async fn set_state(&self, state: OutputState) -> Result<isize, Box<dyn Error>> {
-        let res = self.write(self.command_builder(state).as_slice()).await?;
-        Ok(ascii_to_int(&res[3..]))
+        let state = match &self.dry_cycle {
+            Some(dry_cycle) if dry_cycle.is_running() => OutputState::Off,
+            _ => state,
+        };
+        let Some(group) = &self.interlock else {
+            return self.write_state(state).await;
+        };
+        let mut on = group.on.lock().await;
+        if state == OutputState::On {
+            if let Some(other) = on.iter().find(|other| !self.is_same(other)) {
+                if group.mode == InterlockMode::Reject {
+                    return Err(Box::new(clear_core::Error::Interlocked {
+                        device: clear_core::Device::from_prefix(self.prefix.as_slice()),
+                        other: clear_core::Device::from_prefix(other.prefix.as_slice()),
+                        group: group.name.clone(),
+                    }));
+                }
+            }
+            for other in on.iter().filter(|other| !self.is_same(other)) {
+                other.write_state(OutputState::Off).await?;
+            }
+            on.retain(|other| self.is_same(other));
+        }
+        let result = self.write_state(state).await?;
+        on.retain(|other| !self.is_same(other));
+        if state == OutputState::On {
+            on.push(Output::from_prefix(
+                self.prefix.as_slice(),
+                self.drive_sender.clone(),
+            ));
+        }
+        Ok(result)
+    }
+
+    async fn write_state(&self, state: OutputState) -> Result<isize, Box<dyn Error>> {
+        let expected = match state {
+            OutputState::Off => 0,
+            OutputState::On => 32700,
+        };
+        let res = self
+            .request(self.prefix.as_slice(), self.command_builder(state))
+            .await?;
+        if self.verify {
+            verify_readback(self.prefix.as_slice(), expected, self.get_state().await?)?;
+        }
+        Ok(res)
+    }
+
+    pub async fn get_state(&self) -> Result<isize, Box<dyn Error>> {
+        self.request(self.prefix.as_slice(), b"GS").await
+    }
+}
+
+fn verify_readback(prefix: &[u8], expected: isize, actual: isize) -> Result<(), clear_core::Error> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(clear_core::Error::VerificationFailed {
+            device: clear_core::Device::from_prefix(prefix),
+            expected,
+            actual,
+        })
     }
 }
 
@@ -93,16 +276,136 @@ impl SendRecv for Output {
     }
 }
 
-#[derive(Debug)]
+pub struct AnalogOutput {
+    prefix: [u8; 3],
+    drive_sender: Sender<Message>,
+}
+
+impl AnalogOutput {
+    pub fn new(id: u8, drive_sender: Sender<Message>) -> Self {
+        let prefix = [STX, b'A', int_to_byte(id)];
+        Self {
+            prefix,
+            drive_sender,
+        }
+    }
+
+    pub fn with_failsafe(self, failsafes: &Failsafes, state: FailsafeState) -> Self {
+        let state = match state {
+            FailsafeState::Value(value) => {
+                FailsafeState::Value(value.clamp(0, CLEAR_CORE_ANALOG_OUT_MAX as i16))
+            }
+            state => state,
+        };
+        failsafes.register(self.prefix.as_slice(), state);
+        self
+    }
+
+    pub async fn set_value(&self, value: u16) -> Result<isize, Box<dyn Error>> {
+        let value = num_to_bytes(value.min(CLEAR_CORE_ANALOG_OUT_MAX));
+        self.request(self.prefix.as_slice(), value.as_slice()).await
+    }
+
+    pub async fn set_fraction(&self, fraction: f64) -> Result<isize, Box<dyn Error>> {
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(Box::from("Analog output fraction must be between 0 and 1"));
+        }
+        let value = (fraction * CLEAR_CORE_ANALOG_OUT_MAX as f64).round() as u16;
+        self.set_value(value).await
+    }
+}
+
+impl SendRecv for AnalogOutput {
+    fn get_sender(&self) -> &Sender<Message> {
+        &self.drive_sender
+    }
+}
+
+// Switches an actuator off if it stays energized longer than max_on, e.g. because the caller
+// crashed between On and Off
+#[derive(Clone)]
+pub struct Watchdog {
+    max_on: Duration,
+    generation: Arc<AtomicU64>,
+    tripped: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    pub fn new(max_on: Duration) -> Self {
+        Self {
+            max_on,
+            generation: Arc::new(AtomicU64::new(0)),
+            tripped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn tripped(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn disarm(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn arm(&self, sender: Sender<Message>, off_commands: Vec<Vec<u8>>) {
+        let armed = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let (max_on, generation, tripped) =
+            (self.max_on, self.generation.clone(), self.tripped.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(max_on).await;
+            // Any later command, including Off, moves the generation on and cancels this one
+            if generation.load(Ordering::SeqCst) != armed {
+                return;
+            }
+            tripped.store(true, Ordering::SeqCst);
+            let device = clear_core::Device::from_prefix(off_commands[0].as_slice());
+            println!("WARNING: {device} energized longer than {max_on:?}, switching off");
+            for buffer in off_commands {
+                let (response, reply) = oneshot::channel();
+                if sender.send(Message { buffer, response }).await.is_err() {
+                    return;
+                }
+                let _ = reply.await;
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum HBridgeState {
     Pos,
     Neg,
     Off,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Ramp {
+    pub duration: Duration,
+    pub steps: u16,
+}
+
+impl Ramp {
+    pub fn new(duration: Duration, steps: u16) -> Self {
+        Self {
+            duration,
+            steps: steps.max(1),
+        }
+    }
+
+    fn levels(&self, target: i16) -> Vec<i16> {
+        let steps = self.steps.max(1) as i32;
+        (1..=steps)
+            .map(|step| (target as i32 * step / steps) as i16)
+            .collect()
+    }
+}
+
 pub struct HBridge {
     power: i16,
     prefix: [u8; 3],
+    ramp: Option<Ramp>,
+    verify: bool,
+    watchdog: Option<Watchdog>,
     drive_sender: Sender<Message>,
 }
 
@@ -112,27 +415,89 @@ impl HBridge {
         Self {
             power,
             prefix,
+            ramp: None,
+            verify: false,
+            watchdog: None,
             drive_sender,
         }
     }
 
-    fn command_builder(&self, state: HBridgeState) -> Vec<u8> {
-        let state = match state {
-            HBridgeState::Pos => num_to_bytes(self.power),
-            HBridgeState::Neg => num_to_bytes(-self.power),
-            HBridgeState::Off => num_to_bytes(0),
-        };
-        let mut cmd: Vec<u8> = Vec::with_capacity(self.prefix.len() + state.len() + 1);
-        cmd.extend_from_slice(self.prefix.as_slice());
-        cmd.extend_from_slice(state.as_slice());
-        cmd.push(13);
-        cmd
+    pub fn with_readback(mut self) -> Self {
+        self.verify = true;
+        self
+    }
+
+    pub fn with_ramp(mut self, ramp: Ramp) -> Self {
+        self.ramp = Some(ramp);
+        self
+    }
+
+    pub fn with_watchdog(mut self, max_on: Duration) -> Self {
+        self.watchdog = Some(Watchdog::new(max_on));
+        self
+    }
+
+    pub fn watchdog_tripped(&self) -> bool {
+        self.watchdog.as_ref().is_some_and(Watchdog::tripped)
+    }
+
+    pub fn with_failsafe(self, failsafes: &Failsafes, state: FailsafeState) -> Self {
+        failsafes.register(self.prefix.as_slice(), state);
+        self
+    }
+
+    async fn set_power(&self, power: i16) -> Result<(), Box<dyn Error>> {
+        self.request(self.prefix.as_slice(), num_to_bytes(power).as_slice())
+            .await
     }
 
     pub async fn set_state(&self, state: HBridgeState) -> Result<(), Box<dyn Error>> {
-        self.write(self.command_builder(state).as_slice()).await?;
+        let target = match state {
+            HBridgeState::Pos => self.power,
+            HBridgeState::Neg => -self.power,
+            HBridgeState::Off => 0,
+        };
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.disarm();
+        }
+        match self.ramp {
+            // Stepping up from 0 keeps the in-rush current below the actuator's protection limit
+            Some(ramp) if target != 0 => {
+                let delay = ramp.duration / ramp.steps.max(1) as u32;
+                for (step, power) in ramp.levels(target).into_iter().enumerate() {
+                    if step > 0 {
+                        tokio::time::sleep(delay).await;
+                    }
+                    self.set_power(power).await?;
+                }
+            }
+            _ => {
+                self.set_power(target).await?;
+            }
+        }
+        if self.verify {
+            verify_readback(
+                self.prefix.as_slice(),
+                target as isize,
+                self.get_state().await?,
+            )?;
+        }
+        if let Some(watchdog) = self.watchdog.as_ref().filter(|_| target != 0) {
+            watchdog.arm(self.drive_sender.clone(), vec![self.off_command()]);
+        }
         Ok(())
     }
+
+    pub async fn get_state(&self) -> Result<isize, Box<dyn Error>> {
+        self.request(self.prefix.as_slice(), b"GS").await
+    }
+
+    pub(crate) fn off_command(&self) -> Vec<u8> {
+        let mut cmd = self.prefix.to_vec();
+        cmd.extend_from_slice(b"0");
+        cmd.push(CR);
+        cmd
+    }
 }
 
 impl SendRecv for HBridge {
@@ -140,3 +505,53 @@ impl SendRecv for HBridge {
         &self.drive_sender
     }
 }
+
+#[test]
+fn test_verify_readback() {
+    let prefix = [STX, b'O', int_to_byte(2)];
+    assert!(verify_readback(&prefix, 32700, 32700).is_ok());
+    assert_eq!(
+        verify_readback(&prefix, 32700, 0),
+        Err(clear_core::Error::VerificationFailed {
+            device: clear_core::Device {
+                kind: clear_core::DeviceKind::Output,
+                id: "2".to_string()
+            },
+            expected: 32700,
+            actual: 0
+        })
+    );
+}
+
+#[test]
+fn test_ramp_levels() {
+    let ramp = Ramp::new(Duration::from_millis(400), 4);
+    assert_eq!(ramp.levels(32000), vec![8000, 16000, 24000, 32000]);
+    assert_eq!(ramp.levels(-32000), vec![-8000, -16000, -24000, -32000]);
+    assert_eq!(Ramp::new(Duration::ZERO, 0).levels(100), vec![100]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_h_bridge_watchdog() {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(10);
+    let mock_client = tokio::spawn(async move {
+        let mut sent = Vec::new();
+        while let Some(msg) = rx.recv().await {
+            sent.push(msg.buffer.clone());
+            msg.response.send(vec![2, b'O', b'1', CR]).unwrap();
+        }
+        sent
+    });
+    let h_bridge = HBridge::new(1, 32000, tx).with_watchdog(Duration::from_secs(5));
+    h_bridge.set_state(HBridgeState::Pos).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    h_bridge.set_state(HBridgeState::Neg).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    assert!(!h_bridge.watchdog_tripped());
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(h_bridge.watchdog_tripped());
+    drop(h_bridge);
+    let sent = mock_client.await.unwrap();
+    assert_eq!(sent.last().unwrap().as_slice(), b"\x02O10\r");
+    assert_eq!(sent.len(), 3);
+}
@@ -1,14 +1,60 @@
-use crate::components::send_recv::SendRecv;
+use crate::components::clear_core_io::DigitalInput;
+use crate::components::dry_cycle::DryCycle;
+use crate::components::motion_journal::{JournalEntry, MotionJournal};
+use crate::components::scale::CancelToken;
+use crate::components::send_recv::{Reply, SendRecv};
+use crate::controllers::clear_core::{self, DeviceLocks};
 use crate::interface::tcp::client;
+use crate::subsystems::guard::GuardState;
 use crate::subsystems::linear_actuator::Message;
-use crate::util::utils::{ascii_to_int, make_prefix, num_to_bytes};
-use serde::Serialize;
+use crate::util::units::{Revolutions, RevsPerSec, RevsPerSec2, RevsPerSec3};
+use crate::util::utils::{make_prefix, num_to_bytes};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::future::Future;
+use std::ops::Deref;
 use std::result::Result;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 pub use std::time::Duration;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::{watch, OwnedMutexGuard};
+use tokio::time::Instant;
 
-#[derive(Debug, PartialOrd, PartialEq, Serialize)]
+// Feed-rate override in percent, applied to every velocity sent to the motors given it.
+// Clones share the setting, so one handle from the HMI covers the whole machine
+#[derive(Debug, Clone)]
+pub struct FeedOverride {
+    percent: Arc<AtomicU8>,
+}
+
+impl FeedOverride {
+    pub fn new() -> Self {
+        Self {
+            percent: Arc::new(AtomicU8::new(100)),
+        }
+    }
+
+    pub fn set(&self, percent: u8) {
+        self.percent.store(percent.min(100), Ordering::Relaxed);
+    }
+
+    pub fn percent(&self) -> u8 {
+        self.percent.load(Ordering::Relaxed)
+    }
+
+    fn apply(&self, velocity: f64) -> f64 {
+        velocity * self.percent() as f64 / 100.
+    }
+}
+
+impl Default for FeedOverride {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Serialize)]
 pub enum Status {
     Disabled,
     Enabling,
@@ -18,11 +64,503 @@ pub enum Status {
     Unknown,
 }
 
+impl Reply for Status {
+    fn parse(payload: &[u8]) -> Result<Self, Box<dyn Error>> {
+        match payload.first() {
+            Some(48) => Ok(Status::Disabled),
+            Some(49) => Ok(Status::Enabling),
+            Some(50) => Ok(Status::Faulted),
+            Some(51) => Ok(Status::Ready),
+            Some(52) => Ok(Status::Moving),
+            _ => Ok(Status::Unknown),
+        }
+    }
+}
+
+// Bits of the ClearCore motor alert register, in register order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MotorAlert {
+    MotionCanceledInAlert,
+    MotionCanceledPositiveLimit,
+    MotionCanceledNegativeLimit,
+    MotionCanceledSensorEStop,
+    MotionCanceledMotorDisabled,
+    // Raised by the drive through HLFB; clear_alerts leaves it set until enable is cycled
+    MotorFaulted,
+}
+
+impl MotorAlert {
+    const ALL: [MotorAlert; 6] = [
+        MotorAlert::MotionCanceledInAlert,
+        MotorAlert::MotionCanceledPositiveLimit,
+        MotorAlert::MotionCanceledNegativeLimit,
+        MotorAlert::MotionCanceledSensorEStop,
+        MotorAlert::MotionCanceledMotorDisabled,
+        MotorAlert::MotorFaulted,
+    ];
+
+    pub fn decode(register: u32) -> Vec<MotorAlert> {
+        Self::ALL
+            .into_iter()
+            .enumerate()
+            .filter(|(bit, _)| register & (1 << bit) != 0)
+            .map(|(_, alert)| alert)
+            .collect()
+    }
+}
+
+// Alerts are read as the register's decimal value
+impl Reply for Vec<MotorAlert> {
+    fn parse(payload: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(MotorAlert::decode(isize::parse(payload)? as u32))
+    }
+}
+
+// High level feedback from the drive. Drives set to a PWM HLFB mode report torque as a percent
+// of peak, signed with direction; the ASG modes only assert or deassert
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Hlfb {
+    Deasserted,
+    Asserted,
+    Torque(f64),
+    Unknown,
+}
+
+impl Reply for Hlfb {
+    // "0" or "1" for the ASG modes, "2" followed by the torque in tenths of a percent otherwise
+    fn parse(payload: &[u8]) -> Result<Self, Box<dyn Error>> {
+        match payload.split_first() {
+            Some((b'0', _)) => Ok(Hlfb::Deasserted),
+            Some((b'1', _)) => Ok(Hlfb::Asserted),
+            Some((b'2', tenths)) => Ok(Hlfb::Torque(isize::parse(tenths)? as f64 / 10.)),
+            _ => Ok(Hlfb::Unknown),
+        }
+    }
+}
+
+// Reference `home` drives towards and takes as zero
+pub enum HomeTo<'a> {
+    // A mechanical stop; the stall faults the drive, so alerts are cleared afterwards
+    HardStop,
+    // Stops as soon as the input reads high
+    Sensor(&'a DigitalInput),
+}
+
+const HOMING_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DutyLimits {
+    pub max_run_time: Duration,
+    pub max_moves: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DutyStats {
+    pub run_time: Duration,
+    pub moves: u64,
+}
+
+#[derive(Default)]
+struct DutyTracker {
+    stats: DutyStats,
+    moving_since: Option<Instant>,
+    limits: Option<DutyLimits>,
+    warned: bool,
+}
+
+impl DutyTracker {
+    fn start(&mut self, now: Instant) {
+        self.stats.moves += 1;
+        // A new command while already moving keeps counting from the original start
+        self.moving_since.get_or_insert(now);
+    }
+
+    fn finish(&mut self, now: Instant) {
+        if let Some(since) = self.moving_since.take() {
+            self.stats.run_time += now - since;
+        }
+    }
+
+    fn stats(&self, now: Instant) -> DutyStats {
+        let mut stats = self.stats;
+        if let Some(since) = self.moving_since {
+            stats.run_time += now - since;
+        }
+        stats
+    }
+
+    fn exceeded(&self, now: Instant) -> bool {
+        let stats = self.stats(now);
+        self.limits.is_some_and(|limits| {
+            stats.run_time > limits.max_run_time || stats.moves > limits.max_moves
+        })
+    }
+}
+
+// Lifetime usage of an axis for maintenance scheduling. Unlike duty stats these are never
+// reset; seed them from the last persisted values with `with_odometer`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct MotionStats {
+    pub moves: u64,
+    // Commanded travel in revs, jogs count their speed over the time they ran
+    pub travel: f64,
+    pub run_time: Duration,
+}
+
+impl MotionStats {
+    // Revs per second while moving
+    pub fn average_speed(&self) -> f64 {
+        if self.run_time.is_zero() {
+            0.
+        } else {
+            self.travel / self.run_time.as_secs_f64()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Travel {
+    To(f64),
+    By(f64),
+    Jog(f64),
+}
+
+#[derive(Default)]
+struct Odometer {
+    duty: DutyTracker,
+    travel: f64,
+    // Last absolute target, unknown after a jog until the position is set again
+    position: Option<f64>,
+    jog: Option<(f64, Instant)>,
+}
+
+impl Odometer {
+    fn start(&mut self, travel: Travel, now: Instant) {
+        self.finish_jog(now);
+        self.duty.start(now);
+        match travel {
+            Travel::To(target) => {
+                if let Some(position) = self.position {
+                    self.travel += (target - position).abs();
+                }
+                self.position = Some(target);
+            }
+            Travel::By(distance) => {
+                self.travel += distance.abs();
+                self.position = self.position.map(|position| position + distance);
+            }
+            Travel::Jog(speed) => {
+                self.position = None;
+                self.jog = Some((speed, now));
+            }
+        }
+    }
+
+    fn finish(&mut self, now: Instant) {
+        self.finish_jog(now);
+        self.duty.finish(now);
+    }
+
+    fn finish_jog(&mut self, now: Instant) {
+        if let Some((speed, since)) = self.jog.take() {
+            self.travel += speed.abs() * (now - since).as_secs_f64();
+        }
+    }
+
+    fn stats(&self, now: Instant) -> MotionStats {
+        let duty = self.duty.stats(now);
+        let jogged = self.jog.map_or(0., |(speed, since)| {
+            speed.abs() * (now - since).as_secs_f64()
+        });
+        MotionStats {
+            moves: duty.moves,
+            travel: self.travel + jogged,
+            run_time: duty.run_time,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdlePowerDown {
+    // Time without commands after which the drive is disabled to save heat
+    pub timeout: Duration,
+    // Minimum time the drive stays enabled after an enable, so an axis used every now and
+    // then isn't switched on and off on each command
+    pub hysteresis: Duration,
+}
+
+// How long a woken drive gets to report ready before the command that woke it fails
+const WAKE_TIMEOUT: Duration = Duration::from_secs(2);
+const WAKE_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Default)]
+struct IdleState {
+    // Enabled by the caller; the drive may still be powered down while idle
+    enabled: bool,
+    powered_down: bool,
+    last_activity: Option<Instant>,
+    enabled_at: Option<Instant>,
+}
+
+impl IdleState {
+    fn on_enable(&mut self, now: Instant) {
+        self.enabled = true;
+        self.powered_down = false;
+        self.enabled_at = Some(now);
+        self.last_activity = Some(now);
+    }
+
+    // When the drive may be powered down, if nothing happens before then
+    fn due(&self, policy: &IdlePowerDown) -> Option<Instant> {
+        if !self.enabled || self.powered_down {
+            return None;
+        }
+        let idle = self.last_activity? + policy.timeout;
+        let held = self.enabled_at? + policy.hysteresis;
+        Some(idle.max(held))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommsAlarm {
+    // Weight of the newest command in the moving failure rate
+    pub alpha: f64,
+    // Failure rate that raises the alarm; it clears again below half of this
+    pub threshold: f64,
+}
+
+const DEFAULT_COMMS_ALARM: CommsAlarm = CommsAlarm {
+    alpha: 0.1,
+    threshold: 0.2,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CommsHealth {
+    pub commands: u64,
+    pub failures: u64,
+    pub failure_rate: f64,
+    pub degraded: bool,
+}
+
+impl CommsHealth {
+    // Returns the new degraded state if this command changed it
+    fn record(&mut self, alarm: &CommsAlarm, ok: bool) -> Option<bool> {
+        self.commands += 1;
+        let failed = if ok { 0. } else { 1. };
+        if !ok {
+            self.failures += 1;
+        }
+        self.failure_rate += alarm.alpha * (failed - self.failure_rate);
+        let degraded = if self.degraded {
+            self.failure_rate >= alarm.threshold / 2.
+        } else {
+            self.failure_rate > alarm.threshold
+        };
+        (degraded != self.degraded).then(|| {
+            self.degraded = degraded;
+            degraded
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum LimitMode {
+    // Moves to targets outside the limits fail before anything is sent
+    #[default]
+    Reject,
+    // Targets outside the limits are moved to the nearest limit instead
+    Clamp,
+}
+
+// Allowed absolute positions in revs, so a bad target can't drive an axis into the frame.
+// Jogs aren't covered, they have no target
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TravelLimits {
+    pub min: f64,
+    pub max: f64,
+    #[serde(default)]
+    pub mode: LimitMode,
+}
+
+// Ramp settings in revs/s² and revs/s³. A jerk limit turns the trapezoidal profile into an
+// s-curve, softening the start and end of each move for loads that shake loose
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MotionProfile {
+    pub acceleration: RevsPerSec2,
+    pub deceleration: RevsPerSec2,
+    #[serde(default)]
+    pub jerk: Option<RevsPerSec3>,
+}
+
+// Speed and ramps sent every time the motor is enabled, so each consumer doesn't have to set
+// its own before the first move. Unset values keep whatever the drive already has
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct MotionDefaults {
+    #[serde(default)]
+    pub velocity: Option<RevsPerSec>,
+    #[serde(default)]
+    pub acceleration: Option<RevsPerSec2>,
+    #[serde(default)]
+    pub deceleration: Option<RevsPerSec2>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Direction {
+    Forward,
+    Reverse,
+}
+
+impl Direction {
+    fn apply(&self, speed: f64) -> f64 {
+        match self {
+            Direction::Forward => speed,
+            Direction::Reverse => -speed,
+        }
+    }
+}
+
+// Motor settings gathered in one place, e.g. deserialized from a machine config, and applied
+// as the motor is built
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MotorBuilder {
+    pub id: u8,
+    pub scale: isize,
+    #[serde(default)]
+    pub travel_limits: Option<TravelLimits>,
+    // Highest jog speed in revs/s, faster requests are clamped to it
+    #[serde(default)]
+    pub jog_limit: Option<RevsPerSec>,
+    // Highest velocity in revs/s `set_velocity` will send, faster requests are clamped to it
+    #[serde(default)]
+    pub max_velocity: Option<RevsPerSec>,
+    #[serde(default)]
+    pub default_velocity: Option<RevsPerSec>,
+    #[serde(default)]
+    pub acceleration: Option<RevsPerSec2>,
+    #[serde(default)]
+    pub deceleration: Option<RevsPerSec2>,
+}
+
+impl MotorBuilder {
+    pub fn new(id: u8, scale: isize) -> Self {
+        Self {
+            id,
+            scale,
+            travel_limits: None,
+            jog_limit: None,
+            max_velocity: None,
+            default_velocity: None,
+            acceleration: None,
+            deceleration: None,
+        }
+    }
+
+    pub fn with_travel_limits(mut self, limits: TravelLimits) -> Self {
+        self.travel_limits = Some(limits);
+        self
+    }
+
+    pub fn with_jog_limit(mut self, max_speed: RevsPerSec) -> Self {
+        self.jog_limit = Some(max_speed);
+        self
+    }
+
+    pub fn with_max_velocity(mut self, max_velocity: RevsPerSec) -> Self {
+        self.max_velocity = Some(max_velocity);
+        self
+    }
+
+    pub fn with_default_velocity(mut self, velocity: RevsPerSec) -> Self {
+        self.default_velocity = Some(velocity);
+        self
+    }
+
+    pub fn with_ramps(mut self, acceleration: RevsPerSec2, deceleration: RevsPerSec2) -> Self {
+        self.acceleration = Some(acceleration);
+        self.deceleration = Some(deceleration);
+        self
+    }
+
+    pub fn build(&self, drive_sender: Sender<Message>) -> ClearCoreMotor {
+        let mut motor = ClearCoreMotor::new(self.id, self.scale, drive_sender);
+        if let Some(limits) = self.travel_limits {
+            motor = motor.with_travel_limits(limits);
+        }
+        if let Some(max_speed) = self.jog_limit {
+            motor = motor.with_jog_limit(max_speed);
+        }
+        if let Some(max_velocity) = self.max_velocity {
+            motor = motor.with_max_velocity(max_velocity);
+        }
+        motor.with_motion_defaults(MotionDefaults {
+            velocity: self.default_velocity,
+            acceleration: self.acceleration,
+            deceleration: self.deceleration,
+        })
+    }
+}
+
+// One status poller shared by every handle on a motor. `polled` holds the command count as of
+// the latest read, so a waiter can tell a reading taken after its own move from a stale one
+struct StatusCache {
+    status: Arc<watch::Sender<Status>>,
+    polled: watch::Receiver<u64>,
+}
+
+// Keeps a deadman jog running; the jog stops when this isn't refreshed in time or is dropped
+pub struct Deadman {
+    refresh: watch::Sender<()>,
+}
+
+impl Deadman {
+    // Returns false once the jog has already been stopped
+    pub fn refresh(&self) -> bool {
+        self.refresh.send(()).is_ok()
+    }
+}
+
 pub struct ClearCoreMotor {
     id: u8,
     prefix: [u8; 3],
     scale: isize,
     drive_sender: Sender<Message>,
+    guard: Option<watch::Receiver<GuardState>>,
+    duty: Arc<Mutex<DutyTracker>>,
+    odometer: Arc<Mutex<Odometer>>,
+    comms_alarm: CommsAlarm,
+    comms: Arc<Mutex<CommsHealth>>,
+    jog_limit: Option<f64>,
+    max_velocity: Option<f64>,
+    motion_defaults: MotionDefaults,
+    dry_cycle: Option<DryCycle>,
+    feed_override: Option<FeedOverride>,
+    travel_limits: Option<TravelLimits>,
+    claim: Arc<tokio::sync::Mutex<()>>,
+    journal: Option<(MotionJournal, String)>,
+    idle: Option<IdlePowerDown>,
+    idle_state: Arc<Mutex<IdleState>>,
+    status_cache: Arc<Mutex<Option<StatusCache>>>,
+    // Commands acknowledged so far, see `StatusCache`
+    commands: Arc<AtomicU64>,
+    // Bumped on every move start and stop, so a deadman can tell its jog was superseded
+    motions: Arc<AtomicU64>,
+    // Set on the handle inside a ClaimedMotor, whose commands already hold the claim
+    claimed: bool,
+}
+
+// Exclusive use of a motor, e.g. for homing. Commands from every other handle sharing the
+// claim lock wait until this is dropped, except stops, which are never held back
+pub struct ClaimedMotor {
+    motor: ClearCoreMotor,
+    _claim: OwnedMutexGuard<()>,
+}
+
+impl Deref for ClaimedMotor {
+    type Target = ClearCoreMotor;
+
+    fn deref(&self) -> &Self::Target {
+        &self.motor
+    }
 }
 
 impl ClearCoreMotor {
@@ -33,145 +571,1017 @@ impl ClearCoreMotor {
             prefix,
             scale,
             drive_sender,
+            guard: None,
+            duty: Arc::new(Mutex::new(DutyTracker::default())),
+            odometer: Arc::new(Mutex::new(Odometer::default())),
+            comms_alarm: DEFAULT_COMMS_ALARM,
+            comms: Arc::new(Mutex::new(CommsHealth::default())),
+            jog_limit: None,
+            max_velocity: None,
+            motion_defaults: MotionDefaults::default(),
+            dry_cycle: None,
+            feed_override: None,
+            travel_limits: None,
+            claim: Arc::new(tokio::sync::Mutex::new(())),
+            journal: None,
+            idle: None,
+            idle_state: Arc::new(Mutex::new(IdleState::default())),
+            status_cache: Arc::new(Mutex::new(None)),
+            commands: Arc::new(AtomicU64::new(0)),
+            motions: Arc::new(AtomicU64::new(0)),
+            claimed: false,
+        }
+    }
+
+    // Without this the claim only covers this handle, e.g. a gantry actor and a manual jog
+    // built separately for the same motor need a shared registry to exclude each other
+    pub fn with_lock(mut self, locks: &DeviceLocks) -> Self {
+        self.claim = locks.lock(self.prefix.as_slice());
+        self
+    }
+
+    // Journals each commanded target under `axis` before it's sent, see `homing_required`
+    pub fn with_journal(mut self, journal: MotionJournal, axis: &str) -> Self {
+        self.journal = Some((journal, axis.to_string()));
+        self
+    }
+
+    // The drive is disabled after `timeout` without commands and re-enabled by the next one.
+    // Needs `idle_monitor` running; leave it off for axes that must hold position
+    pub fn with_idle_power_down(mut self, policy: IdlePowerDown) -> Self {
+        self.idle = Some(policy);
+        self
+    }
+
+    pub fn is_powered_down(&self) -> bool {
+        self.idle_state.lock().unwrap().powered_down
+    }
+
+    // Disables the drive once it has been idle long enough. Spawn it alongside the motor; it
+    // returns straight away if no idle power-down is configured
+    pub fn idle_monitor(&self) -> impl Future<Output = ()> + Send + 'static {
+        let motor = self.handle(true);
+        async move {
+            let Some(policy) = motor.idle else {
+                return;
+            };
+            loop {
+                let due = motor.idle_state.lock().unwrap().due(&policy);
+                let Some(due) = due else {
+                    tokio::time::sleep(policy.timeout).await;
+                    continue;
+                };
+                tokio::time::sleep_until(due).await;
+                let result = motor.power_down_if_idle(&policy).await;
+                if let Err(e) = result.map_err(|e| e.to_string()) {
+                    println!("WARNING: Motor {} idle power-down failed: {e}", motor.id);
+                    tokio::time::sleep(policy.timeout).await;
+                }
+            }
+        }
+    }
+
+    // Polls the position every `interval` into a watch channel, NaN until the first read lands.
+    // Failed reads are skipped, they already count against comms health. The poller stops once
+    // every receiver is dropped
+    pub fn subscribe_position(&self, interval: Duration) -> watch::Receiver<Revolutions> {
+        let (position, receiver) = watch::channel(Revolutions(f64::NAN));
+        let motor = self.handle(false);
+        tokio::spawn(async move {
+            while !position.is_closed() {
+                if let Ok(current) = motor.get_position().await.map_err(|e| e.to_string()) {
+                    position.send_replace(current);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        receiver
+    }
+
+    // Starts the shared status poller, or joins it if one is running; subscribers are only
+    // woken when the status changes. While it runs, move waits on this motor read the cache
+    // instead of polling the drive themselves. It stops once every receiver is dropped
+    pub fn subscribe_status(&self, interval: Duration) -> watch::Receiver<Status> {
+        let mut cache = self.status_cache.lock().unwrap();
+        if let Some(cache) = cache.as_ref().filter(|cache| !cache.status.is_closed()) {
+            return cache.status.subscribe();
+        }
+        let (status, receiver) = watch::channel(Status::Unknown);
+        let status = Arc::new(status);
+        let (polled, polled_receiver) = watch::channel(0);
+        *cache = Some(StatusCache {
+            status: status.clone(),
+            polled: polled_receiver,
+        });
+        let motor = self.handle(false);
+        tokio::spawn(async move {
+            while !status.is_closed() {
+                let issued = motor.commands.load(Ordering::SeqCst);
+                if let Ok(current) = motor.get_status().await.map_err(|e| e.to_string()) {
+                    status.send_if_modified(|cached| {
+                        let changed = *cached != current;
+                        *cached = current;
+                        changed
+                    });
+                    polled.send_replace(issued);
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        receiver
+    }
+
+    // From the status cache when a poller is running, waiting for a read taken after the
+    // latest command. Otherwise a round trip to the drive
+    async fn current_status(&self) -> Result<Status, Box<dyn Error>> {
+        let cache = self
+            .status_cache
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|cache| !cache.status.is_closed())
+            .map(|cache| (cache.status.clone(), cache.polled.clone()));
+        let Some((status, mut polled)) = cache else {
+            return self.get_status().await;
+        };
+        let issued = self.commands.load(Ordering::SeqCst);
+        let polled = polled.wait_for(|polled| *polled >= issued).await.is_ok();
+        match polled {
+            true => Ok(*status.borrow()),
+            // The poller stopped in the meantime
+            false => self.get_status().await,
+        }
+    }
+
+    async fn power_down_if_idle(&self, policy: &IdlePowerDown) -> Result<(), Box<dyn Error>> {
+        // Holding the claim keeps commands from other handles out until this is done; if it's
+        // taken the motor is in use anyway
+        let Ok(_claim) = self.claim.try_lock() else {
+            return Ok(());
+        };
+        let due = self.idle_state.lock().unwrap().due(policy);
+        if due.is_none_or(|due| due > Instant::now()) {
+            return Ok(());
+        }
+        if self.get_status().await? == Status::Moving {
+            self.idle_state.lock().unwrap().last_activity = Some(Instant::now());
+            return Ok(());
+        }
+        self.request::<()>(self.prefix.as_slice(), b"DE").await?;
+        self.commands.fetch_add(1, Ordering::SeqCst);
+        self.idle_state.lock().unwrap().powered_down = true;
+        println!("Motor {} idle, drive powered down", self.id);
+        Ok(())
+    }
+
+    // Re-enables a powered down drive ahead of the next command the same way `enable` does,
+    // since the drive drops its motion settings while disabled, then waits for it to be ready.
+    // Runs on a claimed handle as the command that woke it already holds the claim
+    async fn wake(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_powered_down() {
+            return Ok(());
+        }
+        let motor = self.handle(true);
+        Box::pin(motor.enable()).await?;
+        let start = Instant::now();
+        loop {
+            let status = motor.get_status().await?;
+            match status {
+                Status::Ready | Status::Moving => break,
+                Status::Faulted => {
+                    return Err(Box::from(format!("Motor {} faulted on wake", self.id)));
+                }
+                status if Instant::now() - start >= WAKE_TIMEOUT => {
+                    return Err(Box::from(format!(
+                        "Motor {} not ready after wake ({status:?})",
+                        self.id
+                    )));
+                }
+                _ => tokio::time::sleep(WAKE_POLL).await,
+            }
+        }
+        println!("Motor {} drive re-enabled", self.id);
+        Ok(())
+    }
+
+    // Status and position polls count as activity for the idle power-down
+    fn record_activity(&self) {
+        let mut idle = self.idle_state.lock().unwrap();
+        if idle.enabled && !idle.powered_down {
+            idle.last_activity = Some(Instant::now());
+        }
+    }
+
+    // After a restart: false only if the motor still reports its last journaled target
+    pub async fn homing_required(&self, tolerance: Revolutions) -> Result<bool, Box<dyn Error>> {
+        let Some((journal, axis)) = &self.journal else {
+            return Ok(true);
+        };
+        let position = self.get_position().await?;
+        Ok(journal.homing_required(axis, position.0, tolerance.0))
+    }
+
+    async fn journal(&self, entry: JournalEntry) -> Result<(), Box<dyn Error>> {
+        match &self.journal {
+            Some((journal, axis)) => journal.record(axis, entry).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn claim(&self) -> ClaimedMotor {
+        let claim = self.claim.clone().lock_owned().await;
+        self.claimed_handle(claim)
+    }
+
+    // A claim for a sequence of commands, unless this handle already holds one
+    async fn exclusive(&self) -> Option<ClaimedMotor> {
+        match self.claimed {
+            true => None,
+            false => Some(self.claim().await),
+        }
+    }
+
+    pub fn try_claim(&self) -> Result<ClaimedMotor, Box<dyn Error>> {
+        match self.claim.clone().try_lock_owned() {
+            Ok(claim) => Ok(self.claimed_handle(claim)),
+            Err(_) => Err(Box::from(format!("Motor {} is claimed elsewhere", self.id))),
+        }
+    }
+
+    fn claimed_handle(&self, claim: OwnedMutexGuard<()>) -> ClaimedMotor {
+        ClaimedMotor {
+            motor: self.handle(true),
+            _claim: claim,
+        }
+    }
+
+    // Another handle on the same motor, sharing its tracking state
+    fn handle(&self, claimed: bool) -> ClearCoreMotor {
+        ClearCoreMotor {
+            id: self.id,
+            prefix: self.prefix,
+            scale: self.scale,
+            drive_sender: self.drive_sender.clone(),
+            guard: self.guard.clone(),
+            duty: self.duty.clone(),
+            odometer: self.odometer.clone(),
+            comms_alarm: self.comms_alarm,
+            comms: self.comms.clone(),
+            jog_limit: self.jog_limit,
+            max_velocity: self.max_velocity,
+            motion_defaults: self.motion_defaults,
+            dry_cycle: self.dry_cycle.clone(),
+            feed_override: self.feed_override.clone(),
+            travel_limits: self.travel_limits,
+            claim: self.claim.clone(),
+            journal: self.journal.clone(),
+            idle: self.idle,
+            idle_state: self.idle_state.clone(),
+            status_cache: self.status_cache.clone(),
+            commands: self.commands.clone(),
+            motions: self.motions.clone(),
+            claimed,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn with_guard(mut self, guard: watch::Receiver<GuardState>) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    pub fn with_duty_limits(self, limits: DutyLimits) -> Self {
+        self.duty.lock().unwrap().limits = Some(limits);
+        self
+    }
+
+    // Accumulated run time and move count since the motor was created or the last reset
+    pub fn duty(&self) -> DutyStats {
+        self.duty.lock().unwrap().stats(Instant::now())
+    }
+
+    pub fn reset_duty(&self) {
+        let mut duty = self.duty.lock().unwrap();
+        let now = Instant::now();
+        duty.stats = DutyStats::default();
+        duty.moving_since = duty.moving_since.map(|_| now);
+        duty.warned = false;
+    }
+
+    pub fn duty_exceeded(&self) -> bool {
+        self.duty.lock().unwrap().exceeded(Instant::now())
+    }
+
+    pub fn with_odometer(self, stats: MotionStats) -> Self {
+        {
+            let mut odometer = self.odometer.lock().unwrap();
+            odometer.duty.stats = DutyStats {
+                run_time: stats.run_time,
+                moves: stats.moves,
+            };
+            odometer.travel = stats.travel;
         }
+        self
+    }
+
+    pub fn motion_stats(&self) -> MotionStats {
+        self.odometer.lock().unwrap().stats(Instant::now())
+    }
+
+    fn record_move_start(&self, travel: Travel) {
+        self.motions.fetch_add(1, Ordering::SeqCst);
+        let now = Instant::now();
+        self.odometer.lock().unwrap().start(travel, now);
+        let mut duty = self.duty.lock().unwrap();
+        duty.start(now);
+        if !duty.warned && duty.exceeded(now) {
+            duty.warned = true;
+            let stats = duty.stats(now);
+            println!(
+                "WARNING: Motor {} exceeded duty limits ({:.0?} run time, {} moves)",
+                self.id, stats.run_time, stats.moves
+            );
+        }
+    }
+
+    fn record_move_end(&self) {
+        self.motions.fetch_add(1, Ordering::SeqCst);
+        let now = Instant::now();
+        self.odometer.lock().unwrap().finish(now);
+        self.duty.lock().unwrap().finish(now);
+    }
+
+    pub fn with_jog_limit(mut self, max_speed: RevsPerSec) -> Self {
+        self.jog_limit = Some(max_speed.0.abs());
+        self
+    }
+
+    pub fn with_max_velocity(mut self, max_velocity: RevsPerSec) -> Self {
+        self.max_velocity = Some(max_velocity.0.abs());
+        self
+    }
+
+    pub fn with_motion_defaults(mut self, defaults: MotionDefaults) -> Self {
+        self.motion_defaults = defaults;
+        self
+    }
+
+    pub fn with_travel_limits(mut self, limits: TravelLimits) -> Self {
+        self.travel_limits = Some(limits);
+        self
+    }
+
+    // Velocities and jogs are scaled down while the dry cycle runs
+    pub fn with_dry_cycle(mut self, dry_cycle: DryCycle) -> Self {
+        self.dry_cycle = Some(dry_cycle);
+        self
+    }
+
+    pub fn with_feed_override(mut self, feed_override: FeedOverride) -> Self {
+        self.feed_override = Some(feed_override);
+        self
+    }
+
+    fn limit_target(&self, target: f64) -> Result<f64, clear_core::Error> {
+        let Some(limits) = self.travel_limits else {
+            return Ok(target);
+        };
+        if (limits.min..=limits.max).contains(&target) {
+            return Ok(target);
+        }
+        match limits.mode {
+            LimitMode::Clamp => Ok(target.clamp(limits.min, limits.max)),
+            LimitMode::Reject => Err(clear_core::Error::TravelLimitExceeded {
+                device: clear_core::Device::from_prefix(self.prefix.as_slice()),
+                target,
+                min: limits.min,
+                max: limits.max,
+            }),
+        }
+    }
+
+    pub fn with_comms_alarm(mut self, alarm: CommsAlarm) -> Self {
+        self.comms_alarm = alarm;
+        self
+    }
+
+    pub fn comms_health(&self) -> CommsHealth {
+        *self.comms.lock().unwrap()
+    }
+
+    fn record_comms<T>(&self, result: &Result<T, Box<dyn Error>>) {
+        let mut comms = self.comms.lock().unwrap();
+        match comms.record(&self.comms_alarm, result.is_ok()) {
+            Some(true) => println!(
+                "WARNING: Motor {} communication degraded ({:.0}% of recent commands failed)",
+                self.id,
+                comms.failure_rate * 100.
+            ),
+            Some(false) => println!("Motor {} communication recovered", self.id),
+            None => {}
+        }
+    }
+
+    fn check_guard(&self) -> Result<(), clear_core::Error> {
+        match &self.guard {
+            Some(guard) if *guard.borrow() == GuardState::Open => {
+                Err(clear_core::Error::GuardOpen(
+                    clear_core::Device::from_prefix(self.prefix.as_slice()),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn scaled(&self, value: f64) -> Vec<u8> {
+        num_to_bytes((value * (self.scale as f64)).trunc() as isize)
+    }
+
+    async fn command(&self, mnemonic: &[u8], value: &[u8]) -> Result<(), Box<dyn Error>> {
+        // Only commands wait on a claim, status and position reads don't conflict
+        let stop = matches!(mnemonic, b"ST" | b"AS");
+        let _turn = match self.claimed || stop {
+            true => None,
+            false => Some(self.claim.lock().await),
+        };
+        if !stop && !matches!(mnemonic, b"DE" | b"EN") {
+            self.wake().await?;
+        }
+        let mut body = mnemonic.to_vec();
+        body.extend_from_slice(value);
+        let result = self
+            .request::<()>(self.prefix.as_slice(), body.as_slice())
+            .await;
+        self.record_comms(&result);
+        if result.is_ok() {
+            self.commands.fetch_add(1, Ordering::SeqCst);
+            let now = Instant::now();
+            let mut idle = self.idle_state.lock().unwrap();
+            match mnemonic {
+                b"EN" => idle.on_enable(now),
+                b"DE" => *idle = IdleState::default(),
+                _ => idle.last_activity = Some(now),
+            }
+        }
+        result
     }
 
     pub async fn enable(&self) -> Result<&Self, Box<dyn Error>> {
-        let enable_cmd = [2, b'M', self.id + 48, b'E', b'N', 13];
-        self.write(enable_cmd.as_ref()).await?;
+        self.check_guard()?;
+        self.command(b"EN", &[]).await?;
+        let defaults = self.motion_defaults;
+        if let Some(velocity) = defaults.velocity {
+            self.set_velocity(velocity).await?;
+        }
+        if let Some(acceleration) = defaults.acceleration {
+            self.set_acceleration(acceleration).await?;
+        }
+        if let Some(deceleration) = defaults.deceleration {
+            self.set_deceleration(deceleration).await?;
+        }
         Ok(self)
     }
 
     pub async fn disable(&self) -> Result<(), Box<dyn Error>> {
-        let enable_cmd = [2, b'M', self.id + 48, b'D', b'E', 13];
-        self.write(enable_cmd.as_ref()).await?;
+        self.command(b"DE", &[]).await?;
+        self.record_move_end();
         Ok(())
     }
 
-    pub async fn absolute_move(&self, position: f64) -> Result<(), Box<dyn Error>> {
-        let position = num_to_bytes((position * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(position.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"AM");
-        msg.extend_from_slice(position.as_slice());
-        msg.push(13);
-        self.write(msg.as_slice()).await?;
+    pub async fn absolute_move(
+        &self,
+        position: impl Into<Revolutions>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.check_guard()?;
+        let position = self.limit_target(position.into().0)?;
+        self.journal(JournalEntry::Target(position)).await?;
+        self.command(b"AM", self.scaled(position).as_slice())
+            .await?;
+        self.record_move_start(Travel::To(position));
         Ok(())
     }
 
-    pub async fn relative_move(&self, position: f64) -> Result<(), Box<dyn Error>> {
-        let position = num_to_bytes((position * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(position.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"RM");
-        msg.extend_from_slice(position.as_slice());
-        msg.push(13);
-        self.write(msg.as_slice()).await?;
+    pub async fn relative_move(
+        &self,
+        position: impl Into<Revolutions>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.check_guard()?;
+        let position = self.limit_relative(position.into().0).await?;
+        self.start_relative_move(position).await
+    }
+
+    // Distance that keeps a relative move inside the travel limits, reading the position only
+    // when limits are set
+    async fn limit_relative(&self, position: f64) -> Result<f64, Box<dyn Error>> {
+        if self.travel_limits.is_none() {
+            return Ok(position);
+        }
+        let current = self.get_position().await?.0;
+        Ok(self.limit_target(current + position)? - current)
+    }
+
+    async fn start_relative_move(&self, position: f64) -> Result<(), Box<dyn Error>> {
+        self.journal(JournalEntry::Unknown).await?;
+        self.command(b"RM", self.scaled(position).as_slice())
+            .await?;
+        self.record_move_start(Travel::By(position));
         Ok(())
     }
 
-    pub async fn jog(&self, speed: f64) -> Result<(), Box<dyn Error>> {
-        let speed = num_to_bytes((speed * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(speed.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"JG");
-        msg.extend_from_slice(speed.as_slice());
-        msg.push(13);
-        self.write(msg.as_slice()).await?;
+    // Sets the velocity and issues the move under one claim, so another task can't change the
+    // speed in between. The target is checked before the velocity is sent
+    pub async fn absolute_move_with_velocity(
+        &self,
+        position: impl Into<Revolutions>,
+        velocity: impl Into<RevsPerSec>,
+    ) -> Result<(), Box<dyn Error>> {
+        let position = position.into();
+        self.check_guard()?;
+        self.limit_target(position.0)?;
+        let claimed = self.exclusive().await;
+        let motor = claimed.as_deref().unwrap_or(self);
+        motor.set_velocity(velocity).await?;
+        motor.absolute_move(position).await
+    }
+
+    pub async fn relative_move_with_velocity(
+        &self,
+        position: impl Into<Revolutions>,
+        velocity: impl Into<RevsPerSec>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.check_guard()?;
+        let claimed = self.exclusive().await;
+        let motor = claimed.as_deref().unwrap_or(self);
+        let position = motor.limit_relative(position.into().0).await?;
+        motor.set_velocity(velocity).await?;
+        motor.start_relative_move(position).await
+    }
+
+    // Speed is a magnitude in revs/s, clamped to the jog limit when one is set
+    pub async fn jog(
+        &self,
+        direction: Direction,
+        speed: impl Into<RevsPerSec>,
+    ) -> Result<(), Box<dyn Error>> {
+        let speed = speed.into().0;
+        self.check_guard()?;
+        if !speed.is_finite() || speed < 0. {
+            return Err(Box::from(format!("Invalid jog speed {speed}")));
+        }
+        let speed = match self.jog_limit {
+            Some(limit) => speed.min(limit),
+            None => speed,
+        };
+        self.velocity_move(RevsPerSec(direction.apply(speed))).await
+    }
+
+    // Runs at a signed velocity until stopped or replaced by another move. Sending it again
+    // while running changes the speed on the fly, e.g. for a conveyor fed at a commanded rate
+    pub async fn velocity_move(
+        &self,
+        velocity: impl Into<RevsPerSec>,
+    ) -> Result<(), Box<dyn Error>> {
+        let velocity = velocity.into().0;
+        self.check_guard()?;
+        if !velocity.is_finite() {
+            return Err(Box::from(format!("Invalid velocity {velocity}")));
+        }
+        let velocity = self.commanded_velocity(velocity);
+        self.journal(JournalEntry::Unknown).await?;
+        self.command(b"JG", self.scaled(velocity).as_slice())
+            .await?;
+        self.record_move_start(Travel::Jog(velocity));
         Ok(())
     }
 
+    // Like `velocity_move`, but reads the velocity back and fails if the drive didn't take it
+    pub async fn velocity_move_verified(
+        &self,
+        velocity: impl Into<RevsPerSec>,
+    ) -> Result<(), Box<dyn Error>> {
+        let velocity = velocity.into();
+        self.velocity_move(velocity).await?;
+        self.verify_velocity(velocity.0).await
+    }
+
+    pub async fn jog_with_deadman(
+        &self,
+        direction: Direction,
+        speed: impl Into<RevsPerSec>,
+        refresh_window: Duration,
+    ) -> Result<Deadman, Box<dyn Error>> {
+        self.jog(direction, speed).await?;
+        let jog = self.motions.load(Ordering::SeqCst);
+        let (refresh, mut refreshed) = watch::channel(());
+        let motor = self.handle(false);
+        tokio::spawn(async move {
+            // A refresh that misses the window, or the Deadman being dropped, ends the jog,
+            // unless another move or a stop has taken over from it by then
+            let superseded = || motor.motions.load(Ordering::SeqCst) != jog;
+            while let Ok(Ok(())) = tokio::time::timeout(refresh_window, refreshed.changed()).await {
+                if superseded() {
+                    return;
+                }
+            }
+            if superseded() {
+                return;
+            }
+            println!("Motor {} jog deadman released, stopping", motor.id);
+            if let Err(e) = motor.abrupt_stop().await.map_err(|e| e.to_string()) {
+                println!("WARNING: Motor {} deadman stop failed: {e}", motor.id);
+            }
+        });
+        Ok(Deadman { refresh })
+    }
+
     pub async fn abrupt_stop(&self) -> Result<(), Box<dyn Error>> {
-        let stop_cmd = [2, b'M', self.id + 48, b'A', b'S', 13];
-        self.write(stop_cmd.as_ref()).await?;
+        self.command(b"AS", &[]).await?;
+        self.record_move_end();
         Ok(())
     }
 
     pub async fn stop(&self) -> Result<(), Box<dyn Error>> {
-        let stop_cmd = [2, b'M', self.id + 48, b'S', b'T', 13];
-        self.write(stop_cmd.as_ref()).await?;
+        self.command(b"ST", &[]).await?;
+        self.record_move_end();
         Ok(())
     }
 
-    pub async fn set_position(&self, position: isize) -> Result<(), Box<dyn Error>> {
-        let pos = num_to_bytes(position * self.scale);
-        let mut msg: Vec<u8> = Vec::with_capacity(pos.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"SP");
-        msg.extend_from_slice(pos.as_slice());
-        msg.push(13);
-        self.write(msg.as_slice()).await?;
-        Ok(())
+    // Drives up to `distance` (signed, longer than the full travel) at `velocity` until the
+    // reference is found, then sets zero there. Holds the claim throughout so nothing else
+    // moves the axis mid-homing
+    pub async fn home(
+        &self,
+        to: HomeTo<'_>,
+        velocity: impl Into<RevsPerSec>,
+        distance: impl Into<Revolutions>,
+    ) -> Result<(), Box<dyn Error>> {
+        let claimed = self.exclusive().await;
+        let motor = claimed.as_deref().unwrap_or(self);
+        motor
+            .relative_move_with_velocity(distance, velocity)
+            .await?;
+        match to {
+            HomeTo::HardStop => {
+                motor.wait_for_move(HOMING_POLL).await?;
+                motor.clear_alerts().await?;
+            }
+            HomeTo::Sensor(sensor) => loop {
+                if sensor.get_state().await? {
+                    motor.abrupt_stop().await?;
+                    break;
+                }
+                if motor.current_status().await? != Status::Moving {
+                    return Err(Box::from(format!(
+                        "Motor {} ran out of homing travel before reaching the sensor",
+                        self.id
+                    )));
+                }
+                tokio::time::sleep(HOMING_POLL).await;
+            },
+        }
+        motor.set_position(Revolutions(0.)).await
     }
 
-    pub async fn set_velocity(&self, velocity: f64) -> Result<(), Box<dyn Error>> {
+    pub async fn set_position(
+        &self,
+        position: impl Into<Revolutions>,
+    ) -> Result<(), Box<dyn Error>> {
+        let position = position.into().0;
+        self.command(b"SP", self.scaled(position).as_slice())
+            .await?;
+        self.odometer.lock().unwrap().position = Some(position);
+        self.journal(JournalEntry::Target(position)).await
+    }
+
+    pub async fn set_velocity(
+        &self,
+        velocity: impl Into<RevsPerSec>,
+    ) -> Result<(), Box<dyn Error>> {
+        let velocity = velocity.into().0;
         if velocity < 0. {
             return Err(Box::from("Velocity must be positive"));
         }
-        let vel = num_to_bytes((velocity * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(vel.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"SV");
-        msg.extend_from_slice(vel.as_slice());
-        msg.push(13);
-        self.write(msg.as_slice()).await?;
-        Ok(())
+        let velocity = self.commanded_velocity(velocity);
+        self.command(b"SV", self.scaled(velocity).as_slice()).await
     }
 
-    pub async fn set_acceleration(&self, acceleration: f64) -> Result<(), Box<dyn Error>> {
-        let accel = num_to_bytes((acceleration * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(accel.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"SA");
-        msg.extend_from_slice(accel.as_slice());
-        msg.push(13);
-        self.write(msg.as_slice()).await?;
-        Ok(())
+    fn apply_overrides(&self, velocity: f64) -> f64 {
+        let velocity = match &self.feed_override {
+            Some(feed_override) => feed_override.apply(velocity),
+            None => velocity,
+        };
+        match self.dry_cycle.as_ref().and_then(DryCycle::speed) {
+            Some(speed) => velocity * speed as f64 / 100.,
+            None => velocity,
+        }
     }
 
-    pub async fn set_deceleration(&self, deceleration: f64) -> Result<(), Box<dyn Error>> {
-        let accel = num_to_bytes((deceleration * (self.scale as f64)).trunc() as isize);
-        let mut msg: Vec<u8> = Vec::with_capacity(accel.len() + self.prefix.len() + 1);
-        msg.extend_from_slice(self.prefix.as_slice());
-        msg.extend_from_slice(b"SD");
-        msg.extend_from_slice(accel.as_slice());
-        msg.push(13);
-        self.write(msg.as_slice()).await?;
-        Ok(())
+    // Feed override and dry cycle applied, then clamped to the max velocity either way
+    fn commanded_velocity(&self, velocity: f64) -> f64 {
+        let velocity = self.apply_overrides(velocity);
+        match self.max_velocity {
+            Some(max) => velocity.clamp(-max, max),
+            None => velocity,
+        }
+    }
+
+    pub async fn set_acceleration(
+        &self,
+        acceleration: impl Into<RevsPerSec2>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.command(b"SA", self.scaled(acceleration.into().0).as_slice())
+            .await
+    }
+
+    pub async fn set_deceleration(
+        &self,
+        deceleration: impl Into<RevsPerSec2>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.command(b"SD", self.scaled(deceleration.into().0).as_slice())
+            .await
+    }
+
+    // Needs firmware that supports jerk limiting; 0 goes back to a trapezoidal profile
+    pub async fn set_jerk_limit(&self, jerk: impl Into<RevsPerSec3>) -> Result<(), Box<dyn Error>> {
+        let jerk = jerk.into().0;
+        if !jerk.is_finite() || jerk < 0. {
+            return Err(Box::from(format!("Invalid jerk limit {jerk}")));
+        }
+        self.command(b"SJ", self.scaled(jerk).as_slice()).await
+    }
+
+    pub async fn set_motion_profile(&self, profile: &MotionProfile) -> Result<(), Box<dyn Error>> {
+        self.set_acceleration(profile.acceleration).await?;
+        self.set_deceleration(profile.deceleration).await?;
+        self.set_jerk_limit(profile.jerk.unwrap_or_default()).await
     }
 
     pub async fn get_status(&self) -> Result<Status, Box<dyn Error>> {
-        let status_cmd = [2, b'M', self.id + 48, b'G', b'S', 13];
-        let res = self.write(status_cmd.as_slice()).await?;
-        match res[3] {
-            48 => Ok(Status::Disabled),
-            49 => Ok(Status::Enabling),
-            50 => Ok(Status::Faulted),
-            51 => Ok(Status::Ready),
-            52 => Ok(Status::Moving),
-            _ => Ok(Status::Unknown),
+        let result = self.request(self.prefix.as_slice(), b"GS").await;
+        self.record_comms(&result);
+        let status = result?;
+        self.record_activity();
+        if status != Status::Moving {
+            self.record_move_end();
         }
+        Ok(status)
     }
 
-    pub async fn get_position(&self) -> Result<f64, Box<dyn Error>> {
-        let get_pos_cmd = [2, b'M', self.id + 48, b'G', b'P', 13];
-        let res = self.write(get_pos_cmd.as_slice()).await?;
-        let pos = (ascii_to_int(res.as_slice()) as f64) / (self.scale as f64);
-        Ok(pos)
+    pub async fn get_position(&self) -> Result<Revolutions, Box<dyn Error>> {
+        let result = self.request(self.prefix.as_slice(), b"GP").await;
+        self.record_comms(&result);
+        let pos: isize = result?;
+        self.record_activity();
+        Ok(Revolutions::from_counts(pos, self.scale))
     }
 
-    pub async fn clear_alerts(&self) -> Result<(), Box<dyn Error>> {
-        let clear_cmd = [2, b'M', self.id + 48, b'C', b'A', 13];
-        self.write(clear_cmd.as_slice()).await?;
+    // Commanded velocity as the drive holds it, feed override included
+    pub async fn get_velocity(&self) -> Result<RevsPerSec, Box<dyn Error>> {
+        let counts = self.velocity_counts().await?;
+        Ok(RevsPerSec((counts as f64) / (self.scale as f64)))
+    }
+
+    async fn velocity_counts(&self) -> Result<isize, Box<dyn Error>> {
+        let result = self.request(self.prefix.as_slice(), b"GV").await;
+        self.record_comms(&result);
+        result
+    }
+
+    // Like `set_velocity`, but reads the velocity back and fails if the drive didn't take it
+    pub async fn set_velocity_verified(
+        &self,
+        velocity: impl Into<RevsPerSec>,
+    ) -> Result<(), Box<dyn Error>> {
+        let velocity = velocity.into();
+        self.set_velocity(velocity).await?;
+        self.verify_velocity(velocity.0).await
+    }
+
+    async fn verify_velocity(&self, velocity: f64) -> Result<(), Box<dyn Error>> {
+        let expected = (self.commanded_velocity(velocity) * (self.scale as f64)).trunc() as isize;
+        let actual = self.velocity_counts().await?;
+        if actual != expected {
+            return Err(Box::new(clear_core::Error::VerificationFailed {
+                device: clear_core::Device::from_prefix(self.prefix.as_slice()),
+                expected,
+                actual,
+            }));
+        }
         Ok(())
     }
 
+    pub async fn get_hlfb(&self) -> Result<Hlfb, Box<dyn Error>> {
+        let result = self.request(self.prefix.as_slice(), b"GH").await;
+        self.record_comms(&result);
+        result
+    }
+
+    // Percent of peak torque, for spotting a jamming auger before the drive faults. Fails
+    // unless the drive's HLFB is set to a PWM mode
+    pub async fn get_torque(&self) -> Result<f64, Box<dyn Error>> {
+        match self.get_hlfb().await? {
+            Hlfb::Torque(torque) => Ok(torque),
+            hlfb => Err(Box::from(format!("HLFB not reporting torque ({hlfb:?})"))),
+        }
+    }
+
+    pub async fn clear_alerts(&self) -> Result<(), Box<dyn Error>> {
+        self.command(b"CA", &[]).await
+    }
+
+    pub async fn get_alerts(&self) -> Result<Vec<MotorAlert>, Box<dyn Error>> {
+        let result = self.request(self.prefix.as_slice(), b"GA").await;
+        self.record_comms(&result);
+        result
+    }
+
+    // Clears whatever is raised, cycling enable first when the drive itself faulted, and
+    // returns what was found. Errors if alerts remain, e.g. an e-stop still held in
+    pub async fn recover(&self) -> Result<Vec<MotorAlert>, Box<dyn Error>> {
+        let alerts = self.get_alerts().await?;
+        if alerts.is_empty() {
+            return Ok(alerts);
+        }
+        if alerts.contains(&MotorAlert::MotorFaulted) {
+            self.disable().await?;
+            self.enable().await?;
+        }
+        self.clear_alerts().await?;
+        let remaining = self.get_alerts().await?;
+        if !remaining.is_empty() {
+            return Err(Box::from(format!(
+                "Motor {} alerts did not clear: {remaining:?}",
+                self.id
+            )));
+        }
+        Ok(alerts)
+    }
+
     pub async fn wait_for_move(&self, sampling_rate: Duration) -> Result<(), Box<dyn Error>> {
-        while self.get_status().await.unwrap() == Status::Moving {
+        while self.current_status().await? == Status::Moving {
             tokio::time::sleep(sampling_rate).await;
         }
         Ok(())
     }
+
+    // Like `wait_for_move`, but a motor still moving after `timeout`, e.g. a jammed auger, is
+    // stopped and reported as `MoveTimedOut` so the caller can recover
+    pub async fn wait_for_move_with_timeout(
+        &self,
+        sampling_rate: Duration,
+        timeout: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        self.wait_for_move_until(sampling_rate, Some(timeout), None)
+            .await
+    }
+
+    // Stops the motor and returns `MoveCancelled` once `cancel` fires mid-motion
+    pub async fn wait_for_move_cancellable(
+        &self,
+        sampling_rate: Duration,
+        cancel: &CancelToken,
+    ) -> Result<(), Box<dyn Error>> {
+        self.wait_for_move_until(sampling_rate, None, Some(cancel))
+            .await
+    }
+
+    pub async fn wait_for_move_until(
+        &self,
+        sampling_rate: Duration,
+        timeout: Option<Duration>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        let device = || clear_core::Device::from_prefix(self.prefix.as_slice());
+        while self.current_status().await? == Status::Moving {
+            if cancel.is_some_and(CancelToken::is_cancelled) {
+                self.stop().await?;
+                self.wait_for_move(sampling_rate).await?;
+                return Err(Box::new(clear_core::Error::MoveCancelled(device())));
+            }
+            let mut wait = sampling_rate;
+            if let Some(timeout) = timeout {
+                let elapsed = Instant::now() - start;
+                if elapsed >= timeout {
+                    self.abrupt_stop().await?;
+                    return Err(Box::new(clear_core::Error::MoveTimedOut {
+                        device: device(),
+                        timeout,
+                    }));
+                }
+                wait = wait.min(timeout - elapsed);
+            }
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    // Move and wait in one call, see `wait_for_move_cancellable`
+    pub async fn absolute_move_cancellable(
+        &self,
+        position: impl Into<Revolutions>,
+        sampling_rate: Duration,
+        cancel: &CancelToken,
+    ) -> Result<(), Box<dyn Error>> {
+        self.absolute_move(position).await?;
+        self.wait_for_move_cancellable(sampling_rate, cancel).await
+    }
+
+    pub async fn relative_move_cancellable(
+        &self,
+        distance: impl Into<Revolutions>,
+        sampling_rate: Duration,
+        cancel: &CancelToken,
+    ) -> Result<(), Box<dyn Error>> {
+        self.relative_move(distance).await?;
+        self.wait_for_move_cancellable(sampling_rate, cancel).await
+    }
+}
+
+// Axes moved together, e.g. the gantry and gripper rotation. Every axis is claimed before the
+// first move goes out, so the moves are sent back to back with nothing in between
+pub struct MotorGroup {
+    motors: Vec<ClearCoreMotor>,
+}
+
+impl MotorGroup {
+    pub fn new(motors: Vec<ClearCoreMotor>) -> Self {
+        Self { motors }
+    }
+
+    pub fn motors(&self) -> &[ClearCoreMotor] {
+        self.motors.as_slice()
+    }
+
+    // One absolute position per motor, in the order they were given. All targets are checked
+    // before anything moves, so a bad one can't leave the group half moved
+    pub async fn move_all(&self, positions: &[Revolutions]) -> Result<(), Box<dyn Error>> {
+        if positions.len() != self.motors.len() {
+            return Err(Box::from(format!(
+                "Motor group has {} motors, got {} positions",
+                self.motors.len(),
+                positions.len()
+            )));
+        }
+        for (motor, position) in self.motors.iter().zip(positions) {
+            motor.check_guard()?;
+            motor.limit_target(position.0)?;
+        }
+        // Claimed in id order so two groups sharing axes can't deadlock
+        let mut order: Vec<usize> = (0..self.motors.len()).collect();
+        order.sort_by_key(|&i| self.motors[i].id);
+        let mut claims: Vec<Option<ClaimedMotor>> = (0..self.motors.len()).map(|_| None).collect();
+        for i in order {
+            claims[i] = self.motors[i].exclusive().await;
+        }
+        for ((motor, claim), position) in self.motors.iter().zip(&claims).zip(positions) {
+            let motor = claim.as_deref().unwrap_or(motor);
+            // Only the message is kept across the stop, so the group can run in spawned tasks
+            if let Err(e) = motor
+                .absolute_move(*position)
+                .await
+                .map_err(|e| e.to_string())
+            {
+                drop(claims);
+                self.stop_all().await;
+                return Err(Box::from(e));
+            }
+        }
+        Ok(())
+    }
+
+    // Waits until every motor has stopped. On a timeout or failure the rest are stopped too
+    pub async fn wait_for_all(
+        &self,
+        sampling_rate: Duration,
+        timeout: Option<Duration>,
+    ) -> Result<(), Box<dyn Error>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        for motor in &self.motors {
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if let Err(e) = motor
+                .wait_for_move_until(sampling_rate, remaining, None)
+                .await
+                .map_err(|e| e.to_string())
+            {
+                self.stop_all().await;
+                return Err(Box::from(e));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn stop_all(&self) {
+        for motor in &self.motors {
+            if let Err(e) = motor.abrupt_stop().await {
+                println!("WARNING: Failed to stop motor {}: {e}", motor.id);
+            }
+        }
+    }
 }
 
 impl SendRecv for ClearCoreMotor {
@@ -287,6 +1697,653 @@ impl SendRecv for ClearCoreMotor {
 //     enable.await.unwrap();
 // }
 
+#[tokio::test]
+async fn test_feed_override() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let feed_override = FeedOverride::new();
+    let motor = bench
+        .motor(0, 800)
+        .with_feed_override(feed_override.clone());
+    let other = bench.motor(1, 800);
+    feed_override.set(150);
+    assert_eq!(feed_override.percent(), 100);
+    motor.set_velocity(RevsPerSec(20.)).await.unwrap();
+    assert_eq!(motor.get_velocity().await.unwrap(), RevsPerSec(20.));
+    feed_override.set(25);
+    motor.set_velocity(RevsPerSec(20.)).await.unwrap();
+    other.set_velocity(RevsPerSec(20.)).await.unwrap();
+    assert_eq!(motor.get_velocity().await.unwrap(), RevsPerSec(5.));
+    assert_eq!(other.get_velocity().await.unwrap(), RevsPerSec(20.));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_wait_for_move_timeout() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = bench.motor(2, 800);
+    motor.enable().await.unwrap();
+    motor.jog(Direction::Forward, RevsPerSec(1.)).await.unwrap();
+    let start = Instant::now();
+    let result = motor
+        .wait_for_move_with_timeout(Duration::from_millis(150), Duration::from_secs(1))
+        .await;
+    assert_eq!(Instant::now() - start, Duration::from_secs(1));
+    let error = result.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<clear_core::Error>(),
+        Some(clear_core::Error::MoveTimedOut { .. })
+    ));
+    assert_eq!(motor.get_status().await.unwrap(), Status::Ready);
+    motor
+        .wait_for_move_with_timeout(Duration::from_millis(150), Duration::from_secs(1))
+        .await
+        .unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_wait_for_move_cancellable() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = bench.motor(2, 800);
+    motor.enable().await.unwrap();
+    let cancel = CancelToken::new();
+    motor.jog(Direction::Forward, RevsPerSec(1.)).await.unwrap();
+    let (result, ()) = tokio::join!(
+        motor.wait_for_move_cancellable(Duration::from_millis(150), &cancel),
+        async {
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            cancel.cancel();
+        }
+    );
+    let error = result.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<clear_core::Error>(),
+        Some(clear_core::Error::MoveCancelled(_))
+    ));
+    assert_eq!(motor.get_status().await.unwrap(), Status::Ready);
+
+    cancel.reset();
+    motor
+        .relative_move_cancellable(Revolutions(1.), Duration::from_millis(150), &cancel)
+        .await
+        .unwrap();
+
+    // A status read failing while it waits for the stop comes back as an error
+    let controller = bench.controller();
+    controller.set_latency(
+        b"M2ST",
+        Duration::from_millis(200),
+        Duration::from_millis(200),
+    );
+    motor.jog(Direction::Forward, RevsPerSec(1.)).await.unwrap();
+    let (result, ()) = tokio::join!(
+        motor.wait_for_move_cancellable(Duration::from_millis(150), &cancel),
+        async {
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            cancel.cancel();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            controller.set_nak_probability(b"M2GS", 1.);
+        }
+    );
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_get_velocity() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = bench.motor(1, 800);
+    motor.set_velocity_verified(RevsPerSec(2.5)).await.unwrap();
+    assert_eq!(motor.get_velocity().await.unwrap(), RevsPerSec(2.5));
+
+    bench.controller().clamp_velocity(1, 1600);
+    let error = motor
+        .set_velocity_verified(RevsPerSec(3.))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<clear_core::Error>(),
+        Some(&clear_core::Error::VerificationFailed {
+            device: clear_core::Device::from_prefix(b"\x02M1"),
+            expected: 2400,
+            actual: 1600,
+        })
+    );
+    assert_eq!(motor.get_velocity().await.unwrap(), RevsPerSec(2.));
+}
+
+#[tokio::test]
+async fn test_get_torque() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = bench.motor(3, 800);
+    assert_eq!(motor.get_hlfb().await.unwrap(), Hlfb::Deasserted);
+    assert!(motor.get_torque().await.is_err());
+    bench.controller().set_torque(3, -45.5);
+    assert_eq!(motor.get_hlfb().await.unwrap(), Hlfb::Torque(-45.5));
+    assert_eq!(motor.get_torque().await.unwrap(), -45.5);
+    assert_eq!(Hlfb::parse(b"1").unwrap(), Hlfb::Asserted);
+    assert!(Hlfb::parse(b"2").is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_home() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = bench.motor(0, 800);
+    motor.relative_move(Revolutions(3.)).await.unwrap();
+    motor
+        .home(HomeTo::HardStop, RevsPerSec(1.), Revolutions(-50.))
+        .await
+        .unwrap();
+    assert_eq!(bench.controller().motor_position(0), 0);
+    let commands = bench.controller().commands();
+    assert!(commands.ends_with(&[b"\x02M0CA".to_vec(), b"\x02M0SP0".to_vec()]));
+
+    let sensor = DigitalInput::new(5, bench.sender());
+    let error = motor
+        .home(HomeTo::Sensor(&sensor), RevsPerSec(1.), Revolutions(-50.))
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("before reaching the sensor"));
+    bench.controller().set_input(5, 1);
+    motor.relative_move(Revolutions(3.)).await.unwrap();
+    motor
+        .home(HomeTo::Sensor(&sensor), RevsPerSec(1.), Revolutions(-50.))
+        .await
+        .unwrap();
+    assert_eq!(bench.controller().motor_position(0), 0);
+    let commands = bench.controller().commands();
+    assert!(commands.ends_with(&[b"\x02M0AS".to_vec(), b"\x02M0SP0".to_vec()]));
+}
+
+#[tokio::test]
+async fn test_travel_limits() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let limits = TravelLimits {
+        min: 0.,
+        max: 90.,
+        mode: LimitMode::Reject,
+    };
+    let motor = MotorBuilder::new(0, 800)
+        .with_travel_limits(limits)
+        .build(bench.sender());
+    motor.absolute_move(Revolutions(45.)).await.unwrap();
+    let error = motor.absolute_move(Revolutions(95.)).await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<clear_core::Error>(),
+        Some(clear_core::Error::TravelLimitExceeded { target, .. }) if *target == 95.
+    ));
+    assert!(motor.relative_move(Revolutions(-50.)).await.is_err());
+    assert_eq!(motor.get_position().await.unwrap(), Revolutions(45.));
+
+    let clamped = MotorBuilder::new(0, 800)
+        .with_travel_limits(TravelLimits {
+            mode: LimitMode::Clamp,
+            ..limits
+        })
+        .build(bench.sender());
+    clamped.relative_move(Revolutions(50.)).await.unwrap();
+    assert_eq!(clamped.get_position().await.unwrap(), Revolutions(90.));
+    clamped.absolute_move(Revolutions(-3.)).await.unwrap();
+    assert_eq!(clamped.get_position().await.unwrap(), Revolutions(0.));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_motor_group() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let gripper = MotorBuilder::new(1, 800)
+        .with_travel_limits(TravelLimits {
+            min: 0.,
+            max: 2.,
+            mode: LimitMode::Reject,
+        })
+        .build(bench.sender());
+    let group = MotorGroup::new(vec![bench.motor(0, 800), gripper]);
+    group
+        .move_all(&[Revolutions(10.), Revolutions(1.5)])
+        .await
+        .unwrap();
+    group
+        .wait_for_all(Duration::from_millis(100), Some(Duration::from_secs(1)))
+        .await
+        .unwrap();
+    let controller = bench.controller();
+    assert_eq!(controller.motor_position(0), 8000);
+    assert_eq!(controller.motor_position(1), 1200);
+
+    assert!(group
+        .move_all(&[Revolutions(20.), Revolutions(3.)])
+        .await
+        .is_err());
+    assert!(group.move_all(&[Revolutions(20.)]).await.is_err());
+    assert_eq!(controller.motor_position(0), 8000);
+
+    group.motors()[1].enable().await.unwrap();
+    group.motors()[1]
+        .jog(Direction::Forward, RevsPerSec(1.))
+        .await
+        .unwrap();
+    let start = Instant::now();
+    let result = group
+        .wait_for_all(Duration::from_millis(100), Some(Duration::from_secs(1)))
+        .await;
+    assert!(result.is_err());
+    assert_eq!(Instant::now() - start, Duration::from_secs(1));
+    let stops = controller
+        .commands()
+        .iter()
+        .filter(|command| command.ends_with(b"AS"))
+        .count();
+    assert_eq!(stops, 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_subscribe_position() {
+    use crate::test_support::{SimulatedController, TestBench};
+    let bench = TestBench::new();
+    let motor = bench.motor(0, 800);
+    let mut position = motor.subscribe_position(Duration::from_millis(100));
+    position.changed().await.unwrap();
+    assert_eq!(*position.borrow_and_update(), Revolutions(0.));
+    motor.absolute_move(Revolutions(5.)).await.unwrap();
+    position
+        .wait_for(|position| *position == Revolutions(5.))
+        .await
+        .unwrap();
+
+    drop(position);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let polls = |controller: &SimulatedController| {
+        controller
+            .commands()
+            .iter()
+            .filter(|command| command.ends_with(b"GP"))
+            .count()
+    };
+    let stopped_at = polls(bench.controller());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(polls(bench.controller()), stopped_at);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_status_cache() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = bench.motor(0, 800);
+    let mut first = motor.subscribe_status(Duration::from_millis(100));
+    let mut second = motor.subscribe_status(Duration::from_millis(100));
+    first
+        .wait_for(|status| *status == Status::Disabled)
+        .await
+        .unwrap();
+    motor.enable().await.unwrap();
+    second
+        .wait_for(|status| *status == Status::Ready)
+        .await
+        .unwrap();
+
+    // Both subscribers share one poller
+    let polls = || {
+        bench
+            .controller()
+            .commands()
+            .iter()
+            .filter(|command| command.ends_with(b"GS"))
+            .count()
+    };
+    let before = polls();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(polls() - before <= 11);
+
+    // A reading from before the jog must not end the wait early
+    motor.jog(Direction::Forward, RevsPerSec(1.)).await.unwrap();
+    let start = Instant::now();
+    let stopper = motor.handle(false);
+    let (waited, _) = tokio::join!(motor.wait_for_move(Duration::from_millis(10)), async {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        stopper.abrupt_stop().await.unwrap();
+    });
+    waited.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    first
+        .wait_for(|status| *status == Status::Ready)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_move_with_velocity() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = MotorBuilder::new(0, 800)
+        .with_travel_limits(TravelLimits {
+            min: 0.,
+            max: 90.,
+            mode: LimitMode::Reject,
+        })
+        .build(bench.sender());
+    motor
+        .absolute_move_with_velocity(Revolutions(45.), RevsPerSec(2.))
+        .await
+        .unwrap();
+    motor
+        .relative_move_with_velocity(Revolutions(-5.), RevsPerSec(0.5))
+        .await
+        .unwrap();
+    // A rejected target leaves the velocity alone
+    assert!(motor
+        .absolute_move_with_velocity(Revolutions(95.), RevsPerSec(3.))
+        .await
+        .is_err());
+    assert!(motor
+        .relative_move_with_velocity(Revolutions(60.), RevsPerSec(3.))
+        .await
+        .is_err());
+    let sent: Vec<Vec<u8>> = bench
+        .controller()
+        .commands()
+        .iter()
+        .map(|command| command[1..].to_vec())
+        .collect();
+    let expected: [&[u8]; 6] = [
+        b"M0SV1600",
+        b"M0AM36000",
+        b"M0GP",
+        b"M0SV400",
+        b"M0RM-4000",
+        b"M0GP",
+    ];
+    assert_eq!(sent, expected);
+}
+
+#[tokio::test]
+async fn test_motion_profile() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = bench.motor(1, 800);
+    assert!(motor.set_jerk_limit(RevsPerSec3(-1.)).await.is_err());
+    let profile = MotionProfile {
+        acceleration: RevsPerSec2(10.),
+        deceleration: RevsPerSec2(5.),
+        jerk: Some(RevsPerSec3(50.)),
+    };
+    motor.set_motion_profile(&profile).await.unwrap();
+    motor
+        .set_motion_profile(&MotionProfile {
+            jerk: None,
+            ..profile
+        })
+        .await
+        .unwrap();
+    let sent: Vec<Vec<u8>> = bench
+        .controller()
+        .commands()
+        .iter()
+        .map(|command| command[1..].to_vec())
+        .collect();
+    let expected: [&[u8]; 6] = [
+        b"M1SA8000",
+        b"M1SD4000",
+        b"M1SJ40000",
+        b"M1SA8000",
+        b"M1SD4000",
+        b"M1SJ0",
+    ];
+    assert_eq!(sent, expected);
+}
+
+#[tokio::test]
+async fn test_motor_alerts() {
+    use crate::test_support::TestBench;
+    assert_eq!(
+        MotorAlert::decode(0b100010),
+        [
+            MotorAlert::MotionCanceledPositiveLimit,
+            MotorAlert::MotorFaulted
+        ]
+    );
+    let bench = TestBench::new();
+    let motor = bench.motor(1, 800);
+    motor.enable().await.unwrap();
+    assert!(motor.recover().await.unwrap().is_empty());
+    bench.controller().set_alerts(1, 0b100001);
+    assert_eq!(motor.get_status().await.unwrap(), Status::Faulted);
+    let found = motor.recover().await.unwrap();
+    assert_eq!(
+        found,
+        [MotorAlert::MotionCanceledInAlert, MotorAlert::MotorFaulted]
+    );
+    assert_eq!(motor.get_status().await.unwrap(), Status::Ready);
+    let commands = bench.controller().commands();
+    let sent: Vec<&[u8]> = commands.iter().rev().take(5).map(|c| &c[3..]).collect();
+    assert_eq!(sent, [&b"GS"[..], b"GA", b"CA", b"EN", b"DE"]);
+}
+
+#[tokio::test]
+async fn test_motor_claim() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let locks = DeviceLocks::new();
+    let homing = bench.motor(0, 800).with_lock(&locks);
+    let jog = bench.motor(0, 800).with_lock(&locks);
+    let claimed = homing.claim().await;
+    assert!(jog.try_claim().is_err());
+    claimed.relative_move(Revolutions(1.)).await.unwrap();
+
+    let blocked = tokio::time::timeout(
+        Duration::from_millis(50),
+        jog.jog(Direction::Forward, RevsPerSec(1.)),
+    )
+    .await;
+    assert!(blocked.is_err());
+    jog.abrupt_stop().await.unwrap();
+    assert!(jog.get_status().await.is_ok());
+    drop(claimed);
+    jog.jog(Direction::Forward, RevsPerSec(1.)).await.unwrap();
+    assert_eq!(bench.controller().motor_position(0), 800);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_idle_power_down() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = bench
+        .motor(1, 800)
+        .with_idle_power_down(IdlePowerDown {
+            timeout: Duration::from_secs(5),
+            hysteresis: Duration::from_secs(8),
+        })
+        .with_motion_defaults(MotionDefaults {
+            velocity: Some(RevsPerSec(2.)),
+            acceleration: Some(RevsPerSec2(10.)),
+            deceleration: None,
+        });
+    tokio::spawn(motor.idle_monitor());
+    motor.enable().await.unwrap();
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    motor.set_velocity(RevsPerSec(2.)).await.unwrap();
+    // Idle past the timeout, but still inside the hysteresis after enabling
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(!motor.is_powered_down());
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(motor.is_powered_down());
+    assert_eq!(motor.get_status().await.unwrap(), Status::Disabled);
+
+    // Waking goes through `enable`, so the motion defaults are back before the move
+    motor.absolute_move(Revolutions(2.)).await.unwrap();
+    assert!(!motor.is_powered_down());
+    let commands = bench.controller().commands();
+    let sent: Vec<&[u8]> = commands.iter().rev().take(5).map(|c| &c[3..]).collect();
+    assert_eq!(sent, [&b"AM1600"[..], b"GS", b"SA8000", b"SV1600", b"EN"]);
+
+    // Polling the status keeps the drive awake
+    for _ in 0..6 {
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        motor.get_status().await.unwrap();
+    }
+    assert!(!motor.is_powered_down());
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    assert!(motor.is_powered_down());
+
+    // Disabling on purpose isn't undone by the next command
+    motor.disable().await.unwrap();
+    motor.set_velocity(RevsPerSec(1.)).await.unwrap();
+    assert_eq!(motor.get_status().await.unwrap(), Status::Disabled);
+}
+
+#[test]
+fn test_duty_tracker() {
+    let start = Instant::now();
+    let mut duty = DutyTracker {
+        limits: Some(DutyLimits {
+            max_run_time: Duration::from_secs(10),
+            max_moves: 3,
+        }),
+        ..Default::default()
+    };
+    duty.start(start);
+    duty.start(start + Duration::from_secs(2));
+    assert_eq!(
+        duty.stats(start + Duration::from_secs(4)),
+        DutyStats {
+            run_time: Duration::from_secs(4),
+            moves: 2
+        }
+    );
+    duty.finish(start + Duration::from_secs(6));
+    assert!(!duty.exceeded(start + Duration::from_secs(20)));
+    duty.start(start + Duration::from_secs(20));
+    assert!(duty.exceeded(start + Duration::from_secs(25)));
+    duty.finish(start + Duration::from_secs(22));
+    assert_eq!(
+        duty.stats(start + Duration::from_secs(30)).run_time,
+        Duration::from_secs(8)
+    );
+    duty.start(start + Duration::from_secs(30));
+    assert!(duty.exceeded(start + Duration::from_secs(30)));
+}
+
+#[test]
+fn test_odometer() {
+    let start = Instant::now();
+    let mut odometer = Odometer::default();
+    // Travel to the first target is unknown until the position is
+    odometer.start(Travel::To(10.), start);
+    odometer.finish(start + Duration::from_secs(1));
+    odometer.start(Travel::To(4.), start + Duration::from_secs(2));
+    odometer.start(Travel::By(-2.), start + Duration::from_secs(3));
+    odometer.finish(start + Duration::from_secs(4));
+    odometer.start(Travel::Jog(-3.), start + Duration::from_secs(5));
+    assert_eq!(odometer.stats(start + Duration::from_secs(6)).travel, 11.);
+    odometer.finish(start + Duration::from_secs(7));
+    let stats = odometer.stats(start + Duration::from_secs(10));
+    assert_eq!(
+        stats,
+        MotionStats {
+            moves: 4,
+            travel: 14.,
+            run_time: Duration::from_secs(5),
+        }
+    );
+    assert_eq!(stats.average_speed(), 2.8);
+    assert_eq!(odometer.position, None);
+}
+
+#[test]
+fn test_comms_health() {
+    let mut health = CommsHealth::default();
+    let alarm = CommsAlarm {
+        alpha: 0.5,
+        threshold: 0.4,
+    };
+    assert_eq!(health.record(&alarm, true), None);
+    assert_eq!(health.record(&alarm, false), Some(true));
+    assert_eq!(health.failure_rate, 0.5);
+    assert_eq!(health.record(&alarm, true), None);
+    assert_eq!(health.record(&alarm, true), Some(false));
+    assert_eq!((health.commands, health.failures), (4, 1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_jog_deadman() {
+    use crate::controllers::clear_core::CR;
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(10);
+    let mock_client = tokio::spawn(async move {
+        let mut sent = Vec::new();
+        while let Some(msg) = rx.recv().await {
+            let end = msg.buffer.iter().position(|&b| b == CR).unwrap();
+            sent.push(String::from_utf8_lossy(&msg.buffer[3..end]).into_owned());
+            msg.response.send(vec![2, b'M', b'0', CR]).unwrap();
+        }
+        sent
+    });
+    let motor = ClearCoreMotor::new(0, 800, tx).with_jog_limit(RevsPerSec(2.));
+    assert!(motor
+        .jog(Direction::Forward, RevsPerSec(-1.))
+        .await
+        .is_err());
+    assert!(motor
+        .jog(Direction::Forward, RevsPerSec(f64::NAN))
+        .await
+        .is_err());
+    let deadman = motor
+        .jog_with_deadman(
+            Direction::Reverse,
+            RevsPerSec(5.),
+            Duration::from_millis(500),
+        )
+        .await
+        .unwrap();
+    for _ in 0..4 {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(deadman.refresh());
+    }
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert!(!deadman.refresh());
+
+    // A move issued while the Deadman is still held isn't stopped when the window lapses
+    let deadman = motor
+        .jog_with_deadman(
+            Direction::Reverse,
+            RevsPerSec(5.),
+            Duration::from_millis(500),
+        )
+        .await
+        .unwrap();
+    motor.absolute_move(Revolutions(1.)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert!(!deadman.refresh());
+    drop(motor);
+    assert_eq!(
+        mock_client.await.unwrap(),
+        vec!["JG-1600", "AS", "JG-1600", "AM800"]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_wait_for_move() {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(10);
+    let mock_client = tokio::spawn(async move {
+        let mut polls = 0;
+        while let Some(msg) = rx.recv().await {
+            polls += 1;
+            let status = if polls < 4 { b'4' } else { b'3' };
+            msg.response.send(vec![2, b'M', b'0', status, 13]).unwrap();
+        }
+        polls
+    });
+    let motor = ClearCoreMotor::new(0, 800, tx);
+    let start = Instant::now();
+    motor.wait_for_move(Duration::from_secs(1)).await.unwrap();
+    assert_eq!(Instant::now() - start, Duration::from_secs(3));
+    drop(motor);
+    assert_eq!(mock_client.await.unwrap(), 4);
+}
+
 #[tokio::test]
 async fn test_gantry() {
     let (tx, rx) = tokio::sync::mpsc::channel(10);
@@ -296,8 +2353,8 @@ async fn test_gantry() {
         //motor.enable().await.unwrap();
         let motor_status = motor.get_status().await.unwrap();
         assert_eq!(motor_status, Status::Ready);
-        //motor.set_velocity(50.).await.unwrap();
-        motor.relative_move(-22.5).await.unwrap();
+        //motor.set_velocity(RevsPerSec(50.)).await.unwrap();
+        motor.relative_move(Revolutions(-22.5)).await.unwrap();
     });
     let (_, _) = tokio::join!(task, cc1_handler);
 }
@@ -310,10 +2367,59 @@ async fn test_gantry_pos() {
     let task = tokio::spawn(async move {
         let motor_status = motor.get_status().await.unwrap();
         assert_eq!(motor_status, Status::Ready);
-        //motor.set_velocity(50.).await.unwrap();
-        //motor.relative_move(-1.0).await.unwrap();
+        //motor.set_velocity(RevsPerSec(50.)).await.unwrap();
+        //motor.relative_move(Revolutions(-1.0)).await.unwrap();
         let pos = motor.get_position().await.unwrap();
         println!("{pos}");
     });
     let (_, _) = tokio::join!(task, cc1_handler);
 }
+
+#[tokio::test]
+async fn test_motion_defaults() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = MotorBuilder::new(0, 800)
+        .with_max_velocity(RevsPerSec(5.))
+        .with_default_velocity(RevsPerSec(2.))
+        .with_ramps(RevsPerSec2(40.), RevsPerSec2(20.))
+        .build(bench.sender());
+    motor.enable().await.unwrap();
+    motor.set_velocity(RevsPerSec(8.)).await.unwrap();
+    motor.set_velocity_verified(RevsPerSec(7.)).await.unwrap();
+    let sent: Vec<Vec<u8>> = bench
+        .controller()
+        .commands()
+        .iter()
+        .map(|command| command[1..].to_vec())
+        .collect();
+    let expected: [&[u8]; 7] = [
+        b"M0EN",
+        b"M0SV1600",
+        b"M0SA32000",
+        b"M0SD16000",
+        b"M0SV4000",
+        b"M0SV4000",
+        b"M0GV",
+    ];
+    assert_eq!(sent, expected);
+}
+
+#[tokio::test]
+async fn test_velocity_move() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let motor = MotorBuilder::new(0, 800)
+        .with_max_velocity(RevsPerSec(5.))
+        .build(bench.sender());
+    motor.enable().await.unwrap();
+    motor.velocity_move(RevsPerSec(-2.)).await.unwrap();
+    assert_eq!(motor.get_status().await.unwrap(), Status::Moving);
+    // A new velocity while running only changes the speed
+    motor.velocity_move_verified(RevsPerSec(3.)).await.unwrap();
+    motor.velocity_move_verified(RevsPerSec(-8.)).await.unwrap();
+    assert_eq!(motor.get_velocity().await.unwrap(), RevsPerSec(-5.));
+    assert!(motor.velocity_move(RevsPerSec(f64::NAN)).await.is_err());
+    motor.abrupt_stop().await.unwrap();
+    assert_eq!(motor.get_status().await.unwrap(), Status::Ready);
+}
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+// Dry cycle for checking the mechanics after maintenance without wasting product or film:
+// motors run at a fraction of their speed, heaters stay off and subsystems skip the steps
+// that need product. Clones share the switch, so one start covers every device given it
+#[derive(Debug, Clone, Default)]
+pub struct DryCycle {
+    // Speed in percent while running, 0 when off
+    speed: Arc<AtomicU8>,
+}
+
+impl DryCycle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Velocities are scaled to `speed_percent`, on top of the feed override
+    pub fn start(&self, speed_percent: u8) {
+        self.speed
+            .store(speed_percent.clamp(1, 100), Ordering::Relaxed);
+    }
+
+    pub fn stop(&self) {
+        self.speed.store(0, Ordering::Relaxed);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.speed().is_some()
+    }
+
+    pub fn speed(&self) -> Option<u8> {
+        match self.speed.load(Ordering::Relaxed) {
+            0 => None,
+            speed => Some(speed),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_dry_cycle() {
+    use crate::components::clear_core_io::{Output, OutputState};
+    use crate::test_support::TestBench;
+    use crate::util::units::RevsPerSec;
+    let bench = TestBench::new();
+    let dry_cycle = DryCycle::new();
+    let motor = bench.motor(0, 800).with_dry_cycle(dry_cycle.clone());
+    let heater = Output::new(3, bench.sender()).with_dry_cycle(dry_cycle.clone());
+
+    dry_cycle.start(25);
+    motor.set_velocity(RevsPerSec(4.)).await.unwrap();
+    assert_eq!(motor.get_velocity().await.unwrap(), RevsPerSec(1.));
+    heater.set_state(OutputState::On).await.unwrap();
+    assert_eq!(bench.controller().output(3), 0);
+
+    dry_cycle.stop();
+    assert!(!dry_cycle.is_running());
+    motor.set_velocity(RevsPerSec(4.)).await.unwrap();
+    assert_eq!(motor.get_velocity().await.unwrap(), RevsPerSec(4.));
+    heater.set_state(OutputState::On).await.unwrap();
+    assert_eq!(bench.controller().output(3), 32700);
+}
@@ -1,15 +1,28 @@
 use phidget::{devices::VoltageRatioInput, Phidget};
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::sleep;
 use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::time::Instant;
 
 const TIMEOUT: Duration = phidget::TIMEOUT_DEFAULT;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadCellEvent {
+    Disconnected { phidget_id: i32, channel_id: i32 },
+    Reconnected { phidget_id: i32, channel_id: i32 },
+}
+
 pub struct LoadCell {
     phidget_id: i32,
     channel_id: i32,
     vin: VoltageRatioInput,
+    opened: Arc<AtomicBool>,
+    attached: Arc<AtomicBool>,
+    reattached: Arc<AtomicBool>,
+    events: Option<UnboundedSender<LoadCellEvent>>,
 }
 impl LoadCell {
     pub fn new(phidget_id: i32, channel_id: i32) -> Self {
@@ -18,13 +31,65 @@ impl LoadCell {
             phidget_id,
             channel_id,
             vin,
+            opened: Arc::new(AtomicBool::new(false)),
+            attached: Arc::new(AtomicBool::new(false)),
+            reattached: Arc::new(AtomicBool::new(false)),
+            events: None,
         }
     }
 
+    // Must be set before connecting, the attach/detach handlers are installed in connect
+    pub fn set_events(&mut self, events: UnboundedSender<LoadCellEvent>) {
+        self.events = Some(events);
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.attached.load(Ordering::SeqCst)
+    }
+
+    fn install_handlers(&mut self) -> Result<(), Box<dyn Error>> {
+        let (phidget_id, channel_id) = (self.phidget_id, self.channel_id);
+        let (opened, attached, reattached) = (
+            self.opened.clone(),
+            self.attached.clone(),
+            self.reattached.clone(),
+        );
+        let events = self.events.clone();
+        self.vin.set_on_attach_handler(move |_| {
+            // The first attach happens inside open_wait, only later ones are re-attaches
+            if !attached.swap(true, Ordering::SeqCst) && opened.load(Ordering::SeqCst) {
+                println!("Load cell {phidget_id}:{channel_id} reconnected");
+                reattached.store(true, Ordering::SeqCst);
+                if let Some(events) = &events {
+                    let _ = events.send(LoadCellEvent::Reconnected {
+                        phidget_id,
+                        channel_id,
+                    });
+                }
+            }
+        })?;
+        let attached = self.attached.clone();
+        let events = self.events.clone();
+        self.vin.set_on_detach_handler(move |_| {
+            attached.store(false, Ordering::SeqCst);
+            println!("WARNING: Load cell {phidget_id}:{channel_id} disconnected");
+            if let Some(events) = &events {
+                let _ = events.send(LoadCellEvent::Disconnected {
+                    phidget_id,
+                    channel_id,
+                });
+            }
+        })?;
+        Ok(())
+    }
+
     pub fn connect(&mut self) -> Result<(), Box<dyn Error>> {
         self.vin.set_serial_number(self.phidget_id)?;
         self.vin.set_channel(self.channel_id)?;
+        self.install_handlers()?;
         self.vin.open_wait(TIMEOUT)?;
+        self.attached.store(true, Ordering::SeqCst);
+        self.opened.store(true, Ordering::SeqCst);
         let min_data_interval = self.vin.min_data_interval()?;
         self.vin.set_data_interval(min_data_interval)?;
         sleep(Duration::from_millis(3000));
@@ -35,15 +100,37 @@ impl LoadCell {
         Ok(())
     }
 
-    pub fn get_reading(&self) -> Result<f64, Box<dyn Error>> {
+    fn reopen(&mut self) -> Result<(), Box<dyn Error>> {
+        // Phidget22 re-attaches an open channel by itself but drops its settings, so the data
+        // interval has to be applied again before readings are trusted
+        let min_data_interval = self.vin.min_data_interval()?;
+        self.vin.set_data_interval(min_data_interval)?;
+        println!(
+            "Channel {:} re-opened for Phidget {:}",
+            self.channel_id, self.phidget_id
+        );
+        Ok(())
+    }
+
+    pub fn get_reading(&mut self) -> Result<f64, Box<dyn Error>> {
         // Gets the reading of a load cell from
         // Phidget.
+        if self.opened.load(Ordering::SeqCst) && !self.is_attached() {
+            return Err(format!(
+                "Load cell {}:{} is disconnected",
+                self.phidget_id, self.channel_id
+            )
+            .into());
+        }
+        if self.reattached.swap(false, Ordering::SeqCst) {
+            self.reopen()?;
+        }
         let reading = self.vin.voltage_ratio()?;
         Ok(reading)
     }
 
     pub fn diagnose(
-        &self,
+        &mut self,
         duration: Duration,
         sample_rate: usize,
     ) -> Result<(Vec<Duration>, Vec<f64>), Box<dyn Error>> {
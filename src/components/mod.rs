@@ -1,5 +1,10 @@
+pub mod analog_source;
 pub mod clear_core_io;
 pub mod clear_core_motor;
+pub mod dry_cycle;
 pub mod load_cell;
+pub mod motion_journal;
+pub mod push_button;
+pub mod relay;
 pub mod scale;
 pub mod send_recv;
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalEntry {
+    // Last absolute target commanded, in revs
+    Target(f64),
+    // A relative move or jog was commanded, so the axis position can't be checked
+    Unknown,
+}
+
+// Last commanded target per axis, written to disk before each command is sent. On disk it's
+// one "axis target" line per axis, with "unknown" for axes that can't be checked
+#[derive(Debug, Clone)]
+pub struct MotionJournal {
+    path: PathBuf,
+    entries: Arc<Mutex<HashMap<String, JournalEntry>>>,
+    // Held for a whole write and rename, axes share the file
+    writer: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl MotionJournal {
+    // A missing file is a fresh journal, where every axis needs homing
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = HashMap::new();
+        if path.exists() {
+            for line in std::fs::read_to_string(&path)?.lines() {
+                let Some((axis, target)) = line.rsplit_once(' ') else {
+                    continue;
+                };
+                let entry = match target {
+                    "unknown" => JournalEntry::Unknown,
+                    target => JournalEntry::Target(target.parse()?),
+                };
+                entries.insert(axis.to_string(), entry);
+            }
+        }
+        Ok(Self {
+            path,
+            entries: Arc::new(Mutex::new(entries)),
+            writer: Arc::default(),
+        })
+    }
+
+    pub fn entry(&self, axis: &str) -> Option<JournalEntry> {
+        self.entries.lock().unwrap().get(axis).copied()
+    }
+
+    // Only changes are written, so repeated jogs and speed changes don't touch the disk
+    pub async fn record(&self, axis: &str, entry: JournalEntry) -> Result<(), Box<dyn Error>> {
+        let _writer = self.writer.lock().await;
+        let contents = {
+            let mut entries = self.entries.lock().unwrap().clone();
+            if entries.insert(axis.to_string(), entry) == Some(entry) {
+                return Ok(());
+            }
+            let mut lines: Vec<String> = entries
+                .iter()
+                .map(|(axis, entry)| match entry {
+                    JournalEntry::Target(target) => format!("{axis} {target}\n"),
+                    JournalEntry::Unknown => format!("{axis} unknown\n"),
+                })
+                .collect();
+            lines.sort();
+            lines.concat()
+        };
+        // Written aside, flushed to disk and renamed so a crash mid-write never leaves a torn
+        // journal
+        let temp = self.path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&temp).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&temp, &self.path).await?;
+        // Kept in memory only once on disk, so a failed write is retried by the next record
+        self.entries.lock().unwrap().insert(axis.to_string(), entry);
+        Ok(())
+    }
+
+    // After a restart, homing can be skipped only if the axis still reports the target it was
+    // last sent to
+    pub fn homing_required(&self, axis: &str, reported: f64, tolerance: f64) -> bool {
+        match self.entry(axis) {
+            Some(JournalEntry::Target(target)) => (reported - target).abs() > tolerance,
+            _ => true,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_motion_journal() {
+    let path = std::env::temp_dir().join(format!("motion_journal_{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let journal = MotionJournal::open(&path).unwrap();
+    assert!(journal.homing_required("gantry", 0., 0.01));
+    journal
+        .record("gantry", JournalEntry::Target(24.5))
+        .await
+        .unwrap();
+    journal
+        .record("bag gripper", JournalEntry::Unknown)
+        .await
+        .unwrap();
+
+    // Concurrent records from several axes all make it to disk
+    let mut records = Vec::new();
+    for axis in 0..10 {
+        let journal = journal.clone();
+        records.push(tokio::spawn(async move {
+            let axis = format!("axis {axis}");
+            journal
+                .record(&axis, JournalEntry::Target(1.))
+                .await
+                .unwrap();
+        }));
+    }
+    for record in records {
+        record.await.unwrap();
+    }
+
+    let reopened = MotionJournal::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(reopened.entry("axis 7"), Some(JournalEntry::Target(1.)));
+    // An unchanged entry isn't written again
+    journal
+        .record("bag gripper", JournalEntry::Unknown)
+        .await
+        .unwrap();
+    assert!(!path.exists());
+    assert_eq!(reopened.entry("gantry"), Some(JournalEntry::Target(24.5)));
+    assert!(!reopened.homing_required("gantry", 24.505, 0.01));
+    assert!(reopened.homing_required("gantry", 0., 0.01));
+    assert!(reopened.homing_required("bag gripper", 0., 0.01));
+}
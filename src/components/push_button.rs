@@ -0,0 +1,190 @@
+use crate::components::clear_core_io::DigitalInput;
+use std::error::Error;
+use tokio::time::{Duration, Instant};
+
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(30);
+const DEFAULT_LONG_PRESS: Duration = Duration::from_secs(2);
+const DEFAULT_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Press {
+    // Released before the long press time
+    Short,
+    // Reported as soon as the button has been held long enough, not on release
+    Long,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PressTiming {
+    // A level has to hold this long before it counts, contact bounce is a few ms
+    pub debounce: Duration,
+    pub long_press: Duration,
+}
+
+impl Default for PressTiming {
+    fn default() -> Self {
+        Self {
+            debounce: DEFAULT_DEBOUNCE,
+            long_press: DEFAULT_LONG_PRESS,
+        }
+    }
+}
+
+// Turns raw button reads into presses
+pub struct PressClassifier {
+    timing: PressTiming,
+    // Debounced level and since when, if the button is held
+    pressed_since: Option<Instant>,
+    // Raw level that differs from the debounced one and when it was first seen
+    pending: Option<(bool, Instant)>,
+    long_reported: bool,
+}
+
+impl PressClassifier {
+    pub fn new(timing: PressTiming) -> Self {
+        Self {
+            timing,
+            pressed_since: None,
+            pending: None,
+            long_reported: false,
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed_since.is_some()
+    }
+
+    pub fn update(&mut self, raw: bool, now: Instant) -> Option<Press> {
+        if raw == self.is_pressed() {
+            self.pending = None;
+        } else {
+            let (_, since) = *self.pending.get_or_insert((raw, now));
+            if now - since >= self.timing.debounce {
+                self.pending = None;
+                // Dated from the first read at the new level, not from when it was accepted
+                match raw {
+                    true => {
+                        self.pressed_since = Some(since);
+                        self.long_reported = false;
+                    }
+                    false => {
+                        let long_reported = self.long_reported;
+                        self.pressed_since = None;
+                        return (!long_reported).then_some(Press::Short);
+                    }
+                }
+            }
+        }
+        match self.pressed_since {
+            Some(since) if !self.long_reported && now - since >= self.timing.long_press => {
+                self.long_reported = true;
+                Some(Press::Long)
+            }
+            _ => None,
+        }
+    }
+}
+
+// A physical start, stop or reset button on a machine without an HMI
+pub struct PushButton {
+    input: DigitalInput,
+    classifier: PressClassifier,
+    poll_interval: Duration,
+    // Stop buttons are usually wired normally closed so a broken wire reads as pressed
+    normally_closed: bool,
+}
+
+impl PushButton {
+    pub fn new(input: DigitalInput) -> Self {
+        Self {
+            input,
+            classifier: PressClassifier::new(PressTiming::default()),
+            poll_interval: DEFAULT_POLL,
+            normally_closed: false,
+        }
+    }
+
+    pub fn with_timing(mut self, timing: PressTiming) -> Self {
+        self.classifier = PressClassifier::new(timing);
+        self
+    }
+
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn normally_closed(mut self) -> Self {
+        self.normally_closed = true;
+        self
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.classifier.is_pressed()
+    }
+
+    // Polls until the next press; call it in a loop to handle presses as they come
+    pub async fn pressed(&mut self) -> Result<Press, Box<dyn Error>> {
+        loop {
+            let raw = self.input.get_state().await? != self.normally_closed;
+            if let Some(press) = self.classifier.update(raw, Instant::now()) {
+                return Ok(press);
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+}
+
+#[test]
+fn test_press_classifier() {
+    let start = Instant::now();
+    let at = |ms: u64| start + Duration::from_millis(ms);
+    let mut classifier = PressClassifier::new(PressTiming {
+        debounce: Duration::from_millis(30),
+        long_press: Duration::from_millis(1000),
+    });
+    // Bounce on contact, a short press, then a held one
+    let reads = [
+        (0, true),
+        (10, false),
+        (20, true),
+        (40, true),
+        (60, true),
+        (300, false),
+        (340, false),
+        (500, true),
+        (540, true),
+        (1600, true),
+        (2000, false),
+        (2040, false),
+    ];
+    let presses: Vec<(u64, Press)> = reads
+        .iter()
+        .filter_map(|(ms, raw)| Some((*ms, classifier.update(*raw, at(*ms))?)))
+        .collect();
+    assert_eq!(presses, [(340, Press::Short), (1600, Press::Long)]);
+    assert!(!classifier.is_pressed());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_push_button() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let controller = bench.controller().clone();
+    let mut button = PushButton::new(DigitalInput::new(4, bench.sender()));
+    let operator = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        controller.set_input(4, 1);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        controller.set_input(4, 0);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        controller.set_input(4, 1);
+    };
+    let presses = async {
+        let short = button.pressed().await.unwrap();
+        let long = button.pressed().await.unwrap();
+        (short, long)
+    };
+    let (_, presses) = tokio::join!(operator, presses);
+    assert_eq!(presses, (Press::Short, Press::Long));
+}
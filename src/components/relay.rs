@@ -0,0 +1,134 @@
+use crate::components::clear_core_io::{Output, OutputState};
+use std::error::Error;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RelayTiming {
+    // Shortest time the contacts stay closed, or open, before the relay may switch again
+    pub min_on: Duration,
+    pub min_off: Duration,
+}
+
+struct RelayState {
+    // Unknown until the first command
+    state: Option<OutputState>,
+    switched_at: Option<Instant>,
+    switches: u64,
+}
+
+// A mechanical relay or contactor on a ClearCore output. A fast control loop can ask for a
+// new state every cycle; the relay holds each state for its minimum time so the contacts
+// don't chatter
+pub struct Relay {
+    output: Output,
+    timing: RelayTiming,
+    state: Mutex<RelayState>,
+}
+
+impl Relay {
+    pub fn new(output: Output) -> Self {
+        Self {
+            output,
+            timing: RelayTiming::default(),
+            state: Mutex::new(RelayState {
+                state: None,
+                switched_at: None,
+                switches: 0,
+            }),
+        }
+    }
+
+    pub fn with_timing(mut self, timing: RelayTiming) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    // Switches once the current state has been held for its minimum time, waiting if needed
+    pub async fn set_state(&self, state: OutputState) -> Result<(), Box<dyn Error>> {
+        let mut relay = self.state.lock().await;
+        if relay.state == Some(state) {
+            return Ok(());
+        }
+        if let Some(allowed) = self.allowed_at(&relay) {
+            tokio::time::sleep_until(allowed).await;
+        }
+        self.switch(&mut relay, state).await
+    }
+
+    // For control loops: switches only if the minimum time is up and reports whether the
+    // relay is now in the requested state
+    pub async fn try_set_state(&self, state: OutputState) -> Result<bool, Box<dyn Error>> {
+        let mut relay = self.state.lock().await;
+        if relay.state == Some(state) {
+            return Ok(true);
+        }
+        if self
+            .allowed_at(&relay)
+            .is_some_and(|allowed| allowed > Instant::now())
+        {
+            return Ok(false);
+        }
+        self.switch(&mut relay, state).await?;
+        Ok(true)
+    }
+
+    pub async fn state(&self) -> Option<OutputState> {
+        self.state.lock().await.state
+    }
+
+    // Commanded changes of state, to track contact wear. The first command isn't counted as
+    // the relay's state before it is unknown
+    pub async fn switch_count(&self) -> u64 {
+        self.state.lock().await.switches
+    }
+
+    fn allowed_at(&self, relay: &RelayState) -> Option<Instant> {
+        let hold = match relay.state? {
+            OutputState::On => self.timing.min_on,
+            OutputState::Off => self.timing.min_off,
+        };
+        Some(relay.switched_at? + hold)
+    }
+
+    async fn switch(
+        &self,
+        relay: &mut RelayState,
+        state: OutputState,
+    ) -> Result<(), Box<dyn Error>> {
+        self.output.set_state(state).await?;
+        if relay.state.is_some() {
+            relay.switches += 1;
+        }
+        relay.state = Some(state);
+        relay.switched_at = Some(Instant::now());
+        Ok(())
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_timing() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let relay = Relay::new(Output::new(2, bench.sender())).with_timing(RelayTiming {
+        min_on: Duration::from_secs(1),
+        min_off: Duration::from_millis(500),
+    });
+    let start = Instant::now();
+    relay.set_state(OutputState::On).await.unwrap();
+    assert_eq!(bench.controller().output(2), 32700);
+
+    // A control loop asking for Off too soon is held off
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!relay.try_set_state(OutputState::Off).await.unwrap());
+    assert!(relay.try_set_state(OutputState::On).await.unwrap());
+    assert_eq!(relay.state().await, Some(OutputState::On));
+
+    relay.set_state(OutputState::Off).await.unwrap();
+    assert_eq!(start.elapsed(), Duration::from_secs(1));
+    assert_eq!(bench.controller().output(2), 0);
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(relay.try_set_state(OutputState::On).await.unwrap());
+    assert_eq!(relay.switch_count().await, 2);
+}
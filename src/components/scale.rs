@@ -1,14 +1,213 @@
-use crate::components::load_cell::LoadCell;
+use crate::components::analog_source::AnalogSource;
+use crate::components::load_cell::{LoadCell, LoadCellEvent};
 use linalg::MatrixError;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::sleep;
+use std::time::SystemTime;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
 use tokio::time::{Duration, Instant};
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum WeightEstimator {
+    #[default]
+    Median,
+    // Fraction of samples dropped from each end before averaging, e.g. 0.2
+    TrimmedMean(f64),
+    // Centre of the most populated bin of the given width in grams
+    BinnedMode(f64),
+}
+
+impl WeightEstimator {
+    fn estimate(&self, weights: &mut [f64]) -> f64 {
+        weights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        match *self {
+            WeightEstimator::Median => weights[weights.len() / 2],
+            WeightEstimator::TrimmedMean(fraction) => {
+                let trim = (weights.len() as f64 * fraction.clamp(0., 0.49)) as usize;
+                let kept = &weights[trim..weights.len() - trim];
+                kept.iter().sum::<f64>() / kept.len() as f64
+            }
+            WeightEstimator::BinnedMode(width) => {
+                let bin = |weight: f64| (weight / width).floor() as i64;
+                let mut best = (bin(weights[0]), 0);
+                let mut current = best;
+                for &weight in weights.iter() {
+                    if bin(weight) == current.0 {
+                        current.1 += 1;
+                    } else {
+                        current = (bin(weight), 1);
+                    }
+                    if current.1 > best.1 {
+                        best = current;
+                    }
+                }
+                (best.0 as f64 + 0.5) * width
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ThermalCompensation {
+    // Grams of apparent weight change per degree C, measured per scale
+    pub coefficient: f64,
+    // Ambient temperature in degrees C the scale was calibrated at
+    pub reference: f64,
+}
+
+impl ThermalCompensation {
+    fn correction(&self, temperature: f64) -> f64 {
+        self.coefficient * (temperature - self.reference)
+    }
+}
+
+// Publishes the ambient temperature for scales to pick up from their blocking reads
+pub async fn track_temperature<F: AnalogSource>(
+    source: F,
+    to_celsius: impl Fn(isize) -> f64,
+    interval: Duration,
+    temperature: watch::Sender<f64>,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        let counts = source.get_value().await?;
+        temperature.send_replace(to_celsius(counts));
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// Checked between samples so long blocking reads can be abandoned from another task
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    // Re-arms the token for the next operation
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum WeightUnit {
+    #[default]
+    Grams,
+    Kilograms,
+    Pounds,
+    Ounces,
+}
+
+// Which scale took a weight and how finely, for QA traceability. Copied into every report
+// from the source's `info`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScaleInfo {
+    pub serial: String,
+    // Smallest step the scale resolves, in `unit`
+    pub resolution: f64,
+    #[serde(default)]
+    pub unit: WeightUnit,
+    #[serde(default)]
+    pub last_calibrated: Option<SystemTime>,
+}
+
+// Anything a dispense can weigh with: the local Phidget scale, a simulated scale, or one
+// living on another host. Calls block, so nodes run them on the blocking pool and a remote
+// source can do its round trip in here
+pub trait WeightSource: Send + 'static {
+    fn live_weight(&mut self) -> Result<f64, Box<dyn Error>>;
+
+    // Returns no weight if `cancel` fires before the window is over
+    fn settled_weight(
+        &mut self,
+        time: Duration,
+        sample_rate: usize,
+        estimator: WeightEstimator,
+        cancel: &CancelToken,
+    ) -> Result<Option<f64>, Box<dyn Error>> {
+        let mut weights = Vec::new();
+        let delay = Duration::from_secs_f64(1. / sample_rate as f64);
+        let start_time = Instant::now();
+        while Instant::now() - start_time <= time {
+            if cancel.is_cancelled() {
+                return Ok(None);
+            }
+            weights.push(self.live_weight()?);
+            sleep(delay);
+        }
+        if cancel.is_cancelled() {
+            return Ok(None);
+        }
+        if weights.is_empty() {
+            return Err(Box::from("No scale samples taken"));
+        }
+        Ok(Some(estimator.estimate(&mut weights)))
+    }
+
+    // Per-cell readings before calibration, only meaningful for sources with load cells
+    fn raw_readings(&mut self) -> Result<Vec<f64>, Box<dyn Error>> {
+        Err(Box::from("Weight source has no raw readings"))
+    }
+
+    // Zeroes the current live weight
+    fn tare(&mut self) -> Result<(), Box<dyn Error>> {
+        Err(Box::from("Weight source can't be tared"))
+    }
+
+    // Traceability details, if the source was given any
+    fn info(&self) -> Option<ScaleInfo> {
+        None
+    }
+}
+
+impl WeightSource for Box<dyn WeightSource> {
+    fn live_weight(&mut self) -> Result<f64, Box<dyn Error>> {
+        (**self).live_weight()
+    }
+
+    fn settled_weight(
+        &mut self,
+        time: Duration,
+        sample_rate: usize,
+        estimator: WeightEstimator,
+        cancel: &CancelToken,
+    ) -> Result<Option<f64>, Box<dyn Error>> {
+        (**self).settled_weight(time, sample_rate, estimator, cancel)
+    }
+
+    fn raw_readings(&mut self) -> Result<Vec<f64>, Box<dyn Error>> {
+        (**self).raw_readings()
+    }
+
+    fn tare(&mut self) -> Result<(), Box<dyn Error>> {
+        (**self).tare()
+    }
+
+    fn info(&self) -> Option<ScaleInfo> {
+        (**self).info()
+    }
+}
+
 pub struct Scale {
     cells: [LoadCell; 4],
     cell_coefficients: Vec<f64>,
     tare_offset: f64,
+    thermal: Option<(watch::Receiver<f64>, ThermalCompensation)>,
+    info: Option<ScaleInfo>,
 }
 
 impl Scale {
@@ -25,7 +224,32 @@ impl Scale {
             cells,
             cell_coefficients: vec![1.; 4],
             tare_offset: 0.,
+            thermal: None,
+            info: None,
+        }
+    }
+
+    pub fn with_thermal_compensation(
+        mut scale: Self,
+        temperature: watch::Receiver<f64>,
+        compensation: ThermalCompensation,
+    ) -> Self {
+        scale.thermal = Some((temperature, compensation));
+        scale
+    }
+
+    // Update `last_calibrated` along with the coefficients
+    pub fn with_info(mut scale: Self, info: ScaleInfo) -> Self {
+        scale.info = Some(info);
+        scale
+    }
+
+    pub fn with_events(mut scale: Self, events: UnboundedSender<LoadCellEvent>) -> Self {
+        // Reports cells dropping off and coming back, cells re-open themselves on re-attach
+        for cell in scale.cells.iter_mut() {
+            cell.set_events(events.clone());
         }
+        scale
     }
 
     pub fn connect(mut scale: Self) -> Result<Self, Box<dyn Error>> {
@@ -35,46 +259,46 @@ impl Scale {
         Ok(scale)
     }
 
-    fn get_readings(scale: Self) -> Result<(Self, Vec<f64>), Box<dyn Error>> {
-        // Gets each load cell reading from Phidget
-        // and returns them in a matrix.
-
-        let mut readings = vec![0.; 4];
-        for cell in 0..scale.cells.len() {
-            readings[cell] = scale.cells[cell].get_reading()?;
-        }
+    pub fn get_readings(mut scale: Self) -> Result<(Self, Vec<f64>), Box<dyn Error>> {
+        let readings = scale.raw_readings()?;
         Ok((scale, readings))
     }
 
     pub fn live_weigh(mut scale: Self) -> Result<(Self, f64), Box<dyn Error>> {
-        // Gets the instantaneous weight measurement
-        // from the scale by taking the sum of each
-        // load cell's reading, weighted by its
-        // coefficient.
-        let readings: Vec<f64>;
-        (scale, readings) = Scale::get_readings(scale)?;
-        let weight = dot(readings, scale.cell_coefficients.clone()) - scale.tare_offset;
+        let weight = scale.live_weight()?;
         Ok((scale, weight))
     }
 
     pub fn weight_by_median(
-        mut scale: Self,
+        scale: Self,
         time: Duration,
         sample_rate: usize,
     ) -> Result<(Self, f64), Box<dyn Error>> {
-        let mut weights = Vec::new();
-        let delay = Duration::from_secs_f64(1. / sample_rate as f64);
-        let start_time = Instant::now();
-        scale = loop {
-            if Instant::now() - start_time > time {
-                break scale;
-            }
-            let weight: f64;
-            (scale, weight) = Scale::live_weigh(scale)?;
-            weights.push(weight);
-            sleep(delay);
-        };
-        Ok((scale, Scale::median(&mut weights)))
+        Scale::weight_by_estimator(scale, time, sample_rate, WeightEstimator::Median)
+    }
+
+    pub fn weight_by_estimator(
+        scale: Self,
+        time: Duration,
+        sample_rate: usize,
+        estimator: WeightEstimator,
+    ) -> Result<(Self, f64), Box<dyn Error>> {
+        let cancel = CancelToken::new();
+        let (scale, weight) =
+            Scale::weight_by_estimator_cancellable(scale, time, sample_rate, estimator, &cancel)?;
+        Ok((scale, weight.ok_or("Scale read cancelled")?))
+    }
+
+    // Returns no weight if `cancel` fires before the window is over, the scale always comes back
+    pub fn weight_by_estimator_cancellable(
+        mut scale: Self,
+        time: Duration,
+        sample_rate: usize,
+        estimator: WeightEstimator,
+        cancel: &CancelToken,
+    ) -> Result<(Self, Option<f64>), Box<dyn Error>> {
+        let weight = scale.settled_weight(time, sample_rate, estimator, cancel)?;
+        Ok((scale, weight))
     }
 
     fn median(weights: &mut Vec<f64>) -> f64 {
@@ -83,7 +307,7 @@ impl Scale {
         weights[middle]
     }
 
-    pub fn get_medians(scale: Self, time: Duration, sample_rate: f64) -> (Self, Vec<f64>) {
+    pub fn get_medians(mut scale: Self, time: Duration, sample_rate: f64) -> (Self, Vec<f64>) {
         let mut readings: Vec<Vec<f64>> = vec![vec![]; 4];
         let mut medians = vec![0.; 4];
         let delay = Duration::from_secs_f64(1. / sample_rate);
@@ -139,6 +363,40 @@ impl Scale {
     }
 }
 
+impl WeightSource for Scale {
+    fn live_weight(&mut self) -> Result<f64, Box<dyn Error>> {
+        // Gets the instantaneous weight measurement
+        // from the scale by taking the sum of each
+        // load cell's reading, weighted by its
+        // coefficient.
+        let readings = self.raw_readings()?;
+        let mut weight = dot(readings, self.cell_coefficients.clone()) - self.tare_offset;
+        if let Some((temperature, compensation)) = &self.thermal {
+            weight -= compensation.correction(*temperature.borrow());
+        }
+        Ok(weight)
+    }
+
+    fn raw_readings(&mut self) -> Result<Vec<f64>, Box<dyn Error>> {
+        // Gets each load cell reading from Phidget
+        // and returns them in a matrix.
+        let mut readings = vec![0.; 4];
+        for cell in 0..self.cells.len() {
+            readings[cell] = self.cells[cell].get_reading()?;
+        }
+        Ok(readings)
+    }
+
+    fn tare(&mut self) -> Result<(), Box<dyn Error>> {
+        self.tare_offset += self.live_weight()?;
+        Ok(())
+    }
+
+    fn info(&self) -> Option<ScaleInfo> {
+        self.info.clone()
+    }
+}
+
 fn dot(vec1: Vec<f64>, vec2: Vec<f64>) -> f64 {
     assert_eq!(vec1.len(), vec2.len());
     let mut sum = 0.;
@@ -214,6 +472,58 @@ fn test_median() {
     let ans = Scale::median(&mut arr);
     assert_eq!(ans, 3.);
 }
+
+#[test]
+fn test_cancel_token() {
+    let cancel = CancelToken::new();
+    let shared = cancel.clone();
+    assert!(!cancel.is_cancelled());
+    shared.cancel();
+    assert!(cancel.is_cancelled());
+    cancel.reset();
+    assert!(!shared.is_cancelled());
+}
+
+#[test]
+fn test_boxed_weight_source() {
+    struct Sequence(Vec<f64>);
+    impl WeightSource for Sequence {
+        fn live_weight(&mut self) -> Result<f64, Box<dyn Error>> {
+            self.0.pop().ok_or_else(|| Box::from("Out of samples"))
+        }
+    }
+    let mut source: Box<dyn WeightSource> = Box::new(Sequence(vec![12.; 100]));
+    assert_eq!(source.live_weight().unwrap(), 12.);
+    assert!(source.raw_readings().is_err());
+    let cancel = CancelToken::new();
+    let window = Duration::from_millis(20);
+    let settled = source.settled_weight(window, 1000, WeightEstimator::Median, &cancel);
+    assert_eq!(settled.unwrap(), Some(12.));
+    cancel.cancel();
+    let settled = source.settled_weight(window, 1000, WeightEstimator::Median, &cancel);
+    assert_eq!(settled.unwrap(), None);
+}
+
+#[test]
+fn test_thermal_compensation() {
+    let compensation = ThermalCompensation {
+        coefficient: 0.25,
+        reference: 20.,
+    };
+    assert_eq!(compensation.correction(20.), 0.);
+    assert_eq!(compensation.correction(32.), 3.);
+    assert_eq!(compensation.correction(8.), -3.);
+}
+
+#[test]
+fn test_weight_estimators() {
+    let samples = vec![10., 10.4, 10.6, 11., 11.2, 11.4, 11.6, 50.];
+    assert_eq!(WeightEstimator::Median.estimate(&mut samples.clone()), 11.2);
+    let trimmed = WeightEstimator::TrimmedMean(0.25).estimate(&mut samples.clone());
+    assert!((trimmed - 11.05).abs() < 1e-9);
+    let mode = WeightEstimator::BinnedMode(1.).estimate(&mut samples.clone());
+    assert_eq!(mode, 11.5);
+}
 //
 // #[test]
 // fn calibrate_scale() -> Result<(), Box<dyn Error>> {
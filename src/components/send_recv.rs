@@ -1,8 +1,43 @@
-use crate::controllers::clear_core::Message;
+use crate::controllers::clear_core::{self, Message, CR};
+use crate::util::utils::ascii_to_int;
 use std::error::Error;
 use std::future::Future;
 use tokio::sync::{mpsc, oneshot};
 
+pub trait Reply: Sized {
+    // Parses the payload of a reply, i.e. everything between the echoed prefix and the CR
+    fn parse(payload: &[u8]) -> Result<Self, Box<dyn Error>>;
+}
+
+impl Reply for () {
+    fn parse(_payload: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+impl Reply for isize {
+    fn parse(payload: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if !payload.iter().any(u8::is_ascii_digit) {
+            return Err(Box::from("Reply has no numeric payload"));
+        }
+        Ok(ascii_to_int(payload))
+    }
+}
+
+impl Reply for bool {
+    fn parse(payload: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(isize::parse(payload)? == 1)
+    }
+}
+
+pub fn reply_payload(reply: &[u8], prefix_len: usize) -> Result<&[u8], Box<dyn Error>> {
+    let end = reply.iter().position(|&b| b == CR).unwrap_or(reply.len());
+    if end < prefix_len {
+        return Err(Box::from("Reply shorter than command prefix"));
+    }
+    Ok(&reply[prefix_len..end])
+}
+
 pub trait SendRecv {
     fn get_sender(&self) -> &mpsc::Sender<Message>;
     //fn get_receiver(&self) -> mpsc::Receiver<Message>;
@@ -22,4 +57,48 @@ pub trait SendRecv {
             Ok(res)
         }
     }
+
+    fn request<T: Reply>(
+        &self,
+        prefix: &[u8],
+        body: &[u8],
+    ) -> impl Future<Output = Result<T, Box<dyn Error>>> + Send
+    where
+        Self: Sync,
+    {
+        let mut cmd = Vec::with_capacity(prefix.len() + body.len() + 1);
+        cmd.extend_from_slice(prefix);
+        cmd.extend_from_slice(body);
+        cmd.push(CR);
+        let prefix_len = prefix.len();
+        async move {
+            // Every failure carries the device, mnemonic and raw reply so field logs are actionable
+            let (prefix, body) = cmd.split_at(prefix_len);
+            let body = &body[..body.len() - 1];
+            let res = match self.write(cmd.as_slice()).await {
+                Ok(res) => res,
+                Err(e) => {
+                    return Err(
+                        clear_core::Error::command_failed(prefix, body, &[], e.to_string()).into(),
+                    )
+                }
+            };
+            let parsed = reply_payload(res.as_slice(), prefix_len).and_then(T::parse);
+            parsed.map_err(|e| {
+                clear_core::Error::command_failed(prefix, body, res.as_slice(), e.to_string())
+                    .into()
+            })
+        }
+    }
+}
+
+#[test]
+fn test_reply_parsing() {
+    let reply = [2, b'I', b'3', b'-', b'4', b'2', CR, 0, 0];
+    let payload = reply_payload(reply.as_slice(), 3).unwrap();
+    assert_eq!(payload, b"-42");
+    assert_eq!(isize::parse(payload).unwrap(), -42);
+    assert!(bool::parse(b"1").unwrap());
+    assert!(isize::parse(b"").is_err());
+    assert!(reply_payload(&[2, CR], 3).is_err());
 }
@@ -1,15 +1,373 @@
-use std::error::Error;
-use tokio::sync::{mpsc, oneshot};
+use crate::interface::tcp::client;
+use crate::util::utils::{ascii_to_int, int_to_byte, num_to_bytes};
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::net::ToSocketAddrs;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{Duration, Instant};
 
 pub const STX: u8 = 2;
 pub const CR: u8 = 13;
 pub const RESULT_IDX: u8 = 3;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    Motor,
+    Input,
+    Output,
+    AnalogOutput,
+    Expansion,
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub kind: DeviceKind,
+    pub id: String,
+}
+
+impl Device {
+    pub fn from_prefix(prefix: &[u8]) -> Self {
+        let kind = match prefix.get(1) {
+            Some(b'M') => DeviceKind::Motor,
+            Some(b'I') => DeviceKind::Input,
+            Some(b'O') => DeviceKind::Output,
+            Some(b'A') => DeviceKind::AnalogOutput,
+            Some(b'X') => DeviceKind::Expansion,
+            _ => DeviceKind::Unknown,
+        };
+        let id = prefix
+            .iter()
+            .skip(2)
+            .map(|b| b.wrapping_sub(48).to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        Self { kind, id }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {}", self.kind, self.id)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    CommandFailed {
+        device: Device,
+        command: String,
+        reply: String,
+        reason: String,
+    },
+    VerificationFailed {
+        device: Device,
+        expected: isize,
+        actual: isize,
+    },
+    GuardOpen(Device),
+    LowAirPressure,
+    MoveTimedOut {
+        device: Device,
+        timeout: Duration,
+    },
+    MoveCancelled(Device),
+    TravelLimitExceeded {
+        device: Device,
+        target: f64,
+        min: f64,
+        max: f64,
+    },
+    Interlocked {
+        device: Device,
+        other: Device,
+        group: String,
+    },
+}
+
+impl Error {
+    pub fn command_failed(prefix: &[u8], command: &[u8], reply: &[u8], reason: String) -> Self {
+        Error::CommandFailed {
+            device: Device::from_prefix(prefix),
+            command: String::from_utf8_lossy(command).into_owned(),
+            reply: printable(reply),
+            reason,
+        }
+    }
+}
+
+fn printable(reply: &[u8]) -> String {
+    // Replies arrive in a zero padded buffer; keep the framed part and escape control bytes
+    let end = reply
+        .iter()
+        .position(|&b| b == CR)
+        .map_or(reply.len(), |cr| cr + 1);
+    reply[..end].escape_ascii().to_string()
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::CommandFailed {
+                device,
+                command,
+                reply,
+                reason,
+            } => write!(
+                f,
+                "{device} command {command} failed: {reason} (reply: \"{reply}\")"
+            ),
+            Error::VerificationFailed {
+                device,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{device} readback verification failed: expected {expected}, read {actual}"
+            ),
+            Error::GuardOpen(device) => write!(f, "{device} motion blocked while a guard is open"),
+            Error::LowAirPressure => write!(f, "Actuation inhibited by low air pressure"),
+            Error::MoveTimedOut { device, timeout } => {
+                write!(f, "{device} still moving after {timeout:?}, stopped")
+            }
+            Error::MoveCancelled(device) => write!(f, "{device} move cancelled, stopped"),
+            Error::TravelLimitExceeded {
+                device,
+                target,
+                min,
+                max,
+            } => write!(
+                f,
+                "{device} target {target} is outside its travel limits {min} to {max}"
+            ),
+            Error::Interlocked {
+                device,
+                other,
+                group,
+            } => write!(
+                f,
+                "{device} can't turn on while {other} is on (interlock {group})"
+            ),
+        }
+    }
+}
+
+impl StdError for Error {}
+
 pub struct Message {
     pub buffer: Vec<u8>,
     pub response: oneshot::Sender<Vec<u8>>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailsafeState {
+    Off,
+    // Leave the output wherever it was last commanded
+    Hold,
+    Value(i16),
+}
+
+// Commands the client writes when its channel closes or the link drops, shared by every
+// output that registers a failsafe state
+#[derive(Clone, Default)]
+pub struct Failsafes {
+    commands: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Failsafes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, prefix: &[u8], state: FailsafeState) {
+        let value = match state {
+            FailsafeState::Off => 0,
+            FailsafeState::Hold => return,
+            FailsafeState::Value(value) => value,
+        };
+        let mut cmd = prefix.to_vec();
+        cmd.extend_from_slice(num_to_bytes(value).as_slice());
+        cmd.push(CR);
+        self.commands.lock().unwrap().push(cmd);
+    }
+
+    pub fn commands(&self) -> Vec<Vec<u8>> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+// Firmware that supports change notifications pushes "STX E <input prefix> <state> CR" frames
+// unsolicited, e.g. "\x02EI41\r" when IO-4 goes high
+pub const EVENT_MARKER: u8 = b'E';
+
+type FrameHandler = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+// Latest pushed state per input, filled in by a client started with `client_with_events`.
+// Inputs opt in with `DigitalInput::changes`. Other unsolicited frames are routed by the tag
+// byte after STX to handlers registered with `with_handler`
+#[derive(Clone, Default)]
+pub struct InputEvents {
+    watchers: Arc<Mutex<HashMap<Vec<u8>, watch::Sender<bool>>>>,
+    handlers: Arc<Mutex<HashMap<u8, FrameHandler>>>,
+}
+
+impl InputEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn subscribe(&self, prefix: &[u8], state: bool) -> watch::Receiver<bool> {
+        let mut watchers = self.watchers.lock().unwrap();
+        let watcher = watchers
+            .entry(prefix.to_vec())
+            .or_insert_with(|| watch::channel(state).0);
+        watcher.send_replace(state);
+        watcher.subscribe()
+    }
+
+    // The tag must be one the firmware never uses for replies, otherwise replies to requests
+    // would be taken for pushed frames. The handler gets the frame up to but not including CR
+    pub fn with_handler<F>(self, tag: u8, handler: F) -> Self
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.handlers.lock().unwrap().insert(tag, Arc::new(handler));
+        self
+    }
+
+    pub fn is_event(frame: &[u8]) -> bool {
+        frame.first() == Some(&STX) && frame.get(1) == Some(&EVENT_MARKER)
+    }
+
+    // Input changes and frames with a registered tag, which never answer a pending request
+    pub fn is_unsolicited(&self, frame: &[u8]) -> bool {
+        Self::is_event(frame) || self.handler(frame).is_some()
+    }
+
+    fn handler(&self, frame: &[u8]) -> Option<FrameHandler> {
+        if frame.first() != Some(&STX) {
+            return None;
+        }
+        let tag = frame.get(1)?;
+        self.handlers.lock().unwrap().get(tag).cloned()
+    }
+
+    // Returns false if the frame is neither a well formed event nor has a registered handler
+    pub(crate) fn publish(&self, frame: &[u8]) -> bool {
+        let end = frame.iter().position(|&b| b == CR).unwrap_or(frame.len());
+        if let Some(handler) = self.handler(frame) {
+            handler(&frame[..end]);
+            return true;
+        }
+        if !Self::is_event(frame) || end < 4 {
+            return false;
+        }
+        let state = match frame[end - 1] {
+            b'0' => false,
+            b'1' => true,
+            _ => return false,
+        };
+        let mut prefix = vec![STX];
+        prefix.extend_from_slice(&frame[2..end - 1]);
+        if let Some(watcher) = self.watchers.lock().unwrap().get(&prefix) {
+            watcher.send_replace(state);
+        }
+        true
+    }
+}
+
+// Firmware that echoes sequence tags accepts "STX # <tag> <command>" and answers with
+// "STX # <tag> <reply>", so a late reply to an earlier command can't be taken for the answer
+// to the current one. Tags cycle through 'a'..='z'
+pub const TAG_MARKER: u8 = b'#';
+
+pub struct SequenceTags {
+    next: u8,
+}
+
+impl SequenceTags {
+    pub fn new() -> Self {
+        Self { next: b'a' }
+    }
+
+    // Returns the tag used along with the tagged frame
+    pub fn tag(&mut self, frame: &[u8]) -> (u8, Vec<u8>) {
+        let tag = self.next;
+        self.next = if tag == b'z' { b'a' } else { tag + 1 };
+        let mut tagged = Vec::with_capacity(frame.len() + 2);
+        tagged.push(STX);
+        tagged.extend_from_slice(&[TAG_MARKER, tag]);
+        tagged.extend_from_slice(frame.strip_prefix(&[STX]).unwrap_or(frame));
+        (tag, tagged)
+    }
+
+    // One reply frame with its tag removed, or None if it answers some other command. Untagged
+    // replies, from firmware that doesn't echo, are passed through as they are
+    pub fn untag(reply: &[u8], tag: u8) -> Option<Vec<u8>> {
+        match reply {
+            [STX, TAG_MARKER, echoed, rest @ ..] if *echoed == tag => Some([&[STX], rest].concat()),
+            [STX, TAG_MARKER, ..] => None,
+            _ => Some(reply.to_vec()),
+        }
+    }
+}
+
+impl Default for SequenceTags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// One claim lock per device prefix, so separately constructed handles to the same device
+// serialize against each other. Use one registry per controller, prefixes repeat across them
+type DeviceLock = Arc<tokio::sync::Mutex<()>>;
+
+#[derive(Clone, Default)]
+pub struct DeviceLocks {
+    locks: Arc<Mutex<HashMap<Vec<u8>, DeviceLock>>>,
+}
+
+impl DeviceLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn lock(&self, prefix: &[u8]) -> DeviceLock {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(prefix.to_vec())
+            .or_default()
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputSnapshot {
+    // Each input is its own round trip, so the values weren't read at the same moment. These
+    // are when the first reply arrived and how long after it the last one did, which bounds how
+    // far apart the readings are
+    pub taken_at: Instant,
+    pub spread: Duration,
+    pub values: Vec<(u8, isize)>,
+}
+
+impl InputSnapshot {
+    pub fn analog(&self, id: u8) -> Option<isize> {
+        self.values
+            .iter()
+            .find(|(input, _)| *input == id)
+            .map(|(_, value)| *value)
+    }
+
+    pub fn digital(&self, id: u8) -> Option<bool> {
+        self.analog(id).map(|value| value == 1)
+    }
+}
+
 pub struct Controller {
     sender: mpsc::Sender<Message>,
 }
@@ -18,7 +376,25 @@ impl Controller {
     pub fn new(sender: mpsc::Sender<Message>) -> Self {
         Controller { sender }
     }
-    pub async fn write(&self, buffer: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+
+    // Hands back the client future instead of spawning it, so embedders can spawn, select on
+    // or join it themselves. ClientHandle::spawn is the fire-and-forget counterpart
+    pub fn with_client<T: ToSocketAddrs>(
+        addr: T,
+        buffer: usize,
+    ) -> (
+        Self,
+        impl Future<Output = Result<(), Box<dyn StdError + Send + Sync>>>,
+    ) {
+        let (sender, rx) = mpsc::channel(buffer);
+        (Controller::new(sender), client(addr, rx))
+    }
+
+    // For constructing motors and IO on this controller
+    pub fn sender(&self) -> mpsc::Sender<Message> {
+        self.sender.clone()
+    }
+    pub async fn write(&self, buffer: &[u8]) -> Result<Vec<u8>, Box<dyn StdError>> {
         let (resp_tx, resp_rx) = oneshot::channel();
         let msg = Message {
             buffer: buffer.to_vec(),
@@ -28,6 +404,77 @@ impl Controller {
         let res = resp_rx.await?;
         Ok(res)
     }
+
+    pub async fn raw_command(&self, command: &[u8]) -> Result<Vec<u8>, Box<dyn StdError>> {
+        // Frames an arbitrary firmware command so it can be exercised without a dedicated
+        // component, returning the reply payload with the STX/CR framing removed. A command
+        // carrying its own framing bytes would go out as two frames and throw every later
+        // reply out of step, so it's refused
+        if command.iter().any(|&b| b == STX || b == CR) {
+            return Err(Box::from("Raw command must not contain STX or CR"));
+        }
+        let [kind, id, body @ ..] = command else {
+            return Err(Box::from("Raw command needs a device prefix"));
+        };
+        let prefix = [STX, *kind, *id];
+        let mut buffer = Vec::with_capacity(command.len() + 2);
+        buffer.push(STX);
+        buffer.extend_from_slice(command);
+        buffer.push(CR);
+        let res = self.write(buffer.as_slice()).await?;
+        if !res.starts_with(&prefix) {
+            let reason = "Reply is for another device".to_string();
+            return Err(Error::command_failed(&prefix, body, &res, reason).into());
+        }
+        parse_reply(res.as_slice())
+    }
+
+    pub async fn read_all_inputs(&self, ids: &[u8]) -> Result<InputSnapshot, Box<dyn StdError>> {
+        // The firmware has no bulk read, so the client still makes one round trip per input and
+        // commands from other tasks can land in between. Queuing every read up front only saves
+        // this task waiting on each reply before sending the next
+        let mut pending = Vec::with_capacity(ids.len());
+        for &id in ids {
+            let prefix = [STX, b'I', int_to_byte(id)];
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let mut buffer = prefix.to_vec();
+            buffer.push(CR);
+            self.sender
+                .send(Message {
+                    buffer,
+                    response: resp_tx,
+                })
+                .await?;
+            pending.push((id, prefix, resp_rx));
+        }
+        let mut values = Vec::with_capacity(pending.len());
+        let mut taken_at = None;
+        for (id, prefix, resp_rx) in pending {
+            let reply = resp_rx.await?;
+            taken_at.get_or_insert_with(Instant::now);
+            let end = reply.iter().position(|&b| b == CR).unwrap_or(reply.len());
+            let payload = reply.get(prefix.len()..end).unwrap_or_default();
+            if !reply.starts_with(&prefix) || !payload.iter().any(u8::is_ascii_digit) {
+                let reason = "Reply has no numeric payload".to_string();
+                return Err(Error::command_failed(&prefix, &[], &reply, reason).into());
+            }
+            values.push((id, ascii_to_int(payload)));
+        }
+        let taken_at = taken_at.unwrap_or_else(Instant::now);
+        Ok(InputSnapshot {
+            taken_at,
+            spread: Instant::now() - taken_at,
+            values,
+        })
+    }
+}
+
+pub fn parse_reply(reply: &[u8]) -> Result<Vec<u8>, Box<dyn StdError>> {
+    let start = usize::from(reply.first() == Some(&STX));
+    match reply.iter().position(|&b| b == CR) {
+        Some(end) if end >= start => Ok(reply[start..end].to_vec()),
+        _ => Err(Box::from("Malformed reply from controller")),
+    }
 }
 
 #[tokio::test]
@@ -70,3 +517,156 @@ async fn test_controller() {
     controller_task_2.await.unwrap();
     controller_task_3.await.unwrap();
 }
+
+#[tokio::test]
+async fn test_raw_command() {
+    let (tx, mut rx) = mpsc::channel::<Message>(10);
+    let mock_client = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            assert_eq!(&msg.buffer[3..], [b'G', b'S', CR]);
+            let mut reply = vec![STX, b'M', b'0', b'3', CR];
+            reply.resize(100, 0);
+            msg.response.send(reply).unwrap();
+        }
+    });
+    let controller = Controller::new(tx);
+    let reply = controller.raw_command(b"M0GS").await.unwrap();
+    assert_eq!(reply.as_slice(), b"M03");
+    assert!(controller.raw_command(b"M0GS\r\x02M0AS").await.is_err());
+    assert!(controller.raw_command(b"M").await.is_err());
+    let error = controller.raw_command(b"M1GS").await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<Error>(),
+        Some(Error::CommandFailed { .. })
+    ));
+    drop(controller);
+    mock_client.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_read_all_inputs() {
+    let (tx, mut rx) = mpsc::channel::<Message>(10);
+    let mock_client = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let value: &[u8] = match msg.buffer[2] {
+                b'0' => b"1",
+                b'1' => b"0",
+                _ => b"2047",
+            };
+            let mut reply = msg.buffer[..3].to_vec();
+            reply.extend_from_slice(value);
+            reply.push(CR);
+            msg.response.send(reply).unwrap();
+        }
+    });
+    let controller = Controller::new(tx);
+    let snapshot = controller.read_all_inputs(&[0, 1, 3]).await.unwrap();
+    assert_eq!(snapshot.digital(0), Some(true));
+    assert_eq!(snapshot.digital(1), Some(false));
+    assert_eq!(snapshot.analog(3), Some(2047));
+    assert_eq!(snapshot.analog(2), None);
+    drop(controller);
+    mock_client.await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_input_snapshot_spread() {
+    // The first reply takes long to come back, then the rest follow 10 ms apart
+    let (tx, mut rx) = mpsc::channel::<Message>(10);
+    tokio::spawn(async move {
+        let mut delay = Duration::from_millis(500);
+        while let Some(msg) = rx.recv().await {
+            tokio::time::sleep(delay).await;
+            delay = Duration::from_millis(10);
+            let mut reply = msg.buffer[..3].to_vec();
+            reply.extend_from_slice(b"1");
+            reply.push(CR);
+            msg.response.send(reply).unwrap();
+        }
+    });
+    let start = Instant::now();
+    let snapshot = Controller::new(tx)
+        .read_all_inputs(&[0, 1, 2])
+        .await
+        .unwrap();
+    assert_eq!(snapshot.taken_at - start, Duration::from_millis(500));
+    assert_eq!(snapshot.spread, Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn test_controller_with_client() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buffer = [0; 100];
+        while let Ok(n) = socket.read(&mut buffer).await {
+            if n == 0 {
+                break;
+            }
+            socket.write_all(&buffer[..n]).await.unwrap();
+        }
+    });
+    let (controller, client) = Controller::with_client(addr, 10);
+    let client = tokio::spawn(client);
+    let reply = controller.raw_command(b"I0").await.unwrap();
+    assert_eq!(reply, b"I0".to_vec());
+    drop(controller);
+    client.await.unwrap().unwrap();
+}
+
+#[test]
+fn test_error_context() {
+    let err = Error::command_failed(
+        &[STX, b'M', b'2'],
+        b"GP",
+        &[STX, b'M', b'2', CR, 0, 0],
+        "Reply has no numeric payload".to_string(),
+    );
+    assert_eq!(
+        err.to_string(),
+        "Motor 2 command GP failed: Reply has no numeric payload (reply: \"\\x02M2\\r\")"
+    );
+    assert_eq!(Device::from_prefix(&[STX, b'X', b'1', b'7']).id, "1.7");
+}
+
+#[test]
+fn test_sequence_tags() {
+    let mut tags = SequenceTags::new();
+    let (tag, tagged) = tags.tag(&[STX, b'I', b'4', CR]);
+    assert_eq!(tagged, b"\x02#aI4\r");
+    assert_eq!(tags.tag(b"\x02I4\r").0, b'b');
+    let reply = SequenceTags::untag(b"\x02#aI41\r", tag).unwrap();
+    assert_eq!(reply, b"\x02I41\r");
+    assert_eq!(SequenceTags::untag(b"\x02#zI40\r", tag), None);
+    assert_eq!(
+        SequenceTags::untag(b"\x02I40\r", tag).unwrap(),
+        b"\x02I40\r"
+    );
+    for _ in 0..24 {
+        tags.tag(b"\x02I4\r");
+    }
+    assert_eq!(tags.tag(b"\x02I4\r").0, b'a');
+}
+
+#[test]
+fn test_failsafes() {
+    let failsafes = Failsafes::new();
+    failsafes.register(&[STX, b'O', b'3'], FailsafeState::Off);
+    failsafes.register(&[STX, b'O', b'4'], FailsafeState::Hold);
+    failsafes.register(&[STX, b'A', b'0'], FailsafeState::Value(1024));
+    assert_eq!(
+        failsafes.commands(),
+        vec![b"\x02O30\r".to_vec(), b"\x02A01024\r".to_vec()]
+    );
+}
+
+#[test]
+fn test_parse_reply() {
+    assert_eq!(
+        parse_reply(&[STX, b'I', b'1', b'1', CR, 0, 0]).unwrap(),
+        b"I11"
+    );
+    assert!(parse_reply(&[0, 0, 0]).is_err());
+}
@@ -1 +1,2 @@
 pub mod clear_core;
+pub mod registry;
@@ -0,0 +1,591 @@
+use crate::components::clear_core_io::{
+    AnalogInput, AnalogOutput, DigitalInput, HBridge, InterlockGroup, InterlockMode, Output,
+};
+use crate::components::clear_core_motor::{ClearCoreMotor, IdlePowerDown};
+use crate::controllers::clear_core::Message;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use tokio::sync::mpsc::Sender;
+
+// ClearCore has four motor connectors, IO-0..IO-5 can drive outputs and every connector up
+// to A-12 can be read
+const MAX_MOTOR_ID: u8 = 3;
+const MAX_OUTPUT_ID: u8 = 5;
+const MAX_INPUT_ID: u8 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum DeviceType {
+    Motor { scale: isize },
+    DigitalInput,
+    AnalogInput,
+    Output,
+    AnalogOutput,
+    HBridge { power: i16 },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub controller: String,
+    pub device: DeviceType,
+    pub id: u8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScaleConfig {
+    pub name: String,
+    pub serial: i32,
+}
+
+// References devices by name: the two relay outputs and the position feedback input
+#[derive(Debug, Clone, Deserialize)]
+pub struct HatchConfig {
+    pub name: String,
+    pub outputs: (String, String),
+    pub feedback: String,
+}
+
+// Outputs, by device name, that must never be on together
+#[derive(Debug, Clone, Deserialize)]
+pub struct InterlockConfig {
+    pub name: String,
+    pub outputs: Vec<String>,
+    #[serde(default)]
+    pub mode: InterlockMode,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MachineLayout {
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub scales: Vec<ScaleConfig>,
+    #[serde(default)]
+    pub hatches: Vec<HatchConfig>,
+    #[serde(default)]
+    pub interlocks: Vec<InterlockConfig>,
+}
+
+// Every problem found in a layout, reported together so a file can be fixed in one pass
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutErrors(pub Vec<String>);
+
+impl fmt::Display for LayoutErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Machine layout has {} problem(s):", self.0.len())?;
+        for error in &self.0 {
+            writeln!(f, "  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for LayoutErrors {}
+
+impl MachineLayout {
+    pub fn validate(&self, controllers: &[&str]) -> Result<(), LayoutErrors> {
+        let mut errors = Vec::new();
+        let mut names: HashMap<&str, &DeviceConfig> = HashMap::new();
+        // Users of each (controller, firmware device letter, id)
+        let mut channels: HashMap<(&str, u8, u8), Vec<&str>> = HashMap::new();
+        for device in &self.devices {
+            if !controllers.contains(&device.controller.as_str()) {
+                errors.push(format!(
+                    "Device {} references unknown controller {}",
+                    device.name, device.controller
+                ));
+            }
+            if names.insert(device.name.as_str(), device).is_some() {
+                errors.push(format!("Device {} is defined more than once", device.name));
+            }
+            let (letter, max_id) = match device.device {
+                DeviceType::Motor { .. } => (b'M', MAX_MOTOR_ID),
+                DeviceType::Output | DeviceType::HBridge { .. } => (b'O', MAX_OUTPUT_ID),
+                DeviceType::AnalogOutput => (b'A', MAX_OUTPUT_ID),
+                DeviceType::DigitalInput | DeviceType::AnalogInput => (b'I', MAX_INPUT_ID),
+            };
+            if device.id > max_id {
+                errors.push(format!(
+                    "Device {} uses {:?} id {}, the highest available is {max_id}",
+                    device.name, device.device, device.id
+                ));
+            }
+            channels
+                .entry((device.controller.as_str(), letter, device.id))
+                .or_default()
+                .push(device.name.as_str());
+        }
+        // Inputs can be read under several names, anything that drives a channel can't share it
+        let mut conflicts: Vec<String> = channels
+            .iter()
+            .filter(|((_, letter, _), users)| *letter != b'I' && users.len() > 1)
+            .map(|((controller, letter, id), users)| {
+                format!(
+                    "Devices {} share {}{id} on {controller}",
+                    users.join(", "),
+                    *letter as char
+                )
+            })
+            .collect();
+        conflicts.sort();
+        errors.extend(conflicts);
+
+        let mut serials = HashMap::new();
+        for scale in &self.scales {
+            if let Some(other) = serials.insert(scale.serial, scale.name.as_str()) {
+                errors.push(format!(
+                    "Scales {other} and {} both use Phidget serial {}",
+                    scale.name, scale.serial
+                ));
+            }
+        }
+
+        for hatch in &self.hatches {
+            let mut check = |name: &str, expected: DeviceType, role: &str| match names.get(name) {
+                None => errors.push(format!(
+                    "Hatch {} {role} {name} is not a configured device",
+                    hatch.name
+                )),
+                Some(device) if device.device != expected => errors.push(format!(
+                    "Hatch {} {role} {name} is configured as {:?}, expected {expected:?}",
+                    hatch.name, device.device
+                )),
+                Some(_) => {}
+            };
+            check(&hatch.outputs.0, DeviceType::Output, "output");
+            check(&hatch.outputs.1, DeviceType::Output, "output");
+            check(&hatch.feedback, DeviceType::AnalogInput, "feedback");
+        }
+
+        let mut interlocked: HashMap<&str, &str> = HashMap::new();
+        for interlock in &self.interlocks {
+            if interlock.outputs.len() < 2 {
+                errors.push(format!(
+                    "Interlock {} needs at least two outputs",
+                    interlock.name
+                ));
+            }
+            for output in &interlock.outputs {
+                match names.get(output.as_str()) {
+                    None => errors.push(format!(
+                        "Interlock {} output {output} is not a configured device",
+                        interlock.name
+                    )),
+                    Some(device) if device.device != DeviceType::Output => errors.push(format!(
+                        "Interlock {} output {output} is configured as {:?}, expected Output",
+                        interlock.name, device.device
+                    )),
+                    Some(_) => {}
+                }
+                if let Some(other) = interlocked.insert(output.as_str(), interlock.name.as_str()) {
+                    errors.push(format!(
+                        "Output {output} is in both interlocks {other} and {}",
+                        interlock.name
+                    ));
+                }
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(LayoutErrors(errors)),
+        }
+    }
+}
+
+// Idle power-down for every motor the registry hands out, except axes that must hold position
+// such as vertical ones
+#[derive(Debug, Clone)]
+pub struct IdlePolicy {
+    pub power_down: IdlePowerDown,
+    pub exempt: Vec<String>,
+}
+
+pub struct DeviceRegistry {
+    controllers: HashMap<String, Sender<Message>>,
+    devices: HashMap<String, DeviceConfig>,
+    idle_policy: Option<IdlePolicy>,
+    // Keyed by output name
+    interlocks: HashMap<String, InterlockGroup>,
+}
+
+impl DeviceRegistry {
+    pub fn new(controllers: HashMap<String, Sender<Message>>) -> Self {
+        Self {
+            controllers,
+            devices: HashMap::new(),
+            idle_policy: None,
+            interlocks: HashMap::new(),
+        }
+    }
+
+    pub fn from_config(
+        controllers: HashMap<String, Sender<Message>>,
+        devices: Vec<DeviceConfig>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut registry = Self::new(controllers);
+        for device in devices {
+            registry.register(device)?;
+        }
+        Ok(registry)
+    }
+
+    // Validates the whole layout up front, reporting every problem at once
+    pub fn from_layout(
+        controllers: HashMap<String, Sender<Message>>,
+        layout: &MachineLayout,
+    ) -> Result<Self, Box<dyn Error>> {
+        let names: Vec<&str> = controllers.keys().map(String::as_str).collect();
+        layout.validate(names.as_slice())?;
+        let mut registry = Self::from_config(controllers, layout.devices.clone())?;
+        for interlock in &layout.interlocks {
+            registry.register_interlock(interlock)?;
+        }
+        Ok(registry)
+    }
+
+    pub fn with_idle_policy(mut self, policy: IdlePolicy) -> Self {
+        self.idle_policy = Some(policy);
+        self
+    }
+
+    pub fn register(&mut self, device: DeviceConfig) -> Result<(), Box<dyn Error>> {
+        if !self.controllers.contains_key(&device.controller) {
+            return Err(Box::from(format!(
+                "Device {} references unknown controller {}",
+                device.name, device.controller
+            )));
+        }
+        if self.devices.contains_key(&device.name) {
+            return Err(Box::from(format!(
+                "Device {} registered twice",
+                device.name
+            )));
+        }
+        self.devices.insert(device.name.clone(), device);
+        Ok(())
+    }
+
+    // Outputs handed out afterwards share the group, so turning one on is checked against the rest
+    pub fn register_interlock(&mut self, config: &InterlockConfig) -> Result<(), Box<dyn Error>> {
+        for output in &config.outputs {
+            let (device, _) = self.lookup(output)?;
+            if device.device != DeviceType::Output {
+                return Err(Self::wrong_type(output, device.device));
+            }
+            if self.interlocks.contains_key(output) {
+                return Err(Box::from(format!("Output {output} is interlocked twice")));
+            }
+        }
+        let group = InterlockGroup::new(&config.name, config.mode);
+        for output in &config.outputs {
+            self.interlocks.insert(output.clone(), group.clone());
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<(&DeviceConfig, Sender<Message>), Box<dyn Error>> {
+        let device = self
+            .devices
+            .get(name)
+            .ok_or_else(|| format!("Unknown device {name}"))?;
+        let sender = self.controllers[&device.controller].clone();
+        Ok((device, sender))
+    }
+
+    fn wrong_type(name: &str, device: DeviceType) -> Box<dyn Error> {
+        Box::from(format!("Device {name} is configured as {device:?}"))
+    }
+
+    // Every registered device with its kind, sorted by name so listings are stable between runs
+    pub fn devices(&self) -> impl Iterator<Item = &DeviceConfig> {
+        let mut devices: Vec<&DeviceConfig> = self.devices.values().collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        devices.into_iter()
+    }
+
+    // Builds every device the constructor accepts, skipping those of other kinds
+    fn typed<'a, T, F>(&'a self, build: F) -> impl Iterator<Item = (&'a str, T)> + 'a
+    where
+        T: 'a,
+        F: Fn(&Self, &str) -> Result<T, Box<dyn Error>> + 'a,
+    {
+        self.devices()
+            .filter_map(move |device| Some((device.name.as_str(), build(self, &device.name).ok()?)))
+    }
+
+    pub fn motors(&self) -> impl Iterator<Item = (&str, ClearCoreMotor)> {
+        self.typed(Self::motor)
+    }
+
+    pub fn digital_inputs(&self) -> impl Iterator<Item = (&str, DigitalInput)> {
+        self.typed(Self::digital_input)
+    }
+
+    pub fn analog_inputs(&self) -> impl Iterator<Item = (&str, AnalogInput)> {
+        self.typed(Self::analog_input)
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = (&str, Output)> {
+        self.typed(Self::output)
+    }
+
+    pub fn analog_outputs(&self) -> impl Iterator<Item = (&str, AnalogOutput)> {
+        self.typed(Self::analog_output)
+    }
+
+    pub fn h_bridges(&self) -> impl Iterator<Item = (&str, HBridge)> {
+        self.typed(Self::h_bridge)
+    }
+
+    pub fn motor(&self, name: &str) -> Result<ClearCoreMotor, Box<dyn Error>> {
+        match self.lookup(name)? {
+            (
+                DeviceConfig {
+                    device: DeviceType::Motor { scale },
+                    id,
+                    ..
+                },
+                sender,
+            ) => {
+                let motor = ClearCoreMotor::new(*id, *scale, sender);
+                match &self.idle_policy {
+                    Some(policy) if !policy.exempt.iter().any(|axis| axis == name) => {
+                        Ok(motor.with_idle_power_down(policy.power_down))
+                    }
+                    _ => Ok(motor),
+                }
+            }
+            (device, _) => Err(Self::wrong_type(name, device.device)),
+        }
+    }
+
+    pub fn digital_input(&self, name: &str) -> Result<DigitalInput, Box<dyn Error>> {
+        match self.lookup(name)? {
+            (device, sender) if device.device == DeviceType::DigitalInput => {
+                Ok(DigitalInput::new(device.id, sender))
+            }
+            (device, _) => Err(Self::wrong_type(name, device.device)),
+        }
+    }
+
+    pub fn analog_input(&self, name: &str) -> Result<AnalogInput, Box<dyn Error>> {
+        match self.lookup(name)? {
+            (device, sender) if device.device == DeviceType::AnalogInput => {
+                Ok(AnalogInput::new(device.id, sender))
+            }
+            (device, _) => Err(Self::wrong_type(name, device.device)),
+        }
+    }
+
+    pub fn output(&self, name: &str) -> Result<Output, Box<dyn Error>> {
+        match self.lookup(name)? {
+            (device, sender) if device.device == DeviceType::Output => {
+                let output = Output::new(device.id, sender);
+                match self.interlocks.get(name) {
+                    Some(group) => Ok(output.with_interlock(group)),
+                    None => Ok(output),
+                }
+            }
+            (device, _) => Err(Self::wrong_type(name, device.device)),
+        }
+    }
+
+    pub fn analog_output(&self, name: &str) -> Result<AnalogOutput, Box<dyn Error>> {
+        match self.lookup(name)? {
+            (device, sender) if device.device == DeviceType::AnalogOutput => {
+                Ok(AnalogOutput::new(device.id, sender))
+            }
+            (device, _) => Err(Self::wrong_type(name, device.device)),
+        }
+    }
+
+    pub fn h_bridge(&self, name: &str) -> Result<HBridge, Box<dyn Error>> {
+        match self.lookup(name)? {
+            (
+                DeviceConfig {
+                    device: DeviceType::HBridge { power },
+                    id,
+                    ..
+                },
+                sender,
+            ) => Ok(HBridge::new(*id, *power, sender)),
+            (device, _) => Err(Self::wrong_type(name, device.device)),
+        }
+    }
+}
+
+#[test]
+fn test_device_registry() {
+    let (tx, _rx) = tokio::sync::mpsc::channel::<Message>(10);
+    let controllers = HashMap::from([("cc1".to_string(), tx)]);
+    let device = |name: &str, controller: &str, device| DeviceConfig {
+        name: name.to_string(),
+        controller: controller.to_string(),
+        device,
+        id: 1,
+    };
+    let registry = DeviceRegistry::from_config(
+        controllers.clone(),
+        vec![
+            device("hopper_a_motor", "cc1", DeviceType::Motor { scale: 800 }),
+            device("bag_photo_eye", "cc1", DeviceType::DigitalInput),
+        ],
+    )
+    .unwrap();
+    assert_eq!(registry.motor("hopper_a_motor").unwrap().id(), 1);
+    assert!(registry.digital_input("bag_photo_eye").is_ok());
+    assert!(registry.motor("bag_photo_eye").is_err());
+    assert!(registry.output("missing").is_err());
+    let names: Vec<&str> = registry
+        .devices()
+        .map(|device| device.name.as_str())
+        .collect();
+    assert_eq!(names, ["bag_photo_eye", "hopper_a_motor"]);
+    let motors: Vec<(&str, u8)> = registry
+        .motors()
+        .map(|(name, motor)| (name, motor.id()))
+        .collect();
+    assert_eq!(motors, [("hopper_a_motor", 1)]);
+    assert_eq!(registry.digital_inputs().count(), 1);
+    assert_eq!(registry.outputs().count(), 0);
+    assert!(DeviceRegistry::from_config(
+        controllers,
+        vec![device("blower", "cc2", DeviceType::Output)]
+    )
+    .is_err());
+}
+
+#[test]
+fn test_layout_validation() {
+    let device = |name: &str, controller: &str, device, id| DeviceConfig {
+        name: name.to_string(),
+        controller: controller.to_string(),
+        device,
+        id,
+    };
+    let mut layout = MachineLayout {
+        devices: vec![
+            device("gantry", "cc1", DeviceType::Motor { scale: 800 }, 0),
+            device("hatch_a_open", "cc1", DeviceType::Output, 2),
+            device("hatch_a_close", "cc1", DeviceType::Output, 3),
+            device("hatch_a_position", "cc1", DeviceType::AnalogInput, 3),
+            device("hatch_a_position_raw", "cc1", DeviceType::DigitalInput, 3),
+        ],
+        scales: vec![ScaleConfig {
+            name: "node_a".to_string(),
+            serial: 716620,
+        }],
+        hatches: vec![HatchConfig {
+            name: "hatch_a".to_string(),
+            outputs: ("hatch_a_open".to_string(), "hatch_a_close".to_string()),
+            feedback: "hatch_a_position".to_string(),
+        }],
+        interlocks: vec![],
+    };
+    assert!(layout.validate(&["cc1"]).is_ok());
+
+    layout.devices.extend([
+        device("bag_motor", "cc1", DeviceType::Motor { scale: 800 }, 4),
+        device("blower", "cc1", DeviceType::Output, 2),
+        device("agitator", "cc1", DeviceType::HBridge { power: 32000 }, 9),
+        device("sealer_heater", "cc2", DeviceType::Output, 0),
+    ]);
+    layout.scales.push(ScaleConfig {
+        name: "node_b".to_string(),
+        serial: 716620,
+    });
+    layout.hatches[0].feedback = "hatch_a_open".to_string();
+    let errors = layout.validate(&["cc1"]).unwrap_err();
+    assert_eq!(errors.0.len(), 6);
+    let shared = "Devices hatch_a_open, blower share O2 on cc1".to_string();
+    assert!(errors.0.contains(&shared));
+    let report = errors.to_string();
+    assert!(report.starts_with("Machine layout has 6 problem(s):"));
+}
+
+#[tokio::test]
+async fn test_output_interlocks() {
+    use crate::components::clear_core_io::OutputState;
+    use crate::controllers::clear_core;
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let device = |name: &str, id| DeviceConfig {
+        name: name.to_string(),
+        controller: "cc1".to_string(),
+        device: DeviceType::Output,
+        id,
+    };
+    let interlock = |name: &str, outputs: [&str; 2], mode| InterlockConfig {
+        name: name.to_string(),
+        outputs: outputs.map(str::to_string).to_vec(),
+        mode,
+    };
+    let mut layout = MachineLayout {
+        devices: vec![
+            device("gripper_open", 0),
+            device("gripper_close", 1),
+            device("conveyor_forward", 2),
+            device("conveyor_reverse", 3),
+        ],
+        scales: vec![],
+        hatches: vec![],
+        interlocks: vec![
+            interlock(
+                "gripper",
+                ["gripper_open", "gripper_close"],
+                InterlockMode::BreakBeforeMake,
+            ),
+            interlock(
+                "conveyor",
+                ["conveyor_forward", "conveyor_reverse"],
+                InterlockMode::Reject,
+            ),
+        ],
+    };
+    let controllers = HashMap::from([("cc1".to_string(), bench.sender())]);
+    let registry = DeviceRegistry::from_layout(controllers, &layout).unwrap();
+    let output = |name| registry.output(name).unwrap();
+    let controller = bench.controller();
+
+    output("gripper_open")
+        .set_state(OutputState::On)
+        .await
+        .unwrap();
+    output("gripper_close")
+        .set_state(OutputState::On)
+        .await
+        .unwrap();
+    assert_eq!((controller.output(0), controller.output(1)), (0, 32700));
+
+    output("conveyor_forward")
+        .set_state(OutputState::On)
+        .await
+        .unwrap();
+    let error = output("conveyor_reverse")
+        .set_state(OutputState::On)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<clear_core::Error>(),
+        Some(clear_core::Error::Interlocked { group, .. }) if group == "conveyor"
+    ));
+    assert_eq!(controller.output(3), 0);
+    output("conveyor_forward")
+        .set_state(OutputState::Off)
+        .await
+        .unwrap();
+    output("conveyor_reverse")
+        .set_state(OutputState::On)
+        .await
+        .unwrap();
+    assert_eq!((controller.output(2), controller.output(3)), (0, 32700));
+
+    layout.interlocks[1].outputs[1] = "gripper_open".to_string();
+    let errors = layout.validate(&["cc1"]).unwrap_err();
+    assert_eq!(
+        errors.0,
+        ["Output gripper_open is in both interlocks gripper and conveyor"]
+    );
+}
@@ -0,0 +1,99 @@
+use crate::components::clear_core_motor::ClearCoreMotor;
+use crate::components::scale::Scale;
+use crate::util::units::{Revolutions, RevsPerSec};
+use std::error::Error;
+use tokio::time::{Duration, Instant};
+
+pub struct BurnInConfig {
+    pub duration: Duration,
+    // Revs travelled out and back each cycle
+    pub travel: Revolutions,
+    pub velocity: RevsPerSec,
+    pub poll_interval: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct BurnInReport {
+    pub elapsed: Duration,
+    pub cycles: usize,
+    pub position_drift: f64,
+    pub max_position_drift: f64,
+    pub weight_mean: f64,
+    pub weight_std_dev: f64,
+    pub comms_errors: usize,
+}
+
+pub fn mean_std_dev(samples: &[f64]) -> (f64, f64) {
+    if samples.is_empty() {
+        return (0., 0.);
+    }
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+    let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+    (mean, variance.sqrt())
+}
+
+async fn cycle(
+    motor: &ClearCoreMotor,
+    config: &BurnInConfig,
+) -> Result<Revolutions, Box<dyn Error>> {
+    motor.relative_move(config.travel).await?;
+    motor.wait_for_move(config.poll_interval).await?;
+    motor.relative_move(-config.travel).await?;
+    motor.wait_for_move(config.poll_interval).await?;
+    motor.get_position().await
+}
+
+pub async fn burn_in(
+    motor: &ClearCoreMotor,
+    mut scale: Scale,
+    config: BurnInConfig,
+) -> Result<(Scale, BurnInReport), Box<dyn Error>> {
+    // Factory acceptance run: cycles the motor back and forth, weighing between cycles, and
+    // counts failures instead of stopping on them
+    let mut report = BurnInReport::default();
+    let mut weights = Vec::new();
+    motor.set_velocity(config.velocity).await?;
+    let start_position = motor.get_position().await?;
+    let start_time = Instant::now();
+
+    while Instant::now() - start_time < config.duration {
+        match cycle(motor, &config).await {
+            Ok(position) => {
+                report.position_drift = (position - start_position).0;
+                report.max_position_drift =
+                    report.max_position_drift.max(report.position_drift.abs());
+            }
+            Err(e) => {
+                report.comms_errors += 1;
+                println!("Burn-in comms error: {e}");
+            }
+        }
+        report.cycles += 1;
+
+        // The scale is consumed by a failed read, so scale errors end the run
+        let weight: f64;
+        (scale, weight) = tokio::task::spawn_blocking(move || {
+            Scale::live_weigh(scale).map_err(|e| e.to_string())
+        })
+        .await??;
+        weights.push(weight);
+        println!(
+            "Burn-in cycle {}: drift {:.4} revs, weight {:.1}",
+            report.cycles, report.position_drift, weight
+        );
+    }
+
+    report.elapsed = Instant::now() - start_time;
+    (report.weight_mean, report.weight_std_dev) = mean_std_dev(weights.as_slice());
+    println!("Burn-in complete: {:?}", report);
+    Ok((scale, report))
+}
+
+#[test]
+fn test_mean_std_dev() {
+    let (mean, std_dev) = mean_std_dev(&[2., 4., 4., 4., 5., 5., 7., 9.]);
+    assert_eq!(mean, 5.);
+    assert_eq!(std_dev, 2.);
+    assert_eq!(mean_std_dev(&[]), (0., 0.));
+}
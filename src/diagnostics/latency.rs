@@ -0,0 +1,91 @@
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LatencyStage {
+    // Blocking scale read, including the spawn_blocking hop
+    ScaleSample,
+    Filter,
+    Decision,
+    // Motor commands sent over mpsc/TCP until the ClearCore reply comes back
+    MotorCommand,
+    // Scale sample start to the last motor command acknowledged in that iteration
+    EndToEnd,
+}
+
+const STAGES: [LatencyStage; 5] = [
+    LatencyStage::ScaleSample,
+    LatencyStage::Filter,
+    LatencyStage::Decision,
+    LatencyStage::MotorCommand,
+    LatencyStage::EndToEnd,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyStats {
+    pub count: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct LatencyRecorder {
+    samples: [Vec<Duration>; 5],
+}
+
+impl LatencyRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, stage: LatencyStage, latency: Duration) {
+        self.samples[stage as usize].push(latency);
+    }
+
+    // Records the time since `start` and returns now, so stages can be chained
+    pub fn lap(&mut self, stage: LatencyStage, start: Instant) -> Instant {
+        let now = Instant::now();
+        self.record(stage, now - start);
+        now
+    }
+
+    pub fn stats(&self, stage: LatencyStage) -> Option<LatencyStats> {
+        let mut samples = self.samples[stage as usize].clone();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort();
+        let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+        Some(LatencyStats {
+            count: samples.len(),
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: samples[samples.len() - 1],
+        })
+    }
+
+    pub fn summary(&self) -> Vec<(LatencyStage, LatencyStats)> {
+        STAGES
+            .iter()
+            .filter_map(|&stage| self.stats(stage).map(|stats| (stage, stats)))
+            .collect()
+    }
+}
+
+#[test]
+fn test_latency_percentiles() {
+    let mut recorder = LatencyRecorder::new();
+    for ms in 1..=100 {
+        recorder.record(LatencyStage::MotorCommand, Duration::from_millis(ms));
+    }
+    let stats = recorder.stats(LatencyStage::MotorCommand).unwrap();
+    assert_eq!(stats.count, 100);
+    assert_eq!(stats.p50, Duration::from_millis(51));
+    assert_eq!(stats.p90, Duration::from_millis(90));
+    assert_eq!(stats.p99, Duration::from_millis(99));
+    assert_eq!(stats.max, Duration::from_millis(100));
+    assert_eq!(recorder.stats(LatencyStage::Filter), None);
+    assert_eq!(recorder.summary().len(), 1);
+}
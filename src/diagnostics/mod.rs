@@ -0,0 +1,2 @@
+pub mod burn_in;
+pub mod latency;
@@ -0,0 +1,162 @@
+use crate::controllers::clear_core::{Message, CR};
+use std::error::Error;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Duration, Instant};
+
+// One command and the reply it got. On disk each exchange is
+// [elapsed micros: u64][command len: u16][command][reply len: u16][reply], little endian,
+// with replies cut after their CR instead of the 100 byte padded buffer
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub elapsed: Duration,
+    pub command: Vec<u8>,
+    pub reply: Vec<u8>,
+}
+
+impl Exchange {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&(self.elapsed.as_micros() as u64).to_le_bytes());
+        for field in [&self.command, &self.reply] {
+            buffer.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            buffer.extend_from_slice(field);
+        }
+    }
+}
+
+pub fn decode(mut bytes: &[u8]) -> Result<Vec<Exchange>, Box<dyn Error + Send + Sync>> {
+    fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], Box<dyn Error + Send + Sync>> {
+        if bytes.len() < n {
+            return Err(Box::from("Command log is truncated"));
+        }
+        let (head, tail) = bytes.split_at(n);
+        *bytes = tail;
+        Ok(head)
+    }
+    let mut exchanges = Vec::new();
+    while !bytes.is_empty() {
+        let elapsed = u64::from_le_bytes(take(&mut bytes, 8)?.try_into()?);
+        let len = u16::from_le_bytes(take(&mut bytes, 2)?.try_into()?) as usize;
+        let command = take(&mut bytes, len)?.to_vec();
+        let len = u16::from_le_bytes(take(&mut bytes, 2)?.try_into()?) as usize;
+        let reply = take(&mut bytes, len)?.to_vec();
+        exchanges.push(Exchange {
+            elapsed: Duration::from_micros(elapsed),
+            command,
+            reply,
+        });
+    }
+    Ok(exchanges)
+}
+
+pub async fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Exchange>, Box<dyn Error + Send + Sync>> {
+    decode(tokio::fs::read(path).await?.as_slice())
+}
+
+// Sits between the devices and the client, appending every exchange to the log file
+pub async fn recorder<P: AsRef<Path>>(
+    path: P,
+    mut msg: mpsc::Receiver<Message>,
+    upstream: mpsc::Sender<Message>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut file: File = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let start = Instant::now();
+    let mut buffer = Vec::new();
+    while let Some(message) = msg.recv().await {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        upstream
+            .send(Message {
+                buffer: message.buffer.clone(),
+                response: resp_tx,
+            })
+            .await?;
+        let reply = resp_rx.await?;
+        let end = reply
+            .iter()
+            .position(|&b| b == CR)
+            .map_or(reply.len(), |cr| cr + 1);
+        let exchange = Exchange {
+            elapsed: start.elapsed(),
+            command: message.buffer,
+            reply: reply[..end].to_vec(),
+        };
+        buffer.clear();
+        exchange.encode(&mut buffer);
+        file.write_all(buffer.as_slice()).await?;
+        if message.response.send(reply).is_err() {
+            eprintln!("Failed to send via channel");
+        }
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+// Stands in for the client and answers with the recorded replies, in order. Stops with an
+// error as soon as the commands diverge from the log. `SimulatedController::replay_from` runs
+// a log against the simulator instead, to see how it behaves under injected faults
+pub async fn replay(
+    exchanges: Vec<Exchange>,
+    mut msg: mpsc::Receiver<Message>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut exchanges = exchanges.into_iter();
+    while let Some(message) = msg.recv().await {
+        let Some(exchange) = exchanges.next() else {
+            return Err(Box::from("Command log exhausted"));
+        };
+        if exchange.command != message.buffer {
+            return Err(format!(
+                "Replay diverged at {:?}: expected \"{}\", got \"{}\"",
+                exchange.elapsed,
+                exchange.command.escape_ascii(),
+                message.buffer.escape_ascii()
+            )
+            .into());
+        }
+        if message.response.send(exchange.reply).is_err() {
+            eprintln!("Failed to send via channel");
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_record_and_replay() {
+    use crate::components::clear_core_io::DigitalInput;
+    let path = std::env::temp_dir().join(format!("command_log_{}.bin", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let (tx, rx) = mpsc::channel::<Message>(10);
+    let (up_tx, mut up_rx) = mpsc::channel::<Message>(10);
+    let controller = tokio::spawn(async move {
+        while let Some(msg) = up_rx.recv().await {
+            let mut reply = vec![2, b'I', b'0', b'1', CR];
+            reply.resize(100, 0);
+            msg.response.send(reply).unwrap();
+        }
+    });
+    let recording = tokio::spawn(recorder(path.clone(), rx, up_tx));
+    let input = DigitalInput::new(0, tx);
+    assert!(input.get_state().await.unwrap());
+    drop(input);
+    recording.await.unwrap().unwrap();
+    controller.await.unwrap();
+
+    let exchanges = load(&path).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(exchanges.len(), 1);
+    assert_eq!(exchanges[0].command, vec![2, b'I', b'0', CR]);
+    assert_eq!(exchanges[0].reply, vec![2, b'I', b'0', b'1', CR]);
+    assert!(decode(&[0; 9]).is_err());
+
+    let (tx, rx) = mpsc::channel::<Message>(10);
+    let replaying = tokio::spawn(replay(exchanges, rx));
+    let input = DigitalInput::new(0, tx.clone());
+    assert!(input.get_state().await.unwrap());
+    assert!(DigitalInput::new(1, tx).get_state().await.is_err());
+    assert!(replaying.await.unwrap().is_err());
+}
@@ -1 +1,3 @@
+pub mod command_log;
+pub mod remote_scale;
 pub mod tcp;
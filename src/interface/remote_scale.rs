@@ -0,0 +1,397 @@
+use crate::components::scale::{CancelToken, ScaleInfo, WeightEstimator, WeightSource};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::watch;
+
+// Line protocol between a motion controller and the computer the Phidget is attached to.
+// Each request is one line and is answered by one "OK [grams]" or "ERR message" line:
+//   WEIGH                          live weight
+//   SETTLE millis rate estimator   settled weight, estimator is "median", "trimmed f" or "mode f"
+//   TARE                           zero the current weight
+//   STREAM millis                  an "OK grams" line every interval until the client hangs up
+//
+// Allowance on top of any settle window for the round trip and the server's queue
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn encode_estimator(estimator: WeightEstimator) -> String {
+    match estimator {
+        WeightEstimator::Median => "median".to_string(),
+        WeightEstimator::TrimmedMean(fraction) => format!("trimmed {fraction}"),
+        WeightEstimator::BinnedMode(width) => format!("mode {width}"),
+    }
+}
+
+fn decode_estimator(words: &[&str]) -> Result<WeightEstimator, Box<dyn Error>> {
+    match words {
+        ["median"] => Ok(WeightEstimator::Median),
+        ["trimmed", fraction] => Ok(WeightEstimator::TrimmedMean(fraction.parse()?)),
+        ["mode", width] => Ok(WeightEstimator::BinnedMode(width.parse()?)),
+        _ => Err(Box::from(format!("Unknown estimator {}", words.join(" ")))),
+    }
+}
+
+fn parse_reply(line: &str) -> Result<Option<f64>, Box<dyn Error>> {
+    let line = line.trim_end();
+    if let Some(error) = line.strip_prefix("ERR ") {
+        return Err(Box::from(error.to_string()));
+    }
+    match line.strip_prefix("OK") {
+        Some("") => Ok(None),
+        Some(weight) => Ok(Some(weight.trim().parse()?)),
+        None if line.is_empty() => Err(Box::from("Scale server closed the connection")),
+        None => Err(Box::from(format!("Unexpected reply {line}"))),
+    }
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn open(addr: SocketAddr) -> std::io::Result<Self> {
+        let writer = TcpStream::connect(addr)?;
+        writer.set_nodelay(true)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer })
+    }
+
+    fn exchange(&mut self, request: &str, timeout: Duration) -> std::io::Result<String> {
+        self.writer.set_read_timeout(Some(timeout))?;
+        self.writer.write_all(format!("{request}\n").as_bytes())?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Scale server closed the connection",
+            ));
+        }
+        Ok(line)
+    }
+}
+
+// Client end, used wherever a local Scale would be, e.g. `Node::actor_with_source`. Calls
+// block like a local scale does, so run them on the blocking pool
+pub struct RemoteScale {
+    addr: SocketAddr,
+    connection: Option<Connection>,
+    reply_timeout: Duration,
+    info: Option<ScaleInfo>,
+}
+
+impl RemoteScale {
+    pub fn connect<A: std::net::ToSocketAddrs>(addr: A) -> Result<Self, Box<dyn Error>> {
+        let connection = Connection::open(
+            addr.to_socket_addrs()?
+                .next()
+                .ok_or("Scale server address didn't resolve")?,
+        )?;
+        Ok(Self {
+            addr: connection.writer.peer_addr()?,
+            connection: Some(connection),
+            reply_timeout: REPLY_TIMEOUT,
+            info: None,
+        })
+    }
+
+    pub fn with_reply_timeout(mut self, reply_timeout: Duration) -> Self {
+        self.reply_timeout = reply_timeout;
+        self
+    }
+
+    // The server doesn't send its scale's details, so they're configured on this end
+    pub fn with_info(mut self, info: ScaleInfo) -> Self {
+        self.info = Some(info);
+        self
+    }
+
+    // A reply that misses the timeout still arrives later and would be read as the answer to
+    // the next request, so after any I/O error the connection is dropped and the next request
+    // opens a fresh one
+    fn request(&mut self, request: &str, wait: Duration) -> Result<Option<f64>, Box<dyn Error>> {
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => self.connection.insert(Connection::open(self.addr)?),
+        };
+        match connection.exchange(request, wait + self.reply_timeout) {
+            Ok(line) => parse_reply(line.as_str()),
+            Err(e) => {
+                self.connection = None;
+                Err(Box::from(e))
+            }
+        }
+    }
+}
+
+impl WeightSource for RemoteScale {
+    fn live_weight(&mut self) -> Result<f64, Box<dyn Error>> {
+        self.request("WEIGH", Duration::ZERO)?
+            .ok_or_else(|| Box::from("Missing weight in reply"))
+    }
+
+    // The window runs on the server, so a cancel only takes effect once it's over
+    fn settled_weight(
+        &mut self,
+        time: Duration,
+        sample_rate: usize,
+        estimator: WeightEstimator,
+        cancel: &CancelToken,
+    ) -> Result<Option<f64>, Box<dyn Error>> {
+        if cancel.is_cancelled() {
+            return Ok(None);
+        }
+        let request = format!(
+            "SETTLE {} {sample_rate} {}",
+            time.as_millis(),
+            encode_estimator(estimator)
+        );
+        let weight = self
+            .request(request.as_str(), time)?
+            .ok_or("Missing weight in reply")?;
+        Ok((!cancel.is_cancelled()).then_some(weight))
+    }
+
+    fn tare(&mut self) -> Result<(), Box<dyn Error>> {
+        self.request("TARE", Duration::ZERO).map(|_| ())
+    }
+
+    fn info(&self) -> Option<ScaleInfo> {
+        self.info.clone()
+    }
+}
+
+// Streams live weights from the server into `weights` until either end hangs up, for
+// displays that don't need to go through a node
+pub async fn stream_weights<A: ToSocketAddrs>(
+    addr: A,
+    interval: Duration,
+    weights: watch::Sender<f64>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    let (reader, mut writer) = stream.split();
+    let request = format!("STREAM {}\n", interval.as_millis());
+    writer.write_all(request.as_bytes()).await?;
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let weight = parse_reply(line.as_str()).map_err(|e| e.to_string())?;
+        if let Some(weight) = weight {
+            if weights.send(weight).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Server end, run on the computer the scale is attached to. Each connection gets its own task
+// and requests from all of them take turns on the scale
+pub async fn serve_scale<S: WeightSource>(
+    listener: TcpListener,
+    source: S,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let source = Arc::new(Mutex::new(source));
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let source = source.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(socket, source).await {
+                eprintln!("Scale client {peer} dropped: {e}");
+            }
+        });
+    }
+}
+
+async fn serve_connection<S: WeightSource>(
+    mut socket: tokio::net::TcpStream,
+    source: Arc<Mutex<S>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (reader, mut writer) = socket.split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if let ["STREAM", millis] = words.as_slice() {
+            let interval = Duration::from_millis(millis.parse()?);
+            loop {
+                let reply = format_reply(weigh(&source, |s| s.live_weight().map(Some)).await);
+                // The client hanging up is how a stream ends
+                if writer.write_all(reply.as_bytes()).await.is_err() {
+                    return Ok(());
+                }
+                tokio::time::sleep(interval).await;
+            }
+        }
+        let reply = match words.as_slice() {
+            ["WEIGH"] => weigh(&source, |s| s.live_weight().map(Some)).await,
+            ["SETTLE", millis, rate, estimator @ ..] => {
+                let request = settle_request(millis, rate, estimator).map_err(|e| e.to_string());
+                match request {
+                    Ok((time, rate, estimator)) => {
+                        weigh(&source, move |s| {
+                            s.settled_weight(time, rate, estimator, &CancelToken::new())
+                        })
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            ["TARE"] => weigh(&source, |s| s.tare().map(|_| None)).await,
+            _ => Err(format!("Unknown request {line}")),
+        };
+        writer.write_all(format_reply(reply).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn settle_request(
+    millis: &str,
+    rate: &str,
+    estimator: &[&str],
+) -> Result<(Duration, usize, WeightEstimator), Box<dyn Error>> {
+    Ok((
+        Duration::from_millis(millis.parse()?),
+        rate.parse()?,
+        decode_estimator(estimator)?,
+    ))
+}
+
+// Scale calls block, so they run on the blocking pool with the scale locked
+async fn weigh<S, F>(source: &Arc<Mutex<S>>, read: F) -> Result<Option<f64>, String>
+where
+    S: WeightSource,
+    F: FnOnce(&mut S) -> Result<Option<f64>, Box<dyn Error>> + Send + 'static,
+{
+    let source = source.clone();
+    tokio::task::spawn_blocking(move || {
+        read(&mut source.lock().unwrap()).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+fn format_reply(reply: Result<Option<f64>, String>) -> String {
+    match reply {
+        Ok(Some(weight)) => format!("OK {weight}\n"),
+        Ok(None) => "OK\n".to_string(),
+        Err(e) => format!("ERR {}\n", e.replace('\n', " ")),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_remote_scale() {
+    struct Fixed(f64);
+    impl WeightSource for Fixed {
+        fn live_weight(&mut self) -> Result<f64, Box<dyn Error>> {
+            Ok(self.0)
+        }
+
+        fn tare(&mut self) -> Result<(), Box<dyn Error>> {
+            self.0 = 0.;
+            Ok(())
+        }
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_scale(listener, Fixed(412.5)));
+
+    let (tx, mut rx) = watch::channel(0.);
+    tokio::spawn(stream_weights(addr, Duration::from_millis(10), tx));
+    rx.changed().await.unwrap();
+    assert_eq!(*rx.borrow(), 412.5);
+
+    let weights = tokio::task::spawn_blocking(move || {
+        let mut scale = RemoteScale::connect(addr).unwrap();
+        let live = scale.live_weight().unwrap();
+        let window = Duration::from_millis(20);
+        let estimator = WeightEstimator::TrimmedMean(0.2);
+        let cancel = CancelToken::new();
+        let settled = scale
+            .settled_weight(window, 500, estimator, &cancel)
+            .unwrap();
+        assert!(scale.raw_readings().is_err());
+        scale.tare().unwrap();
+        (live, settled, scale.live_weight().unwrap())
+    })
+    .await
+    .unwrap();
+    assert_eq!(weights, (412.5, Some(412.5), 0.));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_remote_scale_late_reply() {
+    // The first request on the first connection is answered after the client gave up
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut late = true;
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let slow = std::mem::replace(&mut late, false);
+            tokio::spawn(async move {
+                let (reader, mut writer) = socket.split();
+                let mut lines = tokio::io::BufReader::new(reader).lines();
+                let mut first = true;
+                while let Ok(Some(_)) = lines.next_line().await {
+                    let reply = if slow && std::mem::replace(&mut first, false) {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        "OK 1\n"
+                    } else {
+                        "OK 2\n"
+                    };
+                    if writer.write_all(reply.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    let weights = tokio::task::spawn_blocking(move || {
+        let mut scale = RemoteScale::connect(addr)
+            .unwrap()
+            .with_reply_timeout(Duration::from_millis(50));
+        let timed_out = scale.live_weight().is_err();
+        std::thread::sleep(Duration::from_millis(300));
+        (timed_out, scale.live_weight().unwrap())
+    })
+    .await
+    .unwrap();
+    assert_eq!(weights, (true, 2.));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_remote_scale_server_closed() {
+    // The first connection is closed by the server after one reply, e.g. on a restart
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut weight = 0;
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            weight += 1;
+            let (reader, mut writer) = socket.split();
+            let mut lines = tokio::io::BufReader::new(reader).lines();
+            while let Ok(Some(_)) = lines.next_line().await {
+                let reply = format!("OK {weight}\n");
+                writer.write_all(reply.as_bytes()).await.unwrap();
+                if weight == 1 {
+                    break;
+                }
+            }
+        }
+    });
+
+    let weights = tokio::task::spawn_blocking(move || {
+        let mut scale = RemoteScale::connect(addr).unwrap();
+        let first = scale.live_weight().unwrap();
+        let closed = scale.live_weight().is_err();
+        (first, closed, scale.live_weight().unwrap())
+    })
+    .await
+    .unwrap();
+    assert_eq!(weights, (1., true, 2.));
+}
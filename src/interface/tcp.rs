@@ -1,32 +1,561 @@
-use crate::controllers::clear_core::Message;
+use crate::controllers::clear_core::{
+    Controller, Failsafes, InputEvents, Message, SequenceTags, CR, STX,
+};
 use std::error::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpStream, ToSocketAddrs};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
 
 pub async fn client<T: ToSocketAddrs>(
     addr: T,
     mut msg: mpsc::Receiver<Message>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut stream = TcpStream::connect(addr).await?;
-    while let Some(message) = msg.recv().await {
-        stream.write_all(&message.buffer).await?;
-        stream.readable().await?;
-        let mut buffer = [0; 100];
-        match stream.read(&mut buffer).await {
-            Ok(0) => {
-                eprintln!("Connection closed by server");
+    serve(&mut stream, &mut msg, None, None, None).await?;
+    Ok(())
+}
+
+// Like `client`, but also watches the socket for input changes and other frames pushed by the
+// firmware and hands them to `events`, keeping them out of request/reply pairing
+pub async fn client_with_events<T: ToSocketAddrs>(
+    addr: T,
+    mut msg: mpsc::Receiver<Message>,
+    events: InputEvents,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    serve(&mut stream, &mut msg, None, Some(&events), None).await?;
+    Ok(())
+}
+
+// Like `client`, but tags every command for firmware that echoes sequence tags and discards
+// replies whose tag doesn't match the command waiting on one
+pub async fn client_with_tags<T: ToSocketAddrs>(
+    addr: T,
+    mut msg: mpsc::Receiver<Message>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut tags = SequenceTags::new();
+    serve(&mut stream, &mut msg, None, None, Some(&mut tags)).await?;
+    Ok(())
+}
+
+// What a spawned client does on top of request/reply pairing, one option per `client_with_*`
+// variant so they can be combined
+#[derive(Clone, Default)]
+pub struct ClientOptions {
+    failsafes: Option<Failsafes>,
+    events: Option<InputEvents>,
+    tags: bool,
+}
+
+impl ClientOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Driven once the client shuts down or the link drops, see `client_with_failsafes`
+    pub fn with_failsafes(mut self, failsafes: Failsafes) -> Self {
+        self.failsafes = Some(failsafes);
+        self
+    }
+
+    pub fn with_events(mut self, events: InputEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn with_tags(mut self) -> Self {
+        self.tags = true;
+        self
+    }
+}
+
+// Owns a spawned client task so it can be torn down instead of left running
+pub struct ClientHandle {
+    sender: mpsc::Sender<Message>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>,
+}
+
+impl ClientHandle {
+    pub fn spawn<T: ToSocketAddrs + Clone + Send + 'static>(addr: T, buffer: usize) -> Self {
+        Self::spawn_with(addr, buffer, ClientOptions::new())
+    }
+
+    pub fn spawn_with<T: ToSocketAddrs + Clone + Send + 'static>(
+        addr: T,
+        buffer: usize,
+        options: ClientOptions,
+    ) -> Self {
+        let (sender, mut msg) = mpsc::channel(buffer);
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr.clone()).await?;
+            let mut tags = options.tags.then(SequenceTags::new);
+            let events = options.events.as_ref();
+            let result = serve(
+                &mut stream,
+                &mut msg,
+                Some(shutdown_rx),
+                events,
+                tags.as_mut(),
+            )
+            .await;
+            if let Some(failsafes) = &options.failsafes {
+                let link_lost = !matches!(result, Ok(false));
+                drive_failsafes(addr, &mut stream, link_lost, failsafes, events).await?;
             }
-            Ok(_) => {
-                if message.response.send(buffer.to_vec()).is_err() {
-                    eprintln!("Failed to send via channel");
-                }
+            result?;
+            stream.shutdown().await?;
+            Ok(())
+        });
+        Self {
+            sender,
+            shutdown,
+            task,
+        }
+    }
+
+    pub fn sender(&self) -> mpsc::Sender<Message> {
+        self.sender.clone()
+    }
+
+    pub fn controller(&self) -> Controller {
+        Controller::new(self.sender.clone())
+    }
+
+    // Stops accepting commands, answers the ones already queued, drives the failsafes if any
+    // were given and then closes the socket. Requests sent after this fail with a closed
+    // channel error
+    pub async fn shutdown(self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let _ = self.shutdown.send(());
+        drop(self.sender);
+        self.task.await?
+    }
+}
+
+// Like `client`, but drives every registered output to its failsafe state once the channel
+// closes (shutdown) or the link drops, reconnecting first if needed
+pub async fn client_with_failsafes<T: ToSocketAddrs + Clone>(
+    addr: T,
+    mut msg: mpsc::Receiver<Message>,
+    failsafes: Failsafes,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut stream = TcpStream::connect(addr.clone()).await?;
+    let result = serve(&mut stream, &mut msg, None, None, None).await;
+    let link_lost = !matches!(result, Ok(false));
+    drive_failsafes(addr, &mut stream, link_lost, &failsafes, None).await?;
+    result?;
+    Ok(())
+}
+
+// Sends each failsafe command and waits for its reply, reconnecting first if the link dropped
+async fn drive_failsafes<T: ToSocketAddrs>(
+    addr: T,
+    stream: &mut TcpStream,
+    link_lost: bool,
+    failsafes: &Failsafes,
+    events: Option<&InputEvents>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if link_lost {
+        eprintln!("Link lost, reconnecting to drive failsafe states");
+        *stream = TcpStream::connect(addr).await?;
+    }
+    let mut frames = Frames::default();
+    for cmd in failsafes.commands() {
+        stream.write_all(cmd.as_slice()).await?;
+        let reply = loop {
+            match frames.read(stream).await? {
+                Some(frame) if events.is_some_and(|events| events.publish(&frame)) => continue,
+                reply => break reply,
+            }
+        };
+        if reply.is_none() {
+            eprintln!("Connection closed before all failsafe states were driven");
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Splits what comes off the socket into CR terminated frames. TCP is free to deliver several
+// frames in one read or one frame over several reads, so bytes after the last CR are kept for
+// the next read
+#[derive(Default)]
+struct Frames {
+    pending: Vec<u8>,
+}
+
+impl Frames {
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let end = self.pending.iter().position(|&b| b == CR)?;
+        let mut frame: Vec<u8> = self.pending.drain(..=end).collect();
+        // Anything ahead of the STX, e.g. padding after the previous frame, isn't part of it
+        let start = frame.iter().position(|&b| b == STX).unwrap_or(0);
+        frame.drain(..start);
+        Some(frame)
+    }
+
+    // Next complete frame, or None once the server closes the connection. Cancel safe, bytes
+    // already read stay in the buffer
+    async fn read(&mut self, stream: &mut TcpStream) -> std::io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(frame) = self.next() {
+                return Ok(Some(frame));
+            }
+            let mut buffer = [0; 100];
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(None);
             }
+            self.pending.extend_from_slice(&buffer[..n]);
+        }
+    }
+}
+
+// Returns true if the loop stopped because the link failed rather than the channel closing
+async fn serve(
+    stream: &mut TcpStream,
+    msg: &mut mpsc::Receiver<Message>,
+    mut shutdown: Option<oneshot::Receiver<()>>,
+    events: Option<&InputEvents>,
+    mut tags: Option<&mut SequenceTags>,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut frames = Frames::default();
+    loop {
+        // Shutdown is only honoured between exchanges so a reply is never left in the socket
+        let message = match shutdown.as_mut() {
+            Some(signal) => tokio::select! {
+                Ok(()) = signal => {
+                    msg.close();
+                    shutdown = None;
+                    continue;
+                }
+                message = next_message(stream, &mut frames, msg, events) => message,
+            },
+            None => next_message(stream, &mut frames, msg, events).await,
+        };
+        let message = match message {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
             Err(e) => {
                 eprintln!("Failed to read from stream: {}", e);
-                break;
+                return Ok(true);
+            }
+        };
+        let tag = match tags.as_deref_mut() {
+            Some(tags) => {
+                let (tag, tagged) = tags.tag(&message.buffer);
+                stream.write_all(&tagged).await?;
+                Some(tag)
+            }
+            None => {
+                stream.write_all(&message.buffer).await?;
+                None
+            }
+        };
+        let reply = loop {
+            let frame = match frames.read(stream).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break None,
+                Err(e) => {
+                    eprintln!("Failed to read from stream: {}", e);
+                    return Ok(true);
+                }
+            };
+            // Frames pushed while the reply was on its way
+            if let Some(events) = events.filter(|events| events.is_unsolicited(&frame)) {
+                events.publish(&frame);
+                continue;
+            }
+            let Some(tag) = tag else {
+                break Some(frame);
+            };
+            match SequenceTags::untag(&frame, tag) {
+                Some(untagged) => break Some(untagged),
+                None => eprintln!("Discarded stale reply {:?}", frame),
+            }
+        };
+        match reply {
+            Some(reply) => {
+                if message.response.send(reply).is_err() {
+                    eprintln!("Failed to send via channel");
+                }
             }
+            None => eprintln!("Connection closed by server"),
         }
     }
-    Ok(())
+    Ok(false)
+}
+
+// Next queued message, publishing any pushed frames that arrive on the socket meanwhile
+async fn next_message(
+    stream: &mut TcpStream,
+    frames: &mut Frames,
+    msg: &mut mpsc::Receiver<Message>,
+    events: Option<&InputEvents>,
+) -> std::io::Result<Option<Message>> {
+    let Some(events) = events else {
+        return Ok(msg.recv().await);
+    };
+    loop {
+        tokio::select! {
+            message = msg.recv() => return Ok(message),
+            frame = frames.read(stream) => {
+                let Some(frame) = frame? else {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "connection closed by server",
+                    ));
+                };
+                if !events.publish(&frame) {
+                    eprintln!("Dropped unexpected frame {:?}", frame);
+                }
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_client_shutdown() {
+    use crate::components::clear_core_io::DigitalInput;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buffer = [0; 100];
+        let mut exchanges = 0;
+        loop {
+            let n = socket.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break exchanges;
+            }
+            exchanges += 1;
+            socket.write_all(&[2, b'I', b'0', b'1', 13]).await.unwrap();
+        }
+    });
+    let handle = ClientHandle::spawn(addr, 10);
+    let input = DigitalInput::new(0, handle.sender());
+    assert!(input.get_state().await.unwrap());
+    handle.shutdown().await.unwrap();
+    assert!(input.get_state().await.is_err());
+    assert_eq!(server.await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_failsafes_on_shutdown() {
+    use crate::components::clear_core_io::Output;
+    use crate::controllers::clear_core::FailsafeState;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 100];
+        loop {
+            let n = socket.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break received;
+            }
+            received.extend_from_slice(&buffer[..n]);
+            socket.write_all(&buffer[..n]).await.unwrap();
+        }
+    });
+    let failsafes = Failsafes::new();
+    let (tx, rx) = mpsc::channel::<Message>(10);
+    let heater = Output::new(3, tx).with_failsafe(&failsafes, FailsafeState::Off);
+    let client = tokio::spawn(client_with_failsafes(addr, rx, failsafes));
+    drop(heater);
+    client.await.unwrap().unwrap();
+    assert_eq!(server.await.unwrap(), b"\x02O30\r".to_vec());
+}
+
+#[tokio::test]
+async fn test_handle_failsafes_on_shutdown() {
+    use crate::components::clear_core_io::{Output, OutputState};
+    use crate::controllers::clear_core::FailsafeState;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 100];
+        loop {
+            let n = socket.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break received;
+            }
+            received.extend_from_slice(&buffer[..n]);
+            // A heartbeat lands ahead of the failsafe reply, in the same write
+            socket
+                .write_all(&[b"\x02H1\r", &buffer[..n]].concat())
+                .await
+                .unwrap();
+        }
+    });
+    let failsafes = Failsafes::new();
+    let (heartbeat, mut heartbeats) = mpsc::unbounded_channel();
+    let events = InputEvents::new().with_handler(b'H', move |frame| {
+        heartbeat.send(frame[2..].to_vec()).unwrap();
+    });
+    let options = ClientOptions::new()
+        .with_failsafes(failsafes.clone())
+        .with_events(events);
+    let handle = ClientHandle::spawn_with(addr, 10, options);
+    let heater =
+        Output::new(3, handle.sender()).with_failsafe(&failsafes, FailsafeState::Value(500));
+    heater.set_state(OutputState::On).await.unwrap();
+    handle.shutdown().await.unwrap();
+    assert_eq!(server.await.unwrap(), b"\x02O332700\r\x02O3500\r".to_vec());
+    assert_eq!(heartbeats.recv().await.unwrap(), b"1");
+    assert_eq!(heartbeats.recv().await.unwrap(), b"1");
+}
+
+#[tokio::test]
+async fn test_client_with_events() {
+    use crate::components::clear_core_io::DigitalInput;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (push, mut pushes) = mpsc::channel::<Vec<u8>>(10);
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buffer = [0; 100];
+        loop {
+            tokio::select! {
+                Some(event) = pushes.recv() => socket.write_all(&event).await.unwrap(),
+                n = socket.read(&mut buffer) => {
+                    let n = n.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    // A change lands just ahead of the reply to each read
+                    if &buffer[..n] == b"\x02I4\r" {
+                        socket.write_all(b"\x02EI40\r").await.unwrap();
+                        socket.write_all(b"\x02I40\r").await.unwrap();
+                    } else {
+                        socket.write_all(&buffer[..n]).await.unwrap();
+                    }
+                }
+            }
+        }
+    });
+    let events = InputEvents::new();
+    let (tx, rx) = mpsc::channel(10);
+    tokio::spawn(client_with_events(addr, rx, events.clone()));
+    let photo_eye = DigitalInput::new(4, tx);
+    let mut changes = photo_eye.changes(&events).await.unwrap();
+    assert!(!*changes.borrow_and_update());
+
+    push.send(b"\x02EI41\r".to_vec()).await.unwrap();
+    changes.changed().await.unwrap();
+    assert!(*changes.borrow_and_update());
+    assert!(!photo_eye.get_state().await.unwrap());
+    changes.changed().await.unwrap();
+    assert!(!*changes.borrow());
+    drop(photo_eye);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_coalesced_frames() {
+    use crate::components::clear_core_io::DigitalInput;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buffer = [0; 100];
+        let mut reads = 0;
+        loop {
+            let n = socket.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            if &buffer[..n] != b"\x02I4\r" {
+                socket.write_all(&buffer[..n]).await.unwrap();
+                continue;
+            }
+            // After the first read, a push and the reply go out in one write, once each way
+            // round
+            reads += 1;
+            let frames: &[u8] = match reads {
+                1 => b"\x02I40\r",
+                2 => b"\x02EI41\r\x02I41\r",
+                _ => b"\x02I40\r\x02EI40\r",
+            };
+            socket.write_all(frames).await.unwrap();
+        }
+    });
+    let events = InputEvents::new();
+    let (tx, rx) = mpsc::channel(10);
+    tokio::spawn(client_with_events(addr, rx, events.clone()));
+    let photo_eye = DigitalInput::new(4, tx);
+    let mut changes = photo_eye.changes(&events).await.unwrap();
+    assert!(photo_eye.get_state().await.unwrap());
+    changes.wait_for(|blocked| *blocked).await.unwrap();
+    assert!(!photo_eye.get_state().await.unwrap());
+    changes.wait_for(|blocked| !*blocked).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_unsolicited_frame_handler() {
+    use crate::components::clear_core_io::DigitalInput;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buffer = [0; 100];
+        socket.write_all(b"\x02Hboot\r").await.unwrap();
+        loop {
+            let n = socket.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            // A heartbeat sneaks in ahead of the reply, in the same write
+            socket.write_all(b"\x02H42\r\x02I51\r").await.unwrap();
+        }
+    });
+    let (heartbeat, mut heartbeats) = mpsc::unbounded_channel();
+    let events = InputEvents::new().with_handler(b'H', move |frame| {
+        heartbeat.send(frame[2..].to_vec()).unwrap();
+    });
+    assert!(events.is_unsolicited(b"\x02H42\r"));
+    assert!(!events.is_unsolicited(b"\x02I51\r"));
+    let (tx, rx) = mpsc::channel(10);
+    tokio::spawn(client_with_events(addr, rx, events));
+    assert_eq!(heartbeats.recv().await.unwrap(), b"boot");
+    let input = DigitalInput::new(5, tx);
+    assert!(input.get_state().await.unwrap());
+    assert_eq!(heartbeats.recv().await.unwrap(), b"42");
+}
+
+#[tokio::test]
+async fn test_client_with_tags() {
+    use crate::components::clear_core_io::DigitalInput;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 100];
+        loop {
+            let n = socket.read(&mut buffer).await.unwrap();
+            if n == 0 {
+                break received;
+            }
+            received.push(buffer[..n].to_vec());
+            // A late reply to a command from before arrives in the same write as the current one
+            let stale = [&[2, b'#', buffer[2].wrapping_sub(1)], &b"I50\r"[..]].concat();
+            let reply = [&buffer[..n - 1], &b"1\r"[..]].concat();
+            socket.write_all(&[stale, reply].concat()).await.unwrap();
+        }
+    });
+    let (tx, rx) = mpsc::channel(10);
+    let client = tokio::spawn(client_with_tags(addr, rx));
+    let input = DigitalInput::new(5, tx);
+    assert!(input.get_state().await.unwrap());
+    assert!(input.get_state().await.unwrap());
+    drop(input);
+    client.await.unwrap().unwrap();
+    assert_eq!(server.await.unwrap(), [b"\x02#aI5\r", b"\x02#bI5\r"]);
 }
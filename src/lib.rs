@@ -1,5 +1,10 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod components;
 pub mod controllers;
+pub mod diagnostics;
 pub mod interface;
 pub mod subsystems;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 pub mod util;
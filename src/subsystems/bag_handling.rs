@@ -1,28 +1,163 @@
 use crate::components::clear_core_io::{DigitalInput, HBridgeState, Output, OutputState};
-use crate::components::clear_core_motor::{ClearCoreMotor, Status};
+use crate::components::clear_core_motor::{ClearCoreMotor, HomeTo, MotionProfile, Status};
+use crate::components::dry_cycle::DryCycle;
+use crate::components::scale::CancelToken;
 use crate::interface::tcp::client;
 use crate::subsystems::linear_actuator::{LinearActuator, SimpleLinearActuator};
+use crate::subsystems::status::{StatusTracker, TrackedSubsystem};
+use crate::util::units::{Revolutions, RevsPerSec};
+use serde::Deserialize;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
+use tokio::sync::watch;
 use tokio::time::sleep;
 use crate::subsystems::gantry::GantryCommand;
 use crate::subsystems::gantry::GantryCommand::GoTo;
 
+// Longest a gripper rotation may take before it's treated as jammed
+const GRIPPER_MOVE_TIMEOUT: Duration = Duration::from_secs(5);
+// Feed for a dry cycle bag dispense, with no film for the photo eye to see
+const DRY_CYCLE_BAG_FEED: Revolutions = Revolutions(2.);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GripperIndex {
+    Grip,
+    Rip,
+    Release,
+}
+
+// Absolute rotation positions from the homed zero, configured per machine
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct GripperPositions {
+    pub grip: Revolutions,
+    pub rip: Revolutions,
+    pub release: Revolutions,
+    pub tolerance: Revolutions,
+}
+
+impl GripperPositions {
+    fn position(&self, index: GripperIndex) -> Revolutions {
+        match index {
+            GripperIndex::Grip => self.grip,
+            GripperIndex::Rip => self.rip,
+            GripperIndex::Release => self.release,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct GripperHoming {
+    pub velocity: RevsPerSec,
+    // Signed travel toward the rotation hard stop, longer than the full stroke
+    pub distance: Revolutions,
+}
+
 pub struct BagGripper {
     motor: ClearCoreMotor,
     actuator: SimpleLinearActuator,
-    positions: Vec<f64>, //Revs, we have to make a units crate for this
+    positions: Vec<Revolutions>,
+    indexed_positions: Option<GripperPositions>,
+    motion_profile: Option<MotionProfile>,
+    homed: AtomicBool,
+    status: StatusTracker,
+    abort: CancelToken,
 }
 
 impl BagGripper {
-    pub fn new(motor: ClearCoreMotor, actuator: SimpleLinearActuator, positions: Vec<f64>) -> Self {
+    pub fn new(
+        motor: ClearCoreMotor,
+        actuator: SimpleLinearActuator,
+        positions: Vec<Revolutions>,
+    ) -> Self {
         Self {
             motor,
             actuator,
             positions,
+            indexed_positions: None,
+            motion_profile: None,
+            homed: AtomicBool::new(false),
+            status: StatusTracker::new("bag gripper"),
+            abort: CancelToken::new(),
         }
     }
 
+    pub fn status(&self) -> StatusTracker {
+        self.status.clone()
+    }
+
+    pub fn with_indexed_positions(mut self, positions: GripperPositions) -> Self {
+        self.indexed_positions = Some(positions);
+        self
+    }
+
+    // Applied once homing finishes, so the homing run keeps the drive's own ramps. A jerk limit
+    // keeps the rotation from shaking the bag off the gripper
+    pub fn with_motion_profile(mut self, profile: MotionProfile) -> Self {
+        self.motion_profile = Some(profile);
+        self
+    }
+
+    // Cancelling the token stops a rotation in progress; reset it before the next move
+    pub fn with_abort(mut self, abort: CancelToken) -> Self {
+        self.abort = abort;
+        self
+    }
+
+    async fn wait_for_rotation(&self, timeout: Option<Duration>) -> Result<(), Box<dyn Error>> {
+        self.motor
+            .wait_for_move_until(Duration::from_millis(150), timeout, Some(&self.abort))
+            .await
+    }
+
+    pub async fn home(&self, homing: GripperHoming) -> Result<(), Box<dyn Error>> {
+        self.status.run(self.run_home(homing)).await
+    }
+
+    async fn run_home(&self, homing: GripperHoming) -> Result<(), Box<dyn Error>> {
+        // Drives into the rotation hard stop and takes it as zero
+        self.homed.store(false, Ordering::Relaxed);
+        self.motor
+            .home(HomeTo::HardStop, homing.velocity, homing.distance)
+            .await?;
+        if let Some(profile) = &self.motion_profile {
+            self.motor.set_motion_profile(profile).await?;
+        }
+        self.homed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub async fn move_to(&self, index: GripperIndex) -> Result<(), Box<dyn Error>> {
+        self.status.run(self.run_move_to(index)).await
+    }
+
+    async fn run_move_to(&self, index: GripperIndex) -> Result<(), Box<dyn Error>> {
+        let Some(positions) = &self.indexed_positions else {
+            return Err(Box::from("Gripper has no indexed positions configured"));
+        };
+        if !self.homed.load(Ordering::Relaxed) {
+            return Err(Box::from(
+                "Gripper rotation must be homed before indexed moves",
+            ));
+        }
+        let target = positions.position(index);
+        self.motor.absolute_move(target).await?;
+        self.wait_for_rotation(Some(GRIPPER_MOVE_TIMEOUT)).await?;
+        let actual = self.motor.get_position().await?;
+        if (actual - target).0.abs() > positions.tolerance.0 {
+            return Err(Box::from(format!(
+                "Gripper missed {index:?}: expected {target}, at {actual}"
+            )));
+        }
+        Ok(())
+    }
+
+    pub async fn rip_bag_indexed(&self) -> Result<(), Box<dyn Error>> {
+        self.move_to(GripperIndex::Grip).await?;
+        self.move_to(GripperIndex::Rip).await?;
+        self.move_to(GripperIndex::Release).await
+    }
+
     pub async fn open(&self) -> Result<(), Box<dyn Error>> {
         self.actuator.actuate(HBridgeState::Pos).await.unwrap();
         sleep(Duration::from_secs_f64(2.0)).await;
@@ -36,11 +171,8 @@ impl BagGripper {
     }
     pub async fn rip_bag(&self) -> Result<(), Box<dyn Error>> {
         for pos in self.positions.as_slice() {
-            self.motor.relative_move(*pos).await.unwrap();
-            self.motor
-                .wait_for_move(Duration::from_millis(150))
-                .await
-                .unwrap();
+            self.motor.relative_move(*pos).await?;
+            self.wait_for_rotation(Some(GRIPPER_MOVE_TIMEOUT)).await?;
         }
         Ok(())
     }
@@ -49,31 +181,87 @@ impl BagGripper {
 pub struct BagDispenser {
     motor: ClearCoreMotor,
     photo_eye: DigitalInput,
+    photo_eye_changes: Option<watch::Receiver<bool>>,
+    status: StatusTracker,
+    dry_cycle: Option<DryCycle>,
 }
 
 impl BagDispenser {
     pub fn new(motor: ClearCoreMotor, photo_eye: DigitalInput) -> Self {
-        Self { motor, photo_eye }
+        Self {
+            motor,
+            photo_eye,
+            photo_eye_changes: None,
+            status: StatusTracker::new("bag dispenser"),
+            dry_cycle: None,
+        }
+    }
+    // Waits on pushed photo eye changes, see `DigitalInput::changes`, instead of polling it
+    pub fn with_photo_eye_changes(mut self, changes: watch::Receiver<bool>) -> Self {
+        self.photo_eye_changes = Some(changes);
+        self
+    }
+    // While the dry cycle runs the motor is slowed down and a dispense feeds a fixed distance
+    // instead of waiting for a bag at the photo eye
+    pub fn with_dry_cycle(mut self, dry_cycle: DryCycle) -> Self {
+        self.motor = self.motor.with_dry_cycle(dry_cycle.clone());
+        self.dry_cycle = Some(dry_cycle);
+        self
+    }
+    pub fn status(&self) -> StatusTracker {
+        self.status.clone()
     }
     pub async fn dispense(&self) -> Result<(), Box<dyn Error>> {
-        self.motor.set_velocity(3.0).await.unwrap();
-        self.motor.relative_move(1000.0).await.unwrap();
-        while !self.photo_eye.get_state().await.unwrap() {
-            sleep(Duration::from_millis(100)).await;
+        let busy = self.status.busy();
+        self.motor.set_velocity(RevsPerSec(3.0)).await.unwrap();
+        if self.dry_cycle.as_ref().is_some_and(DryCycle::is_running) {
+            self.motor.relative_move(DRY_CYCLE_BAG_FEED).await.unwrap();
+            while self.motor.get_status().await.unwrap() == Status::Moving {
+                sleep(Duration::from_millis(100)).await;
+            }
+            busy.idle();
+            return Ok(());
+        }
+        self.motor.velocity_move(RevsPerSec(3.0)).await.unwrap();
+        if let Some(changes) = &self.photo_eye_changes {
+            changes
+                .clone()
+                .wait_for(|blocked| *blocked)
+                .await
+                .expect("Photo eye events dropped");
+        } else {
+            while !self.photo_eye.get_state().await.unwrap() {
+                sleep(Duration::from_millis(100)).await;
+            }
         }
         self.motor.abrupt_stop().await.unwrap();
+        busy.idle();
         Ok(())
     }
     pub async fn pull_back(&self) -> Result<(), Box<dyn Error>> {
-        self.motor.set_velocity(0.5).await.unwrap();
-        self.motor.relative_move(-4.5).await.unwrap();
+        let busy = self.status.busy();
+        self.motor.set_velocity(RevsPerSec(0.5)).await.unwrap();
+        self.motor.relative_move(Revolutions(-4.5)).await.unwrap();
         while self.motor.get_status().await.unwrap() == Status::Moving {
             sleep(Duration::from_millis(100)).await;
         }
+        busy.idle();
         Ok(())
     }
 }
 
+impl TrackedSubsystem for BagGripper {
+    fn status_tracker(&self) -> &StatusTracker {
+        &self.status
+    }
+}
+
+impl TrackedSubsystem for BagDispenser {
+    fn status_tracker(&self) -> &StatusTracker {
+        &self.status
+    }
+}
+
 pub async fn load_bag(bag_dispenser: BagDispenser, bag_gripper: BagGripper, blower: Output) {
     bag_gripper.close().await.unwrap();
     bag_dispenser.dispense().await.unwrap();
@@ -118,7 +306,7 @@ async fn test_gripper_motor() {
         let gripper = BagGripper::new(
             motor,
             SimpleLinearActuator::new(tx2, 4, 0),
-            [0.3, -0.6, 0.3].to_vec(),
+            vec![Revolutions(0.3), Revolutions(-0.6), Revolutions(0.3)],
         );
         gripper.rip_bag().await.unwrap();
     });
@@ -138,7 +326,7 @@ async fn test_gripper_actuator() {
         let gripper = BagGripper::new(
             motor,
             SimpleLinearActuator::new(tx2.clone(), 4, 0),
-            [0.3, -0.6, 0.3].to_vec(),
+            vec![Revolutions(0.3), Revolutions(-0.6), Revolutions(0.3)],
         );
         gripper.open().await.unwrap();
         tokio::time::sleep(Duration::from_millis(2000)).await;
@@ -147,6 +335,52 @@ async fn test_gripper_actuator() {
     let (_, _, _) = tokio::join!(actuator_handler, cc1_handler, cc2_handler);
 }
 
+#[tokio::test(start_paused = true)]
+async fn test_gripper_home_and_index() {
+    use crate::components::clear_core_motor::{LimitMode, TravelLimits};
+    use crate::subsystems::status::{SubsystemState, SubsystemStatus};
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    // The rip position is past the end of travel, so the move stops short of it
+    let motor = bench.motor(2, 800).with_travel_limits(TravelLimits {
+        min: -10.,
+        max: 0.4,
+        mode: LimitMode::Clamp,
+    });
+    let actuator = SimpleLinearActuator::new(bench.sender(), 4, 0);
+    let positions = GripperPositions {
+        grip: Revolutions(0.25),
+        rip: Revolutions(0.5),
+        release: Revolutions(0.),
+        tolerance: Revolutions(0.01),
+    };
+    let gripper = BagGripper::new(motor, actuator, vec![]).with_indexed_positions(positions);
+    let not_homed = gripper.move_to(GripperIndex::Grip).await.unwrap_err();
+    assert!(not_homed.to_string().contains("must be homed"));
+    assert!(gripper.rip_bag_indexed().await.is_err());
+    assert_eq!(bench.controller().motor_position(2), 0);
+
+    gripper
+        .home(GripperHoming {
+            velocity: RevsPerSec(1.),
+            distance: Revolutions(-2.),
+        })
+        .await
+        .unwrap();
+    assert_eq!(bench.controller().motor_position(2), 0);
+    gripper.move_to(GripperIndex::Grip).await.unwrap();
+    assert_eq!(bench.controller().motor_position(2), 200);
+    assert_eq!(gripper.status().state(), SubsystemState::Idle);
+
+    let missed = gripper.rip_bag_indexed().await.unwrap_err();
+    assert!(missed.to_string().contains("Gripper missed Rip"));
+    assert_eq!(bench.controller().motor_position(2), 320);
+    assert_eq!(gripper.status().state(), SubsystemState::Faulted);
+    let sent = bench.controller().commands();
+    assert!(sent.contains(&b"\x02M2RM-1600".to_vec()));
+    assert!(sent.contains(&b"\x02M2SP0".to_vec()));
+}
+
 #[tokio::test]
 async fn test_bag_loading() {
     let (tx, rx) = tokio::sync::mpsc::channel(10);
@@ -164,14 +398,14 @@ async fn test_bag_loading() {
         let gripper = BagGripper::new(
             grip_motor,
             SimpleLinearActuator::new(tx2.clone(), 4, 0),
-            [0.4, -0.8, 0.4].to_vec(),
+            vec![Revolutions(0.4), Revolutions(-0.8), Revolutions(0.4)],
         );
         let blower = Output::new(5, tx2);
         load_bag(dispenser, gripper, blower, /* tokio::sync::mpsc::Sender<GantryCommand> */).await;
         let gantry = ClearCoreMotor::new(0, 800, tx);
 
         tokio::time::sleep(Duration::from_millis(100)).await;
-        gantry.relative_move(25.0).await.unwrap();
+        gantry.relative_move(Revolutions(25.0)).await.unwrap();
     });
     let (_, _, _) = tokio::join!(task, cc1_handler, cc2_handler);
 }
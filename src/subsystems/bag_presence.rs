@@ -0,0 +1,141 @@
+use crate::components::scale::Scale;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BagEvent {
+    // Carries the measured step in grams
+    Placed(f64),
+    Removed(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BagDetection {
+    // A bag is a step change between these magnitudes completed within the window
+    pub min_step: f64,
+    pub max_step: f64,
+    pub window: Duration,
+}
+
+pub struct BagDetector {
+    detection: BagDetection,
+    samples: VecDeque<(Instant, f64)>,
+    present: bool,
+}
+
+impl BagDetector {
+    pub fn new(detection: BagDetection) -> Self {
+        Self {
+            detection,
+            samples: VecDeque::new(),
+            present: false,
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    pub fn update(&mut self, weight: f64, now: Instant) -> Option<BagEvent> {
+        self.samples.push_back((now, weight));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(time, _)| now - *time > self.detection.window)
+        {
+            self.samples.pop_front();
+        }
+        if self.samples.len() < 4 {
+            return None;
+        }
+        // Medians of each half keep a single spike from looking like a step
+        let mut weights: Vec<f64> = self.samples.iter().map(|(_, weight)| *weight).collect();
+        let (before, after) = weights.split_at_mut(self.samples.len() / 2);
+        let step = median(after) - median(before);
+        let in_range =
+            |step: f64| (self.detection.min_step..=self.detection.max_step).contains(&step);
+        let event = match self.present {
+            false if in_range(step) => BagEvent::Placed(step),
+            true if in_range(-step) => BagEvent::Removed(-step),
+            _ => return None,
+        };
+        self.present = !self.present;
+        self.samples.clear();
+        Some(event)
+    }
+}
+
+fn median(weights: &mut [f64]) -> f64 {
+    weights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    weights[weights.len() / 2]
+}
+
+pub struct BagPresenceMonitor {
+    detector: BagDetector,
+    interval: Duration,
+    present: watch::Sender<bool>,
+    events: Option<mpsc::Sender<BagEvent>>,
+}
+
+impl BagPresenceMonitor {
+    pub fn new(detection: BagDetection, interval: Duration) -> Self {
+        let (present, _) = watch::channel(false);
+        Self {
+            detector: BagDetector::new(detection),
+            interval,
+            present,
+            events: None,
+        }
+    }
+
+    pub fn with_events(mut self, events: mpsc::Sender<BagEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    // Fill cycles can wait on this flipping to true before they start
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.present.subscribe()
+    }
+
+    pub async fn run(mut self, mut scale: Scale) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let weight;
+            (scale, weight) = tokio::task::spawn_blocking(move || {
+                Scale::live_weigh(scale).expect("Scale failed to weigh")
+            })
+            .await?;
+            if let Some(event) = self.detector.update(weight, Instant::now()) {
+                self.present.send_replace(self.detector.is_present());
+                if let Some(events) = &self.events {
+                    events.send(event).await?;
+                }
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+#[test]
+fn test_bag_detector() {
+    let start = Instant::now();
+    let mut detector = BagDetector::new(BagDetection {
+        min_step: 20.,
+        max_step: 200.,
+        window: Duration::from_millis(500),
+    });
+    let at = |ms: u64| start + Duration::from_millis(ms);
+    let mut events = Vec::new();
+    // Baseline, a single spike, a 50 g bag, then the bag lifted off
+    let weights = [
+        0., 0., 0., 400., 0., 0., 50., 50., 50., 50., 50., 0., 0., 0., 0.,
+    ];
+    for (i, weight) in weights.iter().enumerate() {
+        events.extend(detector.update(*weight, at(i as u64 * 100)));
+    }
+    assert_eq!(events, vec![BagEvent::Placed(50.), BagEvent::Removed(50.)]);
+    assert!(!detector.is_present());
+}
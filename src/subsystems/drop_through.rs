@@ -0,0 +1,189 @@
+use crate::components::scale::{CancelToken, WeightEstimator, WeightSource};
+use crate::subsystems::hatch::Hatch;
+use crate::subsystems::linear_actuator::LinearActuator;
+use crate::subsystems::status::{StatusTracker, TrackedSubsystem};
+use serde::Deserialize;
+use std::error::Error;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const SETTLE_SAMPLE_RATE: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct DropConfig {
+    // Hatch set points in feedback counts
+    pub open_set_point: isize,
+    pub close_set_point: isize,
+    // Settled read window, used before opening and once the product has fallen
+    pub settle_time: Duration,
+    // How long the hatch stays open for the product to fall through
+    pub dwell: Duration,
+    // Grams still on the tared scale that count as empty
+    pub empty_tolerance: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropEvent {
+    Dropped(f64),
+    // Product hung up in the bucket or hatch; the hatch is left open so it can be cleared
+    DropIncomplete { loaded: f64, remaining: f64 },
+}
+
+// Drop-through station: a weigh bucket on the node's scale, emptied through a hatch once a
+// dispense is done. Hand it the scale when the dispense returns
+pub struct DropStation<T: LinearActuator> {
+    hatch: Hatch<T>,
+    config: DropConfig,
+    status: StatusTracker,
+    events: Option<mpsc::Sender<DropEvent>>,
+    abort: CancelToken,
+}
+
+impl<T: LinearActuator> DropStation<T> {
+    pub fn new(hatch: Hatch<T>, config: DropConfig) -> Self {
+        Self {
+            hatch,
+            config,
+            status: StatusTracker::new("drop station"),
+            events: None,
+            abort: CancelToken::new(),
+        }
+    }
+
+    pub fn with_events(mut self, events: mpsc::Sender<DropEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    // Cancelling the token ends a settled read early and fails the drop
+    pub fn with_abort(mut self, abort: CancelToken) -> Self {
+        self.abort = abort;
+        self
+    }
+
+    pub fn status(&self) -> StatusTracker {
+        self.status.clone()
+    }
+
+    // Waits for the bucket to settle, opens the hatch and checks the scale comes back near
+    // zero. Returns the grams dropped
+    pub async fn drop_product<S: WeightSource>(
+        &self,
+        scale: S,
+    ) -> (S, Result<f64, Box<dyn Error>>) {
+        let busy = self.status.busy();
+        let (scale, result) = self.run_drop(scale).await;
+        match &result {
+            Ok(_) => busy.idle(),
+            Err(e) => busy.fault(&e.to_string()),
+        }
+        (scale, result)
+    }
+
+    async fn run_drop<S: WeightSource>(&self, scale: S) -> (S, Result<f64, Box<dyn Error>>) {
+        let (scale, loaded) = self.read_settled(scale).await;
+        let loaded = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => return (scale, Err(Box::from(e))),
+        };
+        if let Err(e) = self.hatch.open(self.config.open_set_point).await {
+            return (scale, Err(e));
+        }
+        tokio::time::sleep(self.config.dwell).await;
+        let (scale, remaining) = self.read_settled(scale).await;
+        let remaining = match remaining {
+            Ok(remaining) => remaining,
+            Err(e) => return (scale, Err(Box::from(e))),
+        };
+        if remaining.abs() > self.config.empty_tolerance {
+            self.send(DropEvent::DropIncomplete { loaded, remaining })
+                .await;
+            let error = format!("Drop incomplete: {remaining:.1} g of {loaded:.1} g left");
+            return (scale, Err(Box::from(error)));
+        }
+        if let Err(e) = self.hatch.close(self.config.close_set_point).await {
+            return (scale, Err(e));
+        }
+        let dropped = loaded - remaining;
+        self.send(DropEvent::Dropped(dropped)).await;
+        (scale, Ok(dropped))
+    }
+
+    async fn send(&self, event: DropEvent) {
+        if let Some(events) = &self.events {
+            if events.send(event).await.is_err() {
+                println!("WARNING: Drop station events dropped");
+            }
+        }
+    }
+
+    async fn read_settled<S: WeightSource>(&self, mut scale: S) -> (S, Result<f64, String>) {
+        let time = self.config.settle_time;
+        let abort = self.abort.clone();
+        tokio::task::spawn_blocking(move || {
+            let weight = scale
+                .settled_weight(time, SETTLE_SAMPLE_RATE, WeightEstimator::Median, &abort)
+                .map_err(|e| e.to_string())
+                .and_then(|weight| weight.ok_or_else(|| "Drop aborted".to_string()));
+            (scale, weight)
+        })
+        .await
+        .unwrap()
+    }
+}
+
+impl<T: LinearActuator> TrackedSubsystem for DropStation<T> {
+    fn status_tracker(&self) -> &StatusTracker {
+        &self.status
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_drop_station() {
+    use crate::subsystems::status::{SubsystemState, SubsystemStatus};
+    use crate::test_support::TestBench;
+    // Settled reads in order: loaded, then what's left after the dwell
+    struct Bucket(Vec<f64>);
+    impl WeightSource for Bucket {
+        fn live_weight(&mut self) -> Result<f64, Box<dyn Error>> {
+            Ok(self.0[0])
+        }
+
+        fn settled_weight(
+            &mut self,
+            _: Duration,
+            _: usize,
+            _: WeightEstimator,
+            _: &CancelToken,
+        ) -> Result<Option<f64>, Box<dyn Error>> {
+            Ok(Some(self.0.remove(0)))
+        }
+    }
+    let bench = TestBench::new();
+    let config = DropConfig {
+        open_set_point: 1500,
+        close_set_point: 1000,
+        settle_time: Duration::from_secs(1),
+        dwell: Duration::from_secs(2),
+        empty_tolerance: 2.,
+    };
+    let hatch = bench.hatch((0, 1), 3, 1000, Duration::from_secs(5));
+    let (events, mut received) = mpsc::channel(10);
+    let station = DropStation::new(hatch, config).with_events(events);
+
+    let (_, dropped) = station.drop_product(Bucket(vec![250., 0.5])).await;
+    assert_eq!(dropped.unwrap(), 249.5);
+    assert_eq!(received.recv().await, Some(DropEvent::Dropped(249.5)));
+    assert_eq!(station.state(), SubsystemState::Idle);
+
+    let (_, dropped) = station.drop_product(Bucket(vec![250., 40.])).await;
+    assert!(dropped.is_err());
+    assert_eq!(
+        received.recv().await,
+        Some(DropEvent::DropIncomplete {
+            loaded: 250.,
+            remaining: 40.
+        })
+    );
+    assert_eq!(station.state(), SubsystemState::Faulted);
+}
@@ -1,21 +1,56 @@
-use crate::components::clear_core_motor::{ClearCoreMotor, Status};
+use crate::components::clear_core_io::DigitalInput;
+use crate::components::clear_core_motor::{
+    ClearCoreMotor, HomeTo, MotionStats, MotorGroup, Status,
+};
+use crate::components::scale::CancelToken;
 use crate::interface::tcp::client;
+use crate::subsystems::status::StatusTracker;
+use crate::util::units::{Revolutions, RevsPerSec, RevsPerSec2};
 use std::error::Error;
 use std::time::Duration;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::oneshot;
 
+const GANTRY_VELOCITY: RevsPerSec = RevsPerSec(300.);
+
 pub enum GantryCommand {
-    GetPosition(oneshot::Sender<f64>),
-    GoTo(f64),
+    GetPosition(oneshot::Sender<Revolutions>),
+    GoTo(Revolutions),
+    GetMotionStats(oneshot::Sender<MotionStats>),
+    // Establishes zero after power-up, against the hard stop unless a sensor is given
+    Home {
+        sensor: Option<DigitalInput>,
+        velocity: RevsPerSec,
+        distance: Revolutions,
+    },
 }
 
 pub async fn gantry(
+    motor: ClearCoreMotor,
+    rx: Receiver<GantryCommand>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    tracked_gantry(motor, rx, StatusTracker::new("gantry")).await
+}
+
+// Same as `gantry`, reporting Busy through `status` while a move is in progress
+pub async fn tracked_gantry(
+    motor: ClearCoreMotor,
+    rx: Receiver<GantryCommand>,
+    status: StatusTracker,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    abortable_gantry(motor, rx, status, CancelToken::new()).await
+}
+
+// Same as `tracked_gantry`, stopping the current move when `abort` fires. The move is faulted
+// in `status` and later commands run once the token is reset
+pub async fn abortable_gantry(
     motor: ClearCoreMotor,
     mut rx: Receiver<GantryCommand>,
+    status: StatusTracker,
+    abort: CancelToken,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    motor.set_acceleration(40.).await.unwrap();
-    motor.set_velocity(300.).await.unwrap();
+    motor.set_acceleration(RevsPerSec2(40.)).await.unwrap();
+    motor.set_velocity(GANTRY_VELOCITY).await.unwrap();
     motor.enable().await.unwrap();
     while let Some(cmd) = rx.recv().await {
         match cmd {
@@ -24,9 +59,218 @@ pub async fn gantry(
                 sender.send(pos).unwrap();
             }
             GantryCommand::GoTo(pos) => {
-                motor.absolute_move(pos).await.unwrap();
-                while motor.get_status().await.unwrap() == Status::Moving {
-                    tokio::time::sleep(Duration::from_secs_f64(1.0)).await;
+                let busy = status.busy();
+                let result = motor
+                    .absolute_move_cancellable(pos, Duration::from_secs_f64(1.0), &abort)
+                    .await
+                    .map_err(|e| e.to_string());
+                match result {
+                    Ok(()) => busy.idle(),
+                    Err(e) => busy.fault(e.as_str()),
+                }
+            }
+            GantryCommand::GetMotionStats(sender) => {
+                sender.send(motor.motion_stats()).unwrap();
+            }
+            GantryCommand::Home {
+                sensor,
+                velocity,
+                distance,
+            } => {
+                let busy = status.busy();
+                let to = sensor.as_ref().map_or(HomeTo::HardStop, HomeTo::Sensor);
+                let result = motor
+                    .home(to, velocity, distance)
+                    .await
+                    .map_err(|e| e.to_string());
+                // Homing leaves the configured speed behind
+                motor.set_velocity(GANTRY_VELOCITY).await.unwrap();
+                match result {
+                    Ok(()) => busy.idle(),
+                    Err(e) => busy.fault(e.as_str()),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Two motors driving one axis, one on each side of a wide gantry. Every command goes to both
+// and the axis faults if they drift apart by more than `max_skew` revs, before the frame racks
+pub struct DualDriveAxis {
+    motors: MotorGroup,
+    max_skew: Revolutions,
+}
+
+impl DualDriveAxis {
+    pub fn new(primary: ClearCoreMotor, secondary: ClearCoreMotor, max_skew: Revolutions) -> Self {
+        Self {
+            motors: MotorGroup::new(vec![primary, secondary]),
+            max_skew,
+        }
+    }
+
+    pub fn primary(&self) -> &ClearCoreMotor {
+        &self.motors.motors()[0]
+    }
+
+    pub fn secondary(&self) -> &ClearCoreMotor {
+        &self.motors.motors()[1]
+    }
+
+    pub async fn enable(&self) -> Result<(), Box<dyn Error>> {
+        self.primary().enable().await?;
+        self.secondary().enable().await?;
+        Ok(())
+    }
+
+    pub async fn set_velocity(&self, velocity: RevsPerSec) -> Result<(), Box<dyn Error>> {
+        self.primary().set_velocity(velocity).await?;
+        self.secondary().set_velocity(velocity).await
+    }
+
+    pub async fn set_acceleration(&self, acceleration: RevsPerSec2) -> Result<(), Box<dyn Error>> {
+        self.primary().set_acceleration(acceleration).await?;
+        self.secondary().set_acceleration(acceleration).await
+    }
+
+    pub async fn get_position(&self) -> Result<Revolutions, Box<dyn Error>> {
+        self.primary().get_position().await
+    }
+
+    // Secondary minus primary position
+    pub async fn skew(&self) -> Result<Revolutions, Box<dyn Error>> {
+        let primary = self.primary().get_position().await?;
+        Ok(self.secondary().get_position().await? - primary)
+    }
+
+    // Stops both motors if the skew is over the limit
+    pub async fn check_skew(&self) -> Result<(), Box<dyn Error>> {
+        let skew = self.skew().await?;
+        if skew.0.abs() > self.max_skew.0 {
+            self.motors.stop_all().await;
+            return Err(Box::from(format!(
+                "Gantry skew of {skew:.3} is over the {:.3} limit",
+                self.max_skew
+            )));
+        }
+        Ok(())
+    }
+
+    // Refuses to start from a skewed position, the move would only carry the skew along
+    pub async fn absolute_move(&self, position: Revolutions) -> Result<(), Box<dyn Error>> {
+        self.check_skew().await?;
+        self.motors.move_all(&[position, position]).await
+    }
+
+    // Checks the skew on every sample until both motors have stopped
+    pub async fn wait_for_move(
+        &self,
+        sampling_rate: Duration,
+        cancel: &CancelToken,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            self.check_skew().await?;
+            if cancel.is_cancelled() {
+                self.motors.stop_all().await;
+                return Err(Box::from("Gantry move cancelled"));
+            }
+            if self.primary().get_status().await? != Status::Moving
+                && self.secondary().get_status().await? != Status::Moving
+            {
+                return Ok(());
+            }
+            tokio::time::sleep(sampling_rate).await;
+        }
+    }
+
+    pub async fn absolute_move_cancellable(
+        &self,
+        position: Revolutions,
+        sampling_rate: Duration,
+        cancel: &CancelToken,
+    ) -> Result<(), Box<dyn Error>> {
+        self.absolute_move(position).await?;
+        self.wait_for_move(sampling_rate, cancel).await
+    }
+
+    // Each side homes on its own, against its own hard stop or sensor, which squares the
+    // gantry. Skew isn't checked while homing
+    pub async fn home(
+        &self,
+        sensors: Option<(&DigitalInput, &DigitalInput)>,
+        velocity: RevsPerSec,
+        distance: Revolutions,
+    ) -> Result<(), Box<dyn Error>> {
+        let (primary, secondary) = match sensors {
+            Some((primary, secondary)) => (HomeTo::Sensor(primary), HomeTo::Sensor(secondary)),
+            None => (HomeTo::HardStop, HomeTo::HardStop),
+        };
+        // The first side to finish holds its result while the other homes, so keep messages
+        let (primary, secondary) = tokio::join!(
+            async {
+                let home = self.primary().home(primary, velocity, distance).await;
+                home.map_err(|e| e.to_string())
+            },
+            async {
+                let home = self.secondary().home(secondary, velocity, distance).await;
+                home.map_err(|e| e.to_string())
+            }
+        );
+        primary?;
+        Ok(secondary?)
+    }
+}
+
+// `abortable_gantry` for a dual drive axis. Home needs a sensor on each side, so a Home
+// command with a sensor faults; without one both sides home against their hard stops
+pub async fn dual_drive_gantry(
+    axis: DualDriveAxis,
+    mut rx: Receiver<GantryCommand>,
+    status: StatusTracker,
+    abort: CancelToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    axis.set_acceleration(RevsPerSec2(40.)).await.unwrap();
+    axis.set_velocity(GANTRY_VELOCITY).await.unwrap();
+    axis.enable().await.unwrap();
+    while let Some(cmd) = rx.recv().await {
+        match cmd {
+            GantryCommand::GetPosition(sender) => {
+                let pos = axis.get_position().await.unwrap();
+                sender.send(pos).unwrap();
+            }
+            GantryCommand::GoTo(pos) => {
+                let busy = status.busy();
+                let result = axis
+                    .absolute_move_cancellable(pos, Duration::from_secs_f64(1.0), &abort)
+                    .await
+                    .map_err(|e| e.to_string());
+                match result {
+                    Ok(()) => busy.idle(),
+                    Err(e) => busy.fault(e.as_str()),
+                }
+            }
+            GantryCommand::GetMotionStats(sender) => {
+                sender.send(axis.primary().motion_stats()).unwrap();
+            }
+            GantryCommand::Home {
+                sensor,
+                velocity,
+                distance,
+            } => {
+                if sensor.is_some() {
+                    status.fault("Dual drive gantry needs a home sensor on each side");
+                    continue;
+                }
+                let busy = status.busy();
+                let result = axis
+                    .home(None, velocity, distance)
+                    .await
+                    .map_err(|e| e.to_string());
+                axis.set_velocity(GANTRY_VELOCITY).await.unwrap();
+                match result {
+                    Ok(()) => busy.idle(),
+                    Err(e) => busy.fault(e.as_str()),
                 }
             }
         }
@@ -36,7 +280,7 @@ pub async fn gantry(
 
 #[tokio::test]
 async fn test_gantry() {
-    let positions = vec![92.0, 24.5, 47.0, 69.5, 92.0];
+    let positions = [92.0, 24.5, 47.0, 69.5, 92.0].map(Revolutions);
     let (tx, rx) = tokio::sync::mpsc::channel(10);
     let (gtx, grx) = tokio::sync::mpsc::channel(10);
     let gantry_handler = tokio::spawn(gantry(ClearCoreMotor::new(0, 800, tx), grx));
@@ -58,7 +302,7 @@ async fn test_gantry() {
 
 #[tokio::test]
 async fn test_gantry_home() {
-    let pos = -0.25;
+    let pos = Revolutions(-0.25);
     let (tx, rx) = tokio::sync::mpsc::channel(10);
     let (gtx, grx) = tokio::sync::mpsc::channel(10);
     let gantry_handler = tokio::spawn(gantry(ClearCoreMotor::new(0, 800, tx), grx));
@@ -75,3 +319,45 @@ async fn test_gantry_home() {
 
     let (_, _, _) = tokio::join!(goto, gantry_handler, cc1_handler);
 }
+
+#[tokio::test(start_paused = true)]
+async fn test_dual_drive_axis() {
+    use crate::subsystems::status::{SubsystemState, SubsystemStatus};
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let axis = DualDriveAxis::new(bench.motor(0, 800), bench.motor(1, 800), Revolutions(0.1));
+    let (gtx, grx) = tokio::sync::mpsc::channel(10);
+    let status = StatusTracker::new("gantry");
+    let gantry_handler = tokio::spawn(dual_drive_gantry(
+        axis,
+        grx,
+        status.clone(),
+        CancelToken::new(),
+    ));
+
+    gtx.send(GantryCommand::GoTo(Revolutions(5.)))
+        .await
+        .unwrap();
+    let (rep_tx, rep_rx) = oneshot::channel();
+    gtx.send(GantryCommand::GetPosition(rep_tx)).await.unwrap();
+    assert_eq!(rep_rx.await.unwrap(), Revolutions(5.));
+    assert_eq!(bench.controller().motor_position(1), 4000);
+    assert_eq!(status.state(), SubsystemState::Idle);
+
+    // One side slips, e.g. a belt jumping a tooth
+    bench
+        .motor(1, 800)
+        .relative_move(Revolutions(0.5))
+        .await
+        .unwrap();
+    gtx.send(GantryCommand::GoTo(Revolutions(10.)))
+        .await
+        .unwrap();
+    let (rep_tx, rep_rx) = oneshot::channel();
+    gtx.send(GantryCommand::GetPosition(rep_tx)).await.unwrap();
+    assert_eq!(rep_rx.await.unwrap(), Revolutions(5.));
+    assert_eq!(status.state(), SubsystemState::Faulted);
+
+    drop(gtx);
+    gantry_handler.await.unwrap().unwrap();
+}
@@ -0,0 +1,90 @@
+use crate::components::clear_core_io::DigitalInput;
+use std::error::Error;
+use std::time::Duration;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuardState {
+    Closed,
+    Open,
+}
+
+pub struct GuardMonitor {
+    // Guard switches read true while their door is closed
+    switches: Vec<DigitalInput>,
+    interval: Duration,
+    state: watch::Sender<GuardState>,
+}
+
+impl GuardMonitor {
+    pub fn new(switches: Vec<DigitalInput>, interval: Duration) -> Self {
+        // Start out open so nothing moves until the first poll confirms every guard is shut
+        let (state, _) = watch::channel(GuardState::Open);
+        Self {
+            switches,
+            interval,
+            state,
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<GuardState> {
+        self.state.subscribe()
+    }
+
+    async fn poll(&self) -> GuardState {
+        for switch in self.switches.iter() {
+            match switch.get_state().await {
+                Ok(true) => continue,
+                // A failed read is treated the same as an open door
+                _ => return GuardState::Open,
+            }
+        }
+        GuardState::Closed
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let state = self.poll().await;
+            self.state.send_if_modified(|current| {
+                if *current != state {
+                    if state == GuardState::Open {
+                        println!("WARNING: Guard opened, motion locked out");
+                    }
+                    *current = state;
+                    true
+                } else {
+                    false
+                }
+            });
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+pub async fn wait_for_guard_closed(
+    guard: &mut watch::Receiver<GuardState>,
+) -> Result<(), Box<dyn Error>> {
+    guard.wait_for(|state| *state == GuardState::Closed).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_guard_blocks_motion() {
+    use crate::components::clear_core_motor::ClearCoreMotor;
+    use crate::controllers::clear_core::Message;
+    use crate::util::units::Revolutions;
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(10);
+    let mock_client = tokio::spawn(async move {
+        // Guard switch reads open
+        while let Some(msg) = rx.recv().await {
+            msg.response.send(vec![2, b'I', b'0', b'0', 13]).unwrap();
+        }
+    });
+    let monitor = GuardMonitor::new(vec![DigitalInput::new(0, tx.clone())], Duration::ZERO);
+    let guard = monitor.subscribe();
+    assert_eq!(monitor.poll().await, GuardState::Open);
+    let motor = ClearCoreMotor::new(0, 800, tx).with_guard(guard);
+    assert!(motor.relative_move(Revolutions(1.0)).await.is_err());
+    drop((motor, monitor));
+    mock_client.await.unwrap();
+}
@@ -1,67 +1,424 @@
 use crate::components::clear_core_io::HBridgeState;
 use crate::interface::tcp::client;
-use crate::subsystems::linear_actuator::{LinearActuator, RelayHBridge};
+use crate::subsystems::linear_actuator::{LinearActuator, RelayHBridge, TravelCalibration};
+use crate::subsystems::status::{SubsystemState, SubsystemStatus};
 use std::error::Error;
+use std::sync::Mutex;
 use std::time::Duration;
+use tokio::sync::watch;
 use tokio::time::Instant;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HatchState {
+    Opening,
+    Closing,
+    Open,
+    Closed,
+    Unknown,
+    Stalled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HatchStatus {
+    pub state: HatchState,
+    pub position: Option<isize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StallDetection {
+    // Feedback must move at least this many counts within the window to count as moving
+    pub counts: isize,
+    pub window: Duration,
+}
+
+struct StallDetector {
+    detection: StallDetection,
+    anchor: isize,
+    anchor_time: Instant,
+}
+
+impl StallDetector {
+    fn new(detection: StallDetection, position: isize, now: Instant) -> Self {
+        Self {
+            detection,
+            anchor: position,
+            anchor_time: now,
+        }
+    }
+
+    // Time the hatch wasn't being driven, which doesn't count towards a stall
+    fn pause(&mut self, paused: Duration) {
+        self.anchor_time += paused;
+    }
+
+    fn is_stalled(&mut self, position: isize, now: Instant) -> bool {
+        if (position - self.anchor).abs() >= self.detection.counts {
+            self.anchor = position;
+            self.anchor_time = now;
+            false
+        } else {
+            now - self.anchor_time > self.detection.window
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlowApproach {
+    // Within this many counts of the set point the relay is pulsed instead of held on
+    pub band: isize,
+    pub period: Duration,
+    // Fraction of each period the relay is on
+    pub duty: f64,
+}
+
+impl SlowApproach {
+    fn on_time(&self) -> Duration {
+        self.period.mul_f64(self.duty.clamp(0., 1.))
+    }
+}
+
 pub struct Hatch<T: LinearActuator> {
+    name: String,
     actuator: T,
     timeout: Duration,
+    // Off unless set, the right window depends on how fast the hatch travels
+    stall_detection: Option<StallDetection>,
+    slow_approach: Option<SlowApproach>,
+    calibration: Option<TravelCalibration>,
+    status: watch::Sender<HatchStatus>,
+    last_error: Mutex<Option<String>>,
 }
 
 impl<T: LinearActuator> Hatch<T> {
     pub fn new(actuator: T, timeout: Duration) -> Self {
-        Self { actuator, timeout }
+        let (status, _) = watch::channel(HatchStatus {
+            state: HatchState::Unknown,
+            position: None,
+        });
+        Self {
+            name: "hatch".to_string(),
+            actuator,
+            timeout,
+            stall_detection: None,
+            slow_approach: None,
+            calibration: None,
+            status,
+            last_error: Mutex::new(None),
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn with_stall_detection(mut self, stall_detection: StallDetection) -> Self {
+        self.stall_detection = Some(stall_detection);
+        self
+    }
+
+    // Eases the door into its set point so it doesn't slam and throw up product dust
+    pub fn with_slow_approach(mut self, slow_approach: SlowApproach) -> Self {
+        self.slow_approach = Some(slow_approach);
+        self
+    }
+
+    // Enables the percent of travel methods. Sealer jaws, as a SynchronizedPair, get them too
+    // by being wrapped in a Hatch
+    pub fn with_calibration(mut self, calibration: TravelCalibration) -> Self {
+        self.calibration = Some(calibration);
+        self
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<HatchStatus> {
+        self.status.subscribe()
+    }
+
+    fn publish(&self, state: HatchState, position: Option<isize>) {
+        self.status.send_modify(|status| {
+            status.state = state;
+            if position.is_some() {
+                status.position = position;
+            }
+        });
+    }
+
+    fn record_error(&self, error: String) {
+        *self.last_error.lock().unwrap() = Some(error);
     }
 
     pub async fn get_position(&self) -> Result<isize, Box<dyn Error>> {
-        self.actuator.get_feedback().await
+        let position = self.actuator.get_feedback().await?;
+        self.status
+            .send_modify(|status| status.position = Some(position));
+        Ok(position)
+    }
+
+    fn calibration(&self) -> Result<TravelCalibration, Box<dyn Error>> {
+        self.calibration
+            .ok_or_else(|| Box::from(format!("{} has no travel calibration", self.name)))
+    }
+
+    pub async fn get_percent(&self) -> Result<f64, Box<dyn Error>> {
+        let calibration = self.calibration()?;
+        calibration.to_percent(self.get_position().await?)
+    }
+
+    // Opens or closes as needed to reach `percent` of travel
+    pub async fn move_to_percent(&self, percent: f64) -> Result<(), Box<dyn Error>> {
+        let set_point = self.calibration()?.to_raw(percent)?;
+        if percent > self.get_percent().await? {
+            self.open(set_point).await
+        } else {
+            self.close(set_point).await
+        }
+    }
+
+    // Calibration run: drives to each end for `travel_time`, longer than a full stroke, and
+    // reads the feedback there. The hatch is left open
+    pub async fn measure_travel(
+        &self,
+        travel_time: Duration,
+    ) -> Result<TravelCalibration, Box<dyn Error>> {
+        self.timed_close(travel_time).await?;
+        let closed = self.get_position().await?;
+        self.timed_open(travel_time).await?;
+        let open = self.get_position().await?;
+        if closed == open {
+            return Err(Box::from(format!(
+                "{} feedback read {closed} at both ends of travel",
+                self.name
+            )));
+        }
+        Ok(TravelCalibration { closed, open })
     }
 
     pub async fn timed_open(&self, time: Duration) -> Result<(), Box<dyn Error>> {
+        self.publish(HatchState::Opening, None);
         self.actuator.actuate(HBridgeState::Pos).await?;
         tokio::time::sleep(time).await;
         self.actuator.actuate(HBridgeState::Off).await?;
+        self.publish(HatchState::Open, None);
         Ok(())
     }
 
     pub async fn open(&self, set_point: isize) -> Result<(), Box<dyn Error>> {
-        self.actuator.actuate(HBridgeState::Pos).await?;
-        let star_time = Instant::now();
-        while self.actuator.get_feedback().await? >= set_point {
-            let curr_time = Instant::now();
-            if (curr_time - star_time) > self.timeout {
-                //TODO: Add some proper error handling
-                println!("Timed Out!");
-                break;
-            }
-        }
-        self.actuator.actuate(HBridgeState::Off).await?;
-        Ok(())
+        self.drive_to(HBridgeState::Pos, |position| position - set_point)
+            .await
     }
 
     pub async fn timed_close(&self, time: Duration) -> Result<(), Box<dyn Error>> {
+        self.publish(HatchState::Closing, None);
         self.actuator.actuate(HBridgeState::Neg).await?;
         tokio::time::sleep(time).await;
         self.actuator.actuate(HBridgeState::Off).await?;
+        self.publish(HatchState::Closed, None);
         Ok(())
     }
 
     pub async fn close(&self, set_point: isize) -> Result<(), Box<dyn Error>> {
-        self.actuator.actuate(HBridgeState::Neg).await?;
+        self.drive_to(HBridgeState::Neg, |position| set_point - position)
+            .await
+    }
+
+    // Fails if the hatch stalls or times out short of the set point
+    async fn drive_to(
+        &self,
+        direction: HBridgeState,
+        // Counts left to travel, the set point is reached once this goes negative
+        remaining: impl Fn(isize) -> isize,
+    ) -> Result<(), Box<dyn Error>> {
+        let (moving, done) = match direction {
+            HBridgeState::Pos => (HatchState::Opening, HatchState::Open),
+            _ => (HatchState::Closing, HatchState::Closed),
+        };
+        self.publish(moving, None);
+        self.actuator.actuate(direction).await?;
         let star_time = Instant::now();
-        while self.actuator.get_feedback().await? <= set_point {
+        let mut position = self.actuator.get_feedback().await?;
+        let mut stall = self
+            .stall_detection
+            .map(|detection| StallDetector::new(detection, position, star_time));
+        let (state, error) = loop {
+            self.publish(moving, Some(position));
+            if remaining(position) < 0 {
+                break (done, None);
+            }
             let curr_time = Instant::now();
+            if let Some(stall) = stall.as_mut() {
+                if stall.is_stalled(position, curr_time) {
+                    println!("WARNING: {} stalled at {position}", self.name);
+                    break (HatchState::Stalled, Some(format!("Stalled at {position}")));
+                }
+            }
             if (curr_time - star_time) > self.timeout {
-                //TODO: Add some proper error handling
-                println!("Timed Out!");
-                break;
+                println!("WARNING: {} timed out at {position}", self.name);
+                break (
+                    HatchState::Unknown,
+                    Some(format!("Timed out at {position}")),
+                );
             }
-        }
+            if let Some(approach) = &self.slow_approach {
+                if remaining(position) <= approach.band {
+                    // The hatch stops between pulses on purpose, so the stall detector only
+                    // counts the time it's driven. Switching off comes out of the off time, and
+                    // switching back on, relay dead time included, is left out of the on time
+                    let pulse_start = Instant::now();
+                    tokio::time::sleep(approach.on_time()).await;
+                    let off_at = Instant::now();
+                    self.actuator.actuate(HBridgeState::Off).await?;
+                    tokio::time::sleep_until(pulse_start + approach.period).await;
+                    self.actuator.actuate(direction).await?;
+                    if let Some(stall) = stall.as_mut() {
+                        stall.pause(Instant::now() - off_at);
+                    }
+                }
+            }
+            position = self.actuator.get_feedback().await?;
+        };
         self.actuator.actuate(HBridgeState::Off).await?;
-        Ok(())
+        self.publish(state, Some(position));
+        match error {
+            Some(error) => {
+                self.record_error(error.clone());
+                Err(Box::from(format!("{}: {error}", self.name)))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: LinearActuator> SubsystemStatus for Hatch<T> {
+    fn name(&self) -> String {
+        self.name.clone()
     }
+
+    fn state(&self) -> SubsystemState {
+        match self.status.borrow().state {
+            HatchState::Opening | HatchState::Closing => SubsystemState::Busy,
+            HatchState::Open | HatchState::Closed => SubsystemState::Idle,
+            HatchState::Stalled => SubsystemState::Faulted,
+            HatchState::Unknown => SubsystemState::Unknown,
+        }
+    }
+
+    fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_stall_detector() {
+    let start = Instant::now();
+    let detection = StallDetection {
+        counts: 50,
+        window: Duration::from_millis(500),
+    };
+    let mut stall = StallDetector::new(detection, 1000, start);
+    assert!(!stall.is_stalled(1010, start + Duration::from_millis(300)));
+    assert!(!stall.is_stalled(900, start + Duration::from_millis(400)));
+    assert!(!stall.is_stalled(880, start + Duration::from_millis(800)));
+    assert!(stall.is_stalled(880, start + Duration::from_millis(1000)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_hatch_timeout() {
+    use std::sync::atomic::{AtomicIsize, Ordering};
+    // Creeps forward fast enough to never stall but never reaches the set point
+    struct CreepingActuator(AtomicIsize);
+    impl LinearActuator for CreepingActuator {
+        async fn get_feedback(&self) -> Result<isize, Box<dyn Error>> {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(self.0.fetch_add(60, Ordering::Relaxed))
+        }
+        async fn actuate(&self, _power: HBridgeState) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+    }
+    let hatch = Hatch::new(
+        CreepingActuator(AtomicIsize::new(0)),
+        Duration::from_secs(30),
+    );
+    let status = hatch.subscribe();
+    let start = Instant::now();
+    assert!(hatch.open(-1_000_000).await.is_err());
+    assert_eq!(status.borrow().state, HatchState::Unknown);
+    assert!(hatch.last_error().unwrap().starts_with("Timed out"));
+    assert!(Instant::now() - start >= Duration::from_secs(30));
+}
+
+#[tokio::test]
+async fn test_hatch_stalled() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let hatch = bench.hatch((2, 3), 4, 3000, Duration::from_millis(300));
+    // Feedback that never moves, as with a jammed door
+    bench.controller().link_actuator((2, 3), 4, 100);
+    assert!(hatch.open(1000).await.is_err());
+    assert_eq!(hatch.subscribe().borrow().state, HatchState::Unknown);
+
+    let hatch = hatch.with_stall_detection(StallDetection {
+        counts: 50,
+        window: Duration::from_millis(100),
+    });
+    assert!(hatch.open(1000).await.is_err());
+    assert_eq!(hatch.subscribe().borrow().state, HatchState::Stalled);
+    assert!(hatch.last_error().unwrap().starts_with("Stalled"));
+    assert_eq!(bench.controller().output(2), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_hatch_slow_approach() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let controller = bench.controller();
+    controller.set_input(4, 3000);
+    // Travels 500 counts/s while driven, and each feedback read takes 10 ms
+    controller.link_actuator_at((2, 3), 4, -500.);
+    controller.set_latency(b"I4", Duration::from_millis(10), Duration::from_millis(10));
+    // Default relay dead time, and pulses too short to cover 50 counts each
+    let actuator = RelayHBridge::new(bench.sender(), (2, 3), 4);
+    let hatch = Hatch::new(actuator, Duration::from_secs(30))
+        .with_stall_detection(StallDetection {
+            counts: 50,
+            window: Duration::from_millis(500),
+        })
+        .with_slow_approach(SlowApproach {
+            band: 500,
+            period: Duration::from_millis(200),
+            duty: 0.1,
+        });
+    let start = Instant::now();
+    hatch.open(1000).await.unwrap();
+    assert_eq!(hatch.subscribe().borrow().state, HatchState::Open);
+    let pulses = controller
+        .commands()
+        .iter()
+        .filter(|cmd| cmd.as_slice() == b"\x02O20")
+        .count();
+    assert!(pulses >= 20);
+    // 3 s at full speed down to 1500, then about 15 counts per 200 ms pulse
+    assert!(Instant::now() - start >= Duration::from_secs(8));
+}
+
+#[tokio::test]
+async fn test_hatch_percent_of_travel() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let hatch = bench.hatch((2, 3), 4, 3000, Duration::from_secs(5));
+    assert!(hatch.move_to_percent(50.).await.is_err());
+    let hatch = hatch.with_calibration(TravelCalibration {
+        closed: 3000,
+        open: 1000,
+    });
+    assert_eq!(hatch.get_percent().await.unwrap(), 0.);
+    hatch.move_to_percent(50.).await.unwrap();
+    assert_eq!(hatch.subscribe().borrow().state, HatchState::Open);
+    assert!((50. ..=55.).contains(&hatch.get_percent().await.unwrap()));
+    hatch.move_to_percent(10.).await.unwrap();
+    assert_eq!(hatch.subscribe().borrow().state, HatchState::Closed);
+    assert!((5. ..=10.).contains(&hatch.get_percent().await.unwrap()));
+    assert!(hatch.measure_travel(Duration::ZERO).await.is_err());
 }
 
 #[tokio::test]
@@ -0,0 +1,148 @@
+use crate::components::clear_core_io::DigitalInput;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub signal: String,
+    pub rising: bool,
+    // Midpoint of the read that saw the change, so the error is at most half a round trip
+    // plus the poll interval
+    pub at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IntervalStats {
+    pub count: u32,
+    pub min: Duration,
+    pub max: Duration,
+    total: Duration,
+}
+
+impl IntervalStats {
+    fn record(&mut self, interval: Duration) {
+        if self.count == 0 || interval < self.min {
+            self.min = interval;
+        }
+        self.max = self.max.max(interval);
+        self.total += interval;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.total / self.count)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SignalTiming {
+    pub state: bool,
+    pub last_rise: Option<Instant>,
+    pub last_fall: Option<Instant>,
+    // Rising edge to rising edge, e.g. one bag-feed cycle
+    pub period: IntervalStats,
+    // Rising edge to the following falling edge, e.g. how long a photo-eye stays blocked
+    pub high_time: IntervalStats,
+}
+
+impl SignalTiming {
+    fn record(&mut self, rising: bool, at: Instant) {
+        self.state = rising;
+        if rising {
+            if let Some(last_rise) = self.last_rise {
+                self.period.record(at - last_rise);
+            }
+            self.last_rise = Some(at);
+        } else {
+            if let Some(last_rise) = self.last_rise {
+                self.high_time.record(at - last_rise);
+            }
+            self.last_fall = Some(at);
+        }
+    }
+}
+
+pub struct InputWatcher {
+    inputs: Vec<(String, DigitalInput)>,
+    interval: Duration,
+    timing: watch::Sender<HashMap<String, SignalTiming>>,
+    events: Option<mpsc::Sender<Edge>>,
+}
+
+impl InputWatcher {
+    pub fn new(interval: Duration) -> Self {
+        let (timing, _) = watch::channel(HashMap::new());
+        Self {
+            inputs: Vec::new(),
+            interval,
+            timing,
+            events: None,
+        }
+    }
+
+    pub fn watch(mut self, signal: &str, input: DigitalInput) -> Self {
+        self.inputs.push((signal.to_string(), input));
+        self
+    }
+
+    pub fn with_events(mut self, events: mpsc::Sender<Edge>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<HashMap<String, SignalTiming>> {
+        self.timing.subscribe()
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // The first read of each input only establishes its level, it is not an edge
+        let mut levels: Vec<Option<bool>> = vec![None; self.inputs.len()];
+        loop {
+            for ((signal, input), level) in self.inputs.iter().zip(levels.iter_mut()) {
+                let before = Instant::now();
+                let Ok(state) = input.get_state().await else {
+                    println!("WARNING: Failed to read {signal}");
+                    continue;
+                };
+                let at = before + (Instant::now() - before) / 2;
+                let previous = level.replace(state);
+                if previous.is_none() || previous == Some(state) {
+                    continue;
+                }
+                self.timing.send_modify(|timing| {
+                    timing.entry(signal.clone()).or_default().record(state, at)
+                });
+                if let Some(events) = &self.events {
+                    let edge = Edge {
+                        signal: signal.clone(),
+                        rising: state,
+                        at,
+                    };
+                    events.send(edge).await?;
+                }
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+#[test]
+fn test_signal_timing() {
+    let start = Instant::now();
+    let mut timing = SignalTiming::default();
+    timing.record(true, start);
+    timing.record(false, start + Duration::from_millis(200));
+    timing.record(true, start + Duration::from_millis(1000));
+    timing.record(false, start + Duration::from_millis(1400));
+    timing.record(true, start + Duration::from_millis(3000));
+    assert!(timing.state);
+    assert_eq!(timing.period.count, 2);
+    assert_eq!(timing.period.min, Duration::from_millis(1000));
+    assert_eq!(timing.period.max, Duration::from_millis(2000));
+    assert_eq!(timing.period.mean(), Some(Duration::from_millis(1500)));
+    assert_eq!(timing.high_time.mean(), Some(Duration::from_millis(300)));
+    assert_eq!(IntervalStats::default().mean(), None);
+}
@@ -1,8 +1,19 @@
-use crate::components::clear_core_io::{AnalogInput, HBridge, HBridgeState, Output, OutputState};
+use crate::components::analog_source::AnalogSource;
+use crate::components::clear_core_io::{
+    AnalogInput, HBridge, HBridgeState, Output, OutputState, Ramp, Watchdog,
+};
+use crate::components::send_recv::SendRecv;
+use crate::controllers::clear_core;
 pub use crate::controllers::clear_core::Message;
+use crate::subsystems::pneumatics::PressureState;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::time::Instant;
 
 //TODO: Move this to a hatches module
 #[allow(unused)]
@@ -21,6 +32,38 @@ pub trait LinearActuator {
     ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
 }
 
+// Raw feedback at each end of travel, from a calibration run. Positions in percent of travel,
+// 0 closed and 100 open, carry over between machines whose feedback pots span different raw
+// ranges; either end may read higher
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TravelCalibration {
+    pub closed: isize,
+    pub open: isize,
+}
+
+impl TravelCalibration {
+    // Fails for a calibration with both ends at the same reading, which has no travel to
+    // divide into percent
+    fn span(&self) -> Result<f64, Box<dyn Error>> {
+        if self.open == self.closed {
+            return Err(Box::from(format!(
+                "Travel calibration reads {} at both ends",
+                self.closed
+            )));
+        }
+        Ok((self.open - self.closed) as f64)
+    }
+
+    pub fn to_percent(&self, raw: isize) -> Result<f64, Box<dyn Error>> {
+        Ok(100. * (raw - self.closed) as f64 / self.span()?)
+    }
+
+    pub fn to_raw(&self, percent: f64) -> Result<isize, Box<dyn Error>> {
+        let travel = self.span()? * percent.clamp(0., 100.) / 100.;
+        Ok(self.closed + travel.round() as isize)
+    }
+}
+
 pub struct SimpleLinearActuator {
     output: HBridge,
     feedback: AnalogInput,
@@ -47,6 +90,11 @@ impl SimpleLinearActuator {
     pub fn from_io(output: HBridge, feedback: AnalogInput) -> Self {
         Self { output, feedback }
     }
+
+    pub fn with_ramp(mut self, ramp: Ramp) -> Self {
+        self.output = self.output.with_ramp(ramp);
+        self
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -55,19 +103,48 @@ pub enum ActuatorCh {
     Chb,
 }
 
-pub struct RelayHBridge {
-    fb_pair: (AnalogInput, Option<AnalogInput>),
+const RELAY_DEAD_TIME: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy)]
+pub struct CurrentLimits {
+    // Readings above this are treated as a stall/overload and the relays are dropped
+    pub overload: isize,
+    // Once running, a reading below this means the actuator's internal limit switch opened
+    pub end_of_travel: isize,
+    // Ignore the in-rush spike right after energizing
+    pub inrush_blanking: Duration,
+    pub sample_rate: Duration,
+}
+
+struct CurrentSense {
+    input: AnalogInput,
+    limits: CurrentLimits,
+}
+
+pub struct RelayHBridge<F: AnalogSource = AnalogInput> {
+    fb_pair: (Option<F>, Option<F>),
     output_pair: (Output, Output),
+    dead_time: Duration,
+    current: Option<CurrentSense>,
+    pressure: Option<watch::Receiver<PressureState>>,
+    watchdog: Option<Watchdog>,
+    // Last state both relays were commanded into, unknown until a command goes through
+    commanded: Mutex<Option<HBridgeState>>,
 }
 
-impl RelayHBridge {
+impl RelayHBridge<AnalogInput> {
     pub fn new(sender: Sender<Message>, output_pair_ids: (u8, u8), feedback_id: u8) -> Self {
         Self {
-            fb_pair: (AnalogInput::new(feedback_id, sender.clone()), None),
+            fb_pair: (Some(AnalogInput::new(feedback_id, sender.clone())), None),
             output_pair: (
                 Output::new(output_pair_ids.0, sender.clone()),
                 Output::new(output_pair_ids.1, sender),
             ),
+            dead_time: RELAY_DEAD_TIME,
+            current: None,
+            pressure: None,
+            watchdog: None,
+            commanded: Mutex::new(None),
         }
     }
 
@@ -78,61 +155,342 @@ impl RelayHBridge {
     ) -> Self {
         Self {
             fb_pair: (
-                AnalogInput::new(feedback_ids.0, sender.clone()),
+                Some(AnalogInput::new(feedback_ids.0, sender.clone())),
                 Some(AnalogInput::new(feedback_ids.1, sender.clone())),
             ),
             output_pair: (
                 Output::new(output_ids.0, sender.clone()),
                 Output::new(output_ids.1, sender),
             ),
+            dead_time: RELAY_DEAD_TIME,
+            current: None,
+            pressure: None,
+            watchdog: None,
+            commanded: Mutex::new(None),
         }
     }
 
-    pub fn from_io(output_pair: (Output, Output), feedback: AnalogInput) -> Self {
+    pub fn with_current_feedback(
+        sender: Sender<Message>,
+        output_ids: (u8, u8),
+        current_id: u8,
+        limits: CurrentLimits,
+    ) -> Self {
         Self {
-            fb_pair: (feedback, None),
+            fb_pair: (None, None),
+            output_pair: (
+                Output::new(output_ids.0, sender.clone()),
+                Output::new(output_ids.1, sender.clone()),
+            ),
+            dead_time: RELAY_DEAD_TIME,
+            current: Some(CurrentSense {
+                input: AnalogInput::new(current_id, sender),
+                limits,
+            }),
+            pressure: None,
+            watchdog: None,
+            commanded: Mutex::new(None),
+        }
+    }
+}
+
+impl<F: AnalogSource + Sync> RelayHBridge<F> {
+    pub fn from_io(output_pair: (Output, Output), feedback: F) -> Self {
+        Self {
+            fb_pair: (Some(feedback), None),
             output_pair,
+            dead_time: RELAY_DEAD_TIME,
+            current: None,
+            pressure: None,
+            watchdog: None,
+            commanded: Mutex::new(None),
         }
     }
 
     pub fn from_io_with_dual_feedback(
         output_pair: (Output, Output),
-        feedback_pair: (AnalogInput, AnalogInput),
+        feedback_pair: (F, F),
     ) -> Self {
         Self {
-            fb_pair: (feedback_pair.0, Some(feedback_pair.1)),
+            fb_pair: (Some(feedback_pair.0), Some(feedback_pair.1)),
             output_pair,
+            dead_time: RELAY_DEAD_TIME,
+            current: None,
+            pressure: None,
+            watchdog: None,
+            commanded: Mutex::new(None),
+        }
+    }
+
+    pub fn with_dead_time(mut self, dead_time: Duration) -> Self {
+        self.dead_time = dead_time;
+        self
+    }
+
+    pub fn with_watchdog(mut self, max_on: Duration) -> Self {
+        self.watchdog = Some(Watchdog::new(max_on));
+        self
+    }
+
+    pub fn watchdog_tripped(&self) -> bool {
+        self.watchdog.as_ref().is_some_and(Watchdog::tripped)
+    }
+
+    pub fn with_pressure_interlock(mut self, pressure: watch::Receiver<PressureState>) -> Self {
+        self.pressure = Some(pressure);
+        self
+    }
+
+    pub fn with_current_sense(mut self, input: AnalogInput, limits: CurrentLimits) -> Self {
+        self.current = Some(CurrentSense { input, limits });
+        self
+    }
+
+    pub async fn get_current(&self) -> Result<isize, Box<dyn Error>> {
+        match &self.current {
+            Some(sense) => sense.input.get_state().await,
+            None => Err(Box::from("Actuator has no current input")),
         }
     }
+
+    pub async fn actuate_to_end(
+        &self,
+        power: HBridgeState,
+        timeout: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        // Runs the actuator until its current drops off at end of travel, cutting power on
+        // overload or timeout
+        let Some(sense) = &self.current else {
+            return Err(Box::from("Actuator has no current input"));
+        };
+        self.actuate(power).await?;
+        let start_time = Instant::now();
+        let result = loop {
+            tokio::time::sleep(sense.limits.sample_rate).await;
+            let current = match sense.input.get_state().await {
+                Ok(current) => current,
+                Err(e) => break Err(e),
+            };
+            if current > sense.limits.overload {
+                break Err(Box::from(format!("Actuator overload: {current}")));
+            }
+            let elapsed = Instant::now() - start_time;
+            if elapsed > sense.limits.inrush_blanking && current < sense.limits.end_of_travel {
+                break Ok(());
+            }
+            if elapsed > timeout {
+                break Err(Box::from("Actuator did not reach end of travel"));
+            }
+        };
+        self.actuate(HBridgeState::Off).await?;
+        result
+    }
 }
 
-impl LinearActuator for RelayHBridge {
+impl<F: AnalogSource + Sync> LinearActuator for RelayHBridge<F> {
     async fn get_feedback(&self) -> Result<isize, Box<dyn Error>> {
-        let mut position = self.fb_pair.0.get_state().await?;
+        let Some(fb_a) = &self.fb_pair.0 else {
+            return Err(Box::from("Actuator has no position feedback"));
+        };
+        let mut position = fb_a.get_value().await?;
         if let Some(fb) = &self.fb_pair.1 {
-            let pos_b = fb.get_state().await?;
+            let pos_b = fb.get_value().await?;
             position = (position + pos_b) / 2
         }
         Ok(position)
     }
 
     async fn actuate(&self, power: HBridgeState) -> Result<(), Box<dyn Error>> {
+        if let Some(pressure) = &self.pressure {
+            let low = *pressure.borrow() == PressureState::Low;
+            if low && !matches!(power, HBridgeState::Off) {
+                return Err(Box::new(clear_core::Error::LowAirPressure));
+            }
+        }
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.disarm();
+        }
+        // Stays unknown if a command below fails
+        let previous = self.commanded.lock().unwrap().take();
+        // Break before make: both relays closed at once shorts the actuator supply. The dead
+        // time is only waited out when the other relay may still be closed
         match power {
             HBridgeState::Pos => {
+                self.output_pair.1.set_state(OutputState::Off).await?;
+                if !matches!(previous, Some(HBridgeState::Pos | HBridgeState::Off)) {
+                    tokio::time::sleep(self.dead_time).await;
+                }
                 self.output_pair.0.set_state(OutputState::On).await?;
             }
             HBridgeState::Neg => {
+                self.output_pair.0.set_state(OutputState::Off).await?;
+                if !matches!(previous, Some(HBridgeState::Neg | HBridgeState::Off)) {
+                    tokio::time::sleep(self.dead_time).await;
+                }
                 self.output_pair.1.set_state(OutputState::On).await?;
             }
             HBridgeState::Off => {
                 self.output_pair.0.set_state(OutputState::Off).await?;
                 self.output_pair.1.set_state(OutputState::Off).await?;
+                *self.commanded.lock().unwrap() = Some(power);
+                return Ok(());
             }
         }
+        *self.commanded.lock().unwrap() = Some(power);
+        if let Some(watchdog) = &self.watchdog {
+            let off = vec![
+                self.output_pair.0.off_command(),
+                self.output_pair.1.off_command(),
+            ];
+            watchdog.arm(self.output_pair.0.get_sender().clone(), off);
+        }
         Ok(())
     }
 }
 
+// Two actuators that must move together, e.g. the top and bottom jaws of a sealer. Both are
+// driven on every command, and feedback reads fail, switching both off, once they drift too far apart
+pub struct SynchronizedPair<A: LinearActuator, B: LinearActuator> {
+    first: A,
+    second: B,
+    max_mismatch: isize,
+}
+
+impl<A: LinearActuator + Sync, B: LinearActuator + Sync> SynchronizedPair<A, B> {
+    pub fn new(first: A, second: B, max_mismatch: isize) -> Self {
+        Self {
+            first,
+            second,
+            max_mismatch,
+        }
+    }
+
+    pub async fn get_feedback_pair(&self) -> Result<(isize, isize), Box<dyn Error>> {
+        let first = self.first.get_feedback().await?;
+        let second = self.second.get_feedback().await?;
+        Ok((first, second))
+    }
+}
+
+impl<A: LinearActuator + Sync, B: LinearActuator + Sync> LinearActuator for SynchronizedPair<A, B> {
+    async fn get_feedback(&self) -> Result<isize, Box<dyn Error>> {
+        let (first, second) = self.get_feedback_pair().await?;
+        if (first - second).abs() > self.max_mismatch {
+            self.actuate(HBridgeState::Off).await?;
+            return Err(Box::from(format!(
+                "Paired actuators out of sync: {first} and {second}"
+            )));
+        }
+        Ok((first + second) / 2)
+    }
+
+    async fn actuate(&self, power: HBridgeState) -> Result<(), Box<dyn Error>> {
+        // Back to back rather than joined, the errors aren't Send so can't be held across awaits
+        let first = self.first.actuate(power).await.map_err(|e| e.to_string());
+        let second = self.second.actuate(power).await.map_err(|e| e.to_string());
+        let result = first.and(second);
+        if result.is_err() {
+            // Never leave one jaw driving on its own
+            let _ = self.first.actuate(HBridgeState::Off).await;
+            let _ = self.second.actuate(HBridgeState::Off).await;
+        }
+        result.map_err(Box::from)
+    }
+}
+
+#[test]
+fn test_travel_calibration() {
+    let falling = TravelCalibration {
+        closed: 3000,
+        open: 1000,
+    };
+    assert_eq!(falling.to_percent(2500).unwrap(), 25.);
+    assert_eq!(falling.to_raw(25.).unwrap(), 2500);
+    assert_eq!(falling.to_raw(150.).unwrap(), 1000);
+    let rising = TravelCalibration {
+        closed: 400,
+        open: 34000,
+    };
+    assert_eq!(rising.to_raw(50.).unwrap(), 17200);
+    let raw = rising.to_raw(12.5).unwrap();
+    assert_eq!(rising.to_percent(raw).unwrap(), 12.5);
+    let stuck = TravelCalibration {
+        closed: 2000,
+        open: 2000,
+    };
+    assert!(stuck.to_percent(2000).is_err());
+    assert!(stuck.to_raw(50.).is_err());
+}
+
+#[tokio::test]
+async fn test_synchronized_pair() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let controller = bench.controller();
+    controller.set_input(4, 1000);
+    controller.set_input(5, 1000);
+    controller.link_actuator((0, 1), 4, 100);
+    controller.link_actuator((2, 3), 5, 100);
+    let jaws = SynchronizedPair::new(
+        RelayHBridge::new(bench.sender(), (0, 1), 4).with_dead_time(Duration::ZERO),
+        RelayHBridge::new(bench.sender(), (2, 3), 5).with_dead_time(Duration::ZERO),
+        150,
+    );
+    jaws.actuate(HBridgeState::Pos).await.unwrap();
+    assert_eq!(jaws.get_feedback().await.unwrap(), 1100);
+    assert_eq!((controller.output(0), controller.output(2)), (32700, 32700));
+
+    // Second jaw binds and stops following
+    controller.link_actuator((2, 3), 5, -100);
+    assert!(jaws.get_feedback().await.is_ok());
+    assert!(jaws.get_feedback().await.is_err());
+    assert_eq!((controller.output(0), controller.output(2)), (0, 0));
+}
+
+#[tokio::test]
+async fn test_relay_h_bridge_break_before_make() {
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(10);
+    let mock_client = tokio::spawn(async move {
+        let mut sent = Vec::new();
+        while let Some(msg) = rx.recv().await {
+            let end = msg.buffer.iter().position(|&b| b == 13).unwrap();
+            sent.push(msg.buffer[2..=end].to_vec());
+            msg.response
+                .send(vec![2, b'O', msg.buffer[2], b'0', 13])
+                .unwrap();
+        }
+        sent
+    });
+    let relay_h_bridge = RelayHBridge::new(tx, (2, 3), 4).with_dead_time(Duration::ZERO);
+    relay_h_bridge.actuate(HBridgeState::Pos).await.unwrap();
+    relay_h_bridge.actuate(HBridgeState::Neg).await.unwrap();
+    drop(relay_h_bridge);
+    let sent = mock_client.await.unwrap();
+    assert_eq!(sent[0], b"30\r");
+    assert_eq!(sent[1], b"232700\r");
+    assert_eq!(sent[2], b"20\r");
+    assert_eq!(sent[3], b"332700\r");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_relay_dead_time_on_reversal() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let relay_h_bridge = RelayHBridge::new(bench.sender(), (2, 3), 4);
+    let start = Instant::now();
+    // The relays' state is unknown before the first command
+    relay_h_bridge.actuate(HBridgeState::Pos).await.unwrap();
+    assert_eq!(start.elapsed(), RELAY_DEAD_TIME);
+    relay_h_bridge.actuate(HBridgeState::Off).await.unwrap();
+    relay_h_bridge.actuate(HBridgeState::Pos).await.unwrap();
+    relay_h_bridge.actuate(HBridgeState::Pos).await.unwrap();
+    assert_eq!(start.elapsed(), RELAY_DEAD_TIME);
+    relay_h_bridge.actuate(HBridgeState::Neg).await.unwrap();
+    assert_eq!(start.elapsed(), 2 * RELAY_DEAD_TIME);
+    assert_eq!(bench.controller().output(2), 0);
+    assert_eq!(bench.controller().output(3), 32700);
+}
+
 // #[tokio::test]
 // async fn linear_actuator_feedback_test() {
 //     let (tx, rx) = mpsc::channel::<Message>(10);
@@ -1,5 +1,12 @@
 pub mod bag_handling;
+pub mod bag_presence;
+pub mod drop_through;
 pub mod gantry;
+pub mod guard;
 pub mod hatch;
+pub mod input_watcher;
 pub mod linear_actuator;
 pub mod node;
+pub mod pneumatics;
+pub mod sequencer;
+pub mod status;
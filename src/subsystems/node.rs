@@ -1,13 +1,263 @@
-use crate::components::clear_core_motor::ClearCoreMotor;
-use crate::components::scale::Scale;
+use crate::components::clear_core_io::{HBridge, HBridgeState, Output, OutputState};
+use crate::components::clear_core_motor::{ClearCoreMotor, MotionStats};
+use crate::components::dry_cycle::DryCycle;
+use crate::components::scale::{CancelToken, Scale, ScaleInfo, WeightEstimator, WeightSource};
+use crate::components::send_recv::SendRecv;
+use crate::controllers::clear_core::Message;
+use crate::diagnostics::latency::{LatencyRecorder, LatencyStage};
+use crate::subsystems::bag_presence::BagEvent;
+use crate::subsystems::guard::{wait_for_guard_closed, GuardState};
+use crate::subsystems::status::{BusyGuard, StatusTracker, TrackedSubsystem};
+use crate::util::units::{Revolutions, RevsPerSec};
 use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use serde::Deserialize;
-use tokio::sync::mpsc::Receiver;
-use tokio::sync::oneshot;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{oneshot, watch};
 use tokio::time::{Duration, Instant};
 use crate::interface::tcp::client;
 
-#[derive(Deserialize)]
+// Used when a recipe leaves the timeout unset
+pub const DEFAULT_DISPENSE_TIMEOUT: Duration = Duration::from_secs(90);
+// Stop checks sample at this rate; 100 samples keeps the original 2 s window
+const CHECK_SAMPLE_RATE: usize = 50;
+const DEFAULT_CHECK_SAMPLES: usize = 100;
+// Settled reads before feeding starts and after a timed dispense. Weighed dispenses keep the
+// lighter sample rate they've always used
+const DEFAULT_SETTLE_TIME: Duration = Duration::from_secs(3);
+const WEIGHED_SETTLE_RATE: usize = 50;
+const TIMED_SETTLE_RATE: usize = 200;
+// Long enough that the conveyor never finishes a move before the next command replaces it
+const FEED_DISTANCE: Revolutions = Revolutions(10000.);
+// Priming normally runs alongside the initial settled read, so this matches its length
+const PRIME_TIME: Duration = Duration::from_secs(3);
+const ABORT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum FeedDirection {
+    #[default]
+    Forward,
+    Reverse,
+}
+
+impl FeedDirection {
+    fn feed(&self) -> Revolutions {
+        match self {
+            FeedDirection::Forward => FEED_DISTANCE,
+            FeedDirection::Reverse => -FEED_DISTANCE,
+        }
+    }
+
+    fn retract(&self) -> Revolutions {
+        -self.feed()
+    }
+
+    fn retract_by(&self, revs: Revolutions) -> Revolutions {
+        Revolutions(revs.0.abs() * self.retract().0.signum())
+    }
+
+    fn feed_by(&self, revs: Revolutions) -> Revolutions {
+        Revolutions(revs.0.abs() * self.feed().0.signum())
+    }
+
+    // Signed velocity for a speed in the feed direction, see `ClearCoreMotor::velocity_move`
+    fn velocity(&self, speed: RevsPerSec) -> RevsPerSec {
+        RevsPerSec(speed.0.abs() * self.feed().0.signum())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum CompletionCriteria {
+    // Stop past target - check_offset, re-check settled, finish past target - stop_offset
+    #[default]
+    ThresholdWithRecheck,
+    // Stop within tolerance grams of the target and finish once the settled read is too
+    StableWithinTolerance(f64),
+    // Stop when the current feed rate would cross target - check_offset within the lead time,
+    // covering the stop latency and product already in the air
+    PredictedCrossing(Duration),
+}
+
+impl CompletionCriteria {
+    // Weights fall as product leaves the scale, so rate is negative while feeding (g/s)
+    fn should_stop(&self, weight: f64, rate: f64, target: f64, check_offset: f64) -> bool {
+        match *self {
+            CompletionCriteria::ThresholdWithRecheck => weight < target - check_offset,
+            CompletionCriteria::StableWithinTolerance(tolerance) => weight <= target + tolerance,
+            CompletionCriteria::PredictedCrossing(lead) => {
+                weight + rate.min(0.) * lead.as_secs_f64() < target - check_offset
+            }
+        }
+    }
+
+    fn is_complete(&self, settled: f64, target: f64, stop_offset: f64) -> bool {
+        match *self {
+            CompletionCriteria::StableWithinTolerance(tolerance) => settled <= target + tolerance,
+            _ => settled <= target - stop_offset,
+        }
+    }
+}
+
+// Short fixed moves fed after a settled check comes up short, instead of going back to
+// continuous feeding for the last few grams
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct CatchUpNudge {
+    // Travel per nudge and the speed it's fed at
+    pub distance: Revolutions,
+    pub speed: RevsPerSec,
+    // Once used up the dispense goes back to continuous feeding
+    pub max_nudges: u32,
+    // Wait after each nudge before the next settled check
+    pub settle: Duration,
+}
+
+// Timed feeding at the last known flow rate once the scale stops answering, so a Phidget
+// failure doesn't stop the line outright. Bags fed this way are flagged in their report
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ScaleFallback {
+    // Cap on a blind feed, in case the flow rate on record is badly off
+    pub max_feed_time: Duration,
+}
+
+// Grams per second fed at an average motor speed in revs/s, measured over the last completed
+// weighed dispense
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct FlowRate {
+    pub grams_per_sec: f64,
+    pub motor_speed: RevsPerSec,
+}
+
+// Low speed run before the starting weight is read, for cold fats that need shear before they
+// flow consistently. Whatever it feeds isn't counted towards the serving
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct WarmUp {
+    pub speed: RevsPerSec,
+    pub duration: Duration,
+}
+
+// Speed added on top of the proportional term, in revs/s. Pure proportional control slows to
+// a crawl as the serving closes in, which drags out the end of large servings; the base part
+// scales with the remaining fraction and the constant keeps the last grams moving
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct FeedForward {
+    pub base_speed: f64,
+    pub constant: f64,
+}
+
+impl FeedForward {
+    fn speed(&self, remaining: f64) -> f64 {
+        self.base_speed * remaining.clamp(0., 1.) + self.constant
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct LowHopperPrime {
+    // Hopper weight in grams below which the prime is cut short
+    pub threshold: f64,
+    // Share of the normal prime kept below the threshold, 0 skips it
+    pub fraction: f64,
+}
+
+impl LowHopperPrime {
+    fn prime_time(&self, hopper_weight: f64) -> Duration {
+        if hopper_weight < self.threshold {
+            PRIME_TIME.mul_f64(self.fraction.clamp(0., 1.))
+        } else {
+            PRIME_TIME
+        }
+    }
+}
+
+static DISPENSE_SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+// Identifies one dispense across log lines and its report, e.g. "18f3a2b4c1d-0007"
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct DispenseId(String);
+
+impl DispenseId {
+    pub fn generate() -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        let sequence = DISPENSE_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        Self(format!("{millis:x}-{sequence:04}"))
+    }
+}
+
+impl fmt::Display for DispenseId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Controller internals for one scale sample, recorded for offline filter and gain tuning.
+// `error` and `speed` are only set on samples where a motor update was due, `speed` only when
+// a new velocity was actually sent
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DispenseSample {
+    pub time: Duration,
+    pub raw: f64,
+    pub filtered: f64,
+    // g/s, negative while feeding
+    pub feed_rate: f64,
+    pub error: Option<f64>,
+    pub speed: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+pub struct DispenseReport {
+    pub id: DispenseId,
+    pub times: Vec<Duration>,
+    pub weights: Vec<f64>,
+    pub dispensed: f64,
+    pub timeout: Duration,
+    pub timed_out: bool,
+    pub aborted: bool,
+    // Catch-up nudges fed after short settled checks
+    pub nudges: u32,
+    pub latency: LatencyRecorder,
+    // Finished by timed feeding after the scale failed, `dispensed` is an estimate
+    pub scale_fallback: bool,
+    // Empty unless the parameters asked for a trace
+    pub trace: Vec<DispenseSample>,
+    // The source's details for traceability, whatever the outcome
+    pub scale: Option<ScaleInfo>,
+}
+
+impl DispenseReport {
+    fn aborted(id: DispenseId, timeout: Duration) -> Self {
+        Self {
+            id,
+            timeout,
+            aborted: true,
+            ..Default::default()
+        }
+    }
+
+    // One line per traced sample, blank where a value wasn't computed
+    pub fn trace_csv(&self) -> String {
+        let optional = |value: Option<f64>| value.map_or(String::new(), |value| value.to_string());
+        let mut csv = String::from("time_s,raw_g,filtered_g,feed_rate_gps,error,speed_rps\n");
+        for sample in &self.trace {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                sample.time.as_secs_f64(),
+                sample.raw,
+                sample.filtered,
+                sample.feed_rate,
+                optional(sample.error),
+                optional(sample.speed)
+            ));
+        }
+        csv
+    }
+}
+
+#[derive(Clone, Deserialize)]
 pub struct DispensingParameters {
     serving_weight: Option<f64>,
     timeout: Option<Duration>,
@@ -16,8 +266,129 @@ pub struct DispensingParameters {
     cutoff_frequency: f64,
     check_offset: f64,
     stop_offset: f64,
+    // Scale samples taken this soon after the conveyor starts or stops are left out of the
+    // filter and check medians
+    #[serde(default)]
+    vibration_blanking: Option<Duration>,
+    // Estimator for the settled reads before, during and after a dispense
+    #[serde(default)]
+    check_estimator: WeightEstimator,
+    // Samples in each stop check read, and how long to let the product settle before it
+    #[serde(default)]
+    check_samples: Option<usize>,
+    #[serde(default)]
+    settle_delay: Option<Duration>,
+    // Length and sample rate of the settled reads taken before feeding and after a timed
+    // dispense
+    #[serde(default)]
+    settle_time: Option<Duration>,
+    #[serde(default)]
+    settle_sample_rate: Option<usize>,
+    #[serde(default)]
+    feed_direction: FeedDirection,
+    // Revs moved against the feed direction before feeding starts and after it stops, so
+    // product drawn back from the outlet doesn't drip into the container
+    #[serde(default)]
+    retract_before: Option<Revolutions>,
+    #[serde(default)]
+    retract_after: Option<Revolutions>,
+    #[serde(default)]
+    completion: CompletionCriteria,
+    // Reads the hopper before priming instead of during it, so a near-empty hopper can skip
+    // or shorten the prime rather than sling its last grams onto the floor
+    #[serde(default)]
+    low_hopper_prime: Option<LowHopperPrime>,
+    #[serde(default)]
+    catch_up_nudge: Option<CatchUpNudge>,
+    #[serde(default)]
+    warm_up: Option<WarmUp>,
+    #[serde(default)]
+    feed_forward: Option<FeedForward>,
+    // Reads each speed the controller commands back from the drive and fails the dispense if
+    // it didn't take, at the cost of an extra round trip per adjustment
+    #[serde(default)]
+    speed_readback: bool,
+    // Records every sample's filter and controller values into the report, for tuning
+    #[serde(default)]
+    trace: bool,
 }
 impl DispensingParameters {
+    pub fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or(DEFAULT_DISPENSE_TIMEOUT)
+    }
+    pub fn with_vibration_blanking(mut self, blanking: Duration) -> Self {
+        self.vibration_blanking = Some(blanking);
+        self
+    }
+    pub fn with_check_estimator(mut self, estimator: WeightEstimator) -> Self {
+        self.check_estimator = estimator;
+        self
+    }
+    pub fn with_check_window(mut self, check_samples: usize, settle_delay: Duration) -> Self {
+        self.check_samples = Some(check_samples);
+        self.settle_delay = Some(settle_delay);
+        self
+    }
+    pub fn with_settle_window(mut self, settle_time: Duration, sample_rate: usize) -> Self {
+        self.settle_time = Some(settle_time);
+        self.settle_sample_rate = Some(sample_rate);
+        self
+    }
+    pub fn with_feed_direction(mut self, feed_direction: FeedDirection) -> Self {
+        self.feed_direction = feed_direction;
+        self
+    }
+    pub fn with_retracts(
+        mut self,
+        before: Option<Revolutions>,
+        after: Option<Revolutions>,
+    ) -> Self {
+        self.retract_before = before;
+        self.retract_after = after;
+        self
+    }
+    pub fn with_completion(mut self, completion: CompletionCriteria) -> Self {
+        self.completion = completion;
+        self
+    }
+    pub fn with_low_hopper_prime(mut self, low_hopper_prime: LowHopperPrime) -> Self {
+        self.low_hopper_prime = Some(low_hopper_prime);
+        self
+    }
+    pub fn with_catch_up_nudge(mut self, catch_up_nudge: CatchUpNudge) -> Self {
+        self.catch_up_nudge = Some(catch_up_nudge);
+        self
+    }
+    pub fn with_warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+    pub fn with_feed_forward(mut self, feed_forward: FeedForward) -> Self {
+        self.feed_forward = Some(feed_forward);
+        self
+    }
+    pub fn with_speed_readback(mut self) -> Self {
+        self.speed_readback = true;
+        self
+    }
+    pub fn with_trace(mut self) -> Self {
+        self.trace = true;
+        self
+    }
+    fn check_window(&self) -> Duration {
+        let samples = self.check_samples.unwrap_or(DEFAULT_CHECK_SAMPLES).max(1);
+        Duration::from_secs_f64(samples as f64 / CHECK_SAMPLE_RATE as f64)
+    }
+    // Motor speed for the remaining fraction of the serving
+    fn feed_speed(&self, remaining: f64) -> f64 {
+        let feed_forward = self.feed_forward.map_or(0., |ff| ff.speed(remaining));
+        remaining * self.motor_speed + feed_forward
+    }
+    fn settle_window(&self, default_rate: usize) -> (Duration, usize) {
+        let time = self.settle_time.unwrap_or(DEFAULT_SETTLE_TIME);
+        (time, self.settle_sample_rate.unwrap_or(default_rate).max(1))
+    }
+
     pub fn with_weight(
         serving_weight: f64,
         timeout: Duration,
@@ -35,6 +406,22 @@ impl DispensingParameters {
             cutoff_frequency,
             check_offset,
             stop_offset,
+            vibration_blanking: None,
+            check_estimator: WeightEstimator::Median,
+            check_samples: None,
+            settle_delay: None,
+            settle_time: None,
+            settle_sample_rate: None,
+            feed_direction: FeedDirection::Forward,
+            retract_before: None,
+            retract_after: None,
+            completion: CompletionCriteria::ThresholdWithRecheck,
+            low_hopper_prime: None,
+            catch_up_nudge: None,
+            warm_up: None,
+            feed_forward: None,
+            speed_readback: false,
+            trace: false,
         }
     }
     pub fn only_timeout(
@@ -53,17 +440,311 @@ impl DispensingParameters {
             cutoff_frequency,
             check_offset,
             stop_offset,
+            vibration_blanking: None,
+            check_estimator: WeightEstimator::Median,
+            check_samples: None,
+            settle_delay: None,
+            settle_time: None,
+            settle_sample_rate: None,
+            feed_direction: FeedDirection::Forward,
+            retract_before: None,
+            retract_after: None,
+            completion: CompletionCriteria::ThresholdWithRecheck,
+            low_hopper_prime: None,
+            catch_up_nudge: None,
+            warm_up: None,
+            feed_forward: None,
+            speed_readback: false,
+            trace: false,
+        }
+    }
+}
+
+pub enum AgitatorOutput {
+    Digital(Output),
+    HBridge(HBridge, HBridgeState),
+}
+
+pub struct Agitator {
+    output: AgitatorOutput,
+    on_time: Duration,
+    // Zero keeps the agitator on for the whole dispense
+    off_time: Duration,
+}
+
+impl Agitator {
+    pub fn new(output: AgitatorOutput, on_time: Duration, off_time: Duration) -> Self {
+        Self {
+            output,
+            on_time,
+            off_time,
+        }
+    }
+
+    async fn set(&self, on: bool) -> Result<(), Box<dyn Error>> {
+        match &self.output {
+            AgitatorOutput::Digital(output) => {
+                let state = if on {
+                    OutputState::On
+                } else {
+                    OutputState::Off
+                };
+                output.set_state(state).await?;
+            }
+            AgitatorOutput::HBridge(h_bridge, direction) => {
+                let state = match (on, direction) {
+                    (false, _) => HBridgeState::Off,
+                    (true, HBridgeState::Pos) => HBridgeState::Pos,
+                    (true, HBridgeState::Neg) => HBridgeState::Neg,
+                    (true, HBridgeState::Off) => HBridgeState::Off,
+                };
+                h_bridge.set_state(state).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn off_on_drop(&self) -> AgitatorOff {
+        let (sender, buffer) = match &self.output {
+            AgitatorOutput::Digital(output) => (output.get_sender(), output.off_command()),
+            AgitatorOutput::HBridge(h_bridge, _) => (h_bridge.get_sender(), h_bridge.off_command()),
+        };
+        AgitatorOff {
+            sender: sender.clone(),
+            buffer: Some(buffer),
+        }
+    }
+
+    async fn run(&self) -> Result<(), Box<dyn Error>> {
+        loop {
+            self.set(true).await?;
+            tokio::time::sleep(self.on_time).await;
+            if self.off_time > Duration::ZERO {
+                self.set(false).await?;
+                tokio::time::sleep(self.off_time).await;
+            }
         }
     }
 }
 
+// Switches the agitator off when dropped, so a dispense that panics doesn't leave it running.
+// Drop can't await, so the command goes out from a task of its own
+struct AgitatorOff {
+    sender: Sender<Message>,
+    // Taken once the agitator has been switched off normally
+    buffer: Option<Vec<u8>>,
+}
+
+impl AgitatorOff {
+    fn disarm(&mut self) {
+        self.buffer = None;
+    }
+}
+
+impl Drop for AgitatorOff {
+    fn drop(&mut self) {
+        let Some(buffer) = self.buffer.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            println!("WARNING: No runtime left to stop the agitator");
+            return;
+        };
+        let sender = self.sender.clone();
+        runtime.spawn(async move {
+            let (response, reply) = oneshot::channel();
+            if sender.send(Message { buffer, response }).await.is_ok() {
+                let _ = reply.await;
+            }
+        });
+    }
+}
+
 pub struct Node {
     motor: ClearCoreMotor,
+    guard: Option<watch::Receiver<GuardState>>,
+    pause: Option<watch::Receiver<bool>>,
+    agitator: Option<Agitator>,
+    status: StatusTracker,
+    abort: Option<CancelToken>,
+    scale_fallback: Option<ScaleFallback>,
+    flow_rate: Mutex<Option<FlowRate>>,
+    dry_cycle: Option<DryCycle>,
 }
 
 impl Node {
     pub fn new(motor: ClearCoreMotor) -> Self {
-        Self { motor }
+        Self {
+            motor,
+            guard: None,
+            pause: None,
+            agitator: None,
+            status: StatusTracker::new("node"),
+            abort: None,
+            scale_fallback: None,
+            flow_rate: Mutex::new(None),
+            dry_cycle: None,
+        }
+    }
+
+    pub fn with_scale_fallback(mut self, fallback: ScaleFallback) -> Self {
+        self.scale_fallback = Some(fallback);
+        self
+    }
+
+    // Seeds the fallback flow rate, e.g. from the previous shift, until a dispense measures one
+    pub fn with_flow_rate(self, flow_rate: FlowRate) -> Self {
+        *self.flow_rate.lock().unwrap() = Some(flow_rate);
+        self
+    }
+
+    pub fn flow_rate(&self) -> Option<FlowRate> {
+        *self.flow_rate.lock().unwrap()
+    }
+
+    // While the dry cycle runs the motor is slowed down and weighed dispenses run as timed
+    // ones, since there's no product to reach the serving weight with
+    pub fn with_dry_cycle(mut self, dry_cycle: DryCycle) -> Self {
+        self.motor = self.motor.with_dry_cycle(dry_cycle.clone());
+        self.dry_cycle = Some(dry_cycle);
+        self
+    }
+
+    // Cancelling the token stops the running dispense, including mid-way through its settled
+    // reads. Reset it before the next dispense
+    pub fn with_abort(mut self, abort: CancelToken) -> Self {
+        self.abort = Some(abort);
+        self
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.abort.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    // Pass the same tracker from a supervisor's make_node so restarts keep reporting into it
+    pub fn with_status(mut self, status: StatusTracker) -> Self {
+        self.status = status;
+        self
+    }
+
+    // The node usually moves into its actor task, so the status page keeps this clone instead
+    pub fn status(&self) -> StatusTracker {
+        self.status.clone()
+    }
+
+    fn finish(&self, busy: BusyGuard, id: &DispenseId, report: &DispenseReport) {
+        if report.scale_fallback {
+            busy.warn(&format!("Dispense {id} fed by time, scale offline"));
+        }
+        if report.aborted {
+            busy.fault(&format!("Dispense {id} aborted"));
+        } else if report.timed_out {
+            busy.fault(&format!("Dispense {id} timed out"));
+        } else {
+            busy.idle();
+        }
+    }
+
+    pub fn with_agitator(mut self, agitator: Agitator) -> Self {
+        self.agitator = Some(agitator);
+        self
+    }
+
+    async fn agitated<T>(&self, id: &DispenseId, dispense: impl Future<Output = T>) -> T {
+        // Runs the agitator duty cycle alongside a dispense and switches it off afterwards, from
+        // the drop guard if the dispense panics
+        let Some(agitator) = &self.agitator else {
+            return dispense.await;
+        };
+        let mut off = agitator.off_on_drop();
+        let mut dispense = std::pin::pin!(dispense);
+        let outcome = tokio::select! {
+            result = &mut dispense => Ok(result),
+            Err(e) = self.run_agitator(agitator) => Err(e.to_string()),
+        };
+        let result = match outcome {
+            Ok(result) => result,
+            Err(e) => {
+                println!("[{id}] WARNING: Agitator failed: {e}");
+                dispense.await
+            }
+        };
+        if let Err(e) = agitator.set(false).await {
+            println!("[{id}] WARNING: Failed to stop agitator: {e}");
+        }
+        off.disarm();
+        result
+    }
+
+    // The duty cycle pauses with the dispense: the agitator is switched off for as long as a
+    // guard is open or a pause was requested, and restarts once the hold ends
+    async fn run_agitator(&self, agitator: &Agitator) -> Result<(), Box<dyn Error>> {
+        loop {
+            self.wait_for_hold(false).await;
+            tokio::select! {
+                Err(e) = agitator.run() => return Err(e),
+                _ = self.wait_for_hold(true) => {}
+            }
+            agitator.set(false).await?;
+        }
+    }
+
+    pub fn with_guard(mut self, guard: watch::Receiver<GuardState>) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    pub fn with_pause(mut self, pause: watch::Receiver<bool>) -> Self {
+        self.pause = Some(pause);
+        self
+    }
+
+    fn is_held(&self) -> bool {
+        let guard_open = self
+            .guard
+            .as_ref()
+            .is_some_and(|guard| *guard.borrow() == GuardState::Open);
+        let paused = self.pause.as_ref().is_some_and(|pause| *pause.borrow());
+        guard_open || paused
+    }
+
+    async fn wait_for_hold(&self, held: bool) {
+        let mut guard = self.guard.clone();
+        let mut pause = self.pause.clone();
+        while self.is_held() != held {
+            tokio::select! {
+                _ = changed(&mut guard) => {}
+                _ = changed(&mut pause) => {}
+            }
+        }
+    }
+
+    async fn hold_while_paused(&self, id: &DispenseId) -> Duration {
+        // Stops the conveyor while a guard is open or a pause was requested and returns how long
+        // the dispense was held, so callers can restart the motor and keep their timeouts honest.
+        // Filter and tracking state live in the caller's loop and carry over untouched.
+        if !self.is_held() {
+            return Duration::ZERO;
+        }
+        let held_at = Instant::now();
+        self.motor.abrupt_stop().await.expect("Failed to stop");
+        println!("[{id}] WARNING: Dispense paused");
+        while self.is_held() {
+            if let Some(guard) = &self.guard {
+                wait_for_guard_closed(&mut guard.clone())
+                    .await
+                    .expect("Guard monitor dropped");
+            }
+            if let Some(pause) = &self.pause {
+                pause
+                    .clone()
+                    .wait_for(|paused| !*paused)
+                    .await
+                    .expect("Pause control dropped");
+            }
+        }
+        println!("[{id}] Dispense resumed");
+        Instant::now() - held_at
     }
 
     pub async fn connect_scale(&self, scale: Scale) -> Scale {
@@ -72,41 +753,249 @@ impl Node {
             .unwrap()
     }
 
+    async fn prime(&self, parameters: &DispensingParameters) {
+        // Prime conveyor by backing it off against the feed direction, it runs until the
+        // feed move replaces it
+        let speed = 2. * parameters.motor_speed;
+        let velocity = parameters.feed_direction.velocity(RevsPerSec(speed));
+        self.motor.velocity_move(-velocity).await.unwrap();
+    }
+
+    // Ends early on an abort, leaving the dispense to notice it on the starting read
+    async fn warm_up(&self, id: &DispenseId, parameters: &DispensingParameters) {
+        let Some(warm_up) = parameters.warm_up else {
+            return;
+        };
+        println!("[{id}] Warming up for {:?}", warm_up.duration);
+        self.motor
+            .velocity_move(parameters.feed_direction.velocity(warm_up.speed))
+            .await
+            .expect("Failed to warm up");
+        self.sleep_unless_aborted(warm_up.duration).await;
+        self.motor.stop().await.expect("Failed to stop");
+        self.wait_for_motor("Failed to warm up").await;
+    }
+
+    async fn sleep_unless_aborted(&self, duration: Duration) {
+        let done = Instant::now() + duration;
+        while Instant::now() < done && !self.is_aborted() {
+            tokio::time::sleep_until(done.min(Instant::now() + ABORT_POLL)).await;
+        }
+    }
+
+    // Feeds what's left of the serving by time after a scale failure and returns the estimated
+    // total dispensed. Without a fallback and a flow rate on record the failure is fatal
+    async fn fall_back(
+        &self,
+        id: &DispenseId,
+        parameters: &DispensingParameters,
+        dispensed: f64,
+        error: String,
+    ) -> f64 {
+        let (Some(fallback), Some(flow)) = (self.scale_fallback, self.flow_rate()) else {
+            panic!("Failed to weigh scale: {error}");
+        };
+        let remaining = (parameters.serving_weight.unwrap_or(0.) - dispensed).max(0.);
+        let feed_time =
+            Duration::from_secs_f64(remaining / flow.grams_per_sec).min(fallback.max_feed_time);
+        println!(
+            "[{id}] ALARM: Scale failed ({error}), feeding {remaining:.1} g by time over {feed_time:.1?}"
+        );
+        self.motor
+            .velocity_move(parameters.feed_direction.velocity(flow.motor_speed))
+            .await
+            .expect("Failed to send move command");
+        let start = Instant::now();
+        self.sleep_unless_aborted(feed_time).await;
+        self.motor.abrupt_stop().await.expect("Failed to stop");
+        dispensed + flow.grams_per_sec * (Instant::now() - start).as_secs_f64()
+    }
+
+    async fn record_flow_rate(
+        &self,
+        dispensed: f64,
+        elapsed: Duration,
+        start_position: Revolutions,
+    ) {
+        let Ok(end_position) = self.motor.get_position().await else {
+            return;
+        };
+        let secs = elapsed.as_secs_f64();
+        let motor_speed = RevsPerSec((end_position - start_position).0.abs() / secs);
+        let grams_per_sec = dispensed / secs;
+        if grams_per_sec.is_finite() && grams_per_sec > 0. && motor_speed.0.is_finite() {
+            *self.flow_rate.lock().unwrap() = Some(FlowRate {
+                grams_per_sec,
+                motor_speed,
+            });
+        }
+    }
+
+    async fn retract(&self, parameters: &DispensingParameters, revs: Option<Revolutions>) {
+        let Some(revs) = revs else {
+            return;
+        };
+        self.motor
+            .relative_move(parameters.feed_direction.retract_by(revs))
+            .await
+            .expect("Failed to retract");
+        self.wait_for_motor("Failed to retract").await;
+    }
+
+    // An abort stops the motor mid-move; the dispense loop notices the abort on its next check
+    async fn wait_for_motor(&self, context: &str) {
+        let result = self
+            .motor
+            .wait_for_move_until(Duration::from_millis(50), None, self.abort.as_ref())
+            .await;
+        if let Err(e) = result {
+            if !self.is_aborted() {
+                panic!("{context}: {e}");
+            }
+        }
+    }
+
+    async fn nudge(&self, parameters: &DispensingParameters, nudge: &CatchUpNudge) {
+        self.motor
+            .relative_move_with_velocity(
+                parameters.feed_direction.feed_by(nudge.distance),
+                nudge.speed,
+            )
+            .await
+            .expect("Failed to nudge");
+        self.wait_for_motor("Failed to nudge").await;
+        tokio::time::sleep(nudge.settle).await;
+    }
+
     pub async fn read_scale(&self, scale: Scale) -> (Scale, f64) {
+        self.weigh(scale).await
+    }
+
+    async fn weigh<S: WeightSource>(&self, source: S) -> (S, f64) {
+        let (source, weight) = self.try_weigh(source).await;
+        (source, weight.expect("Scale failed to weigh"))
+    }
+
+    async fn try_weigh<S: WeightSource>(&self, mut source: S) -> (S, Result<f64, String>) {
+        tokio::task::spawn_blocking(move || {
+            let weight = source.live_weight().map_err(|e| e.to_string());
+            (source, weight)
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn read_raw_readings(&self, scale: Scale) -> (Scale, Vec<f64>) {
+        // Per-cell voltage ratios before the calibration coefficients are applied
+        tokio::task::spawn_blocking(move || {
+            Scale::get_readings(scale).expect("Failed to read load cells")
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn read_scale_median<S: WeightSource>(
+        &self,
+        mut scale: S,
+        time: Duration,
+        sample_rate: usize,
+    ) -> (S, f64) {
         tokio::task::spawn_blocking(move || {
-            Scale::live_weigh(scale).expect("Scale failed to weigh")
+            let weight = scale
+                .settled_weight(
+                    time,
+                    sample_rate,
+                    WeightEstimator::Median,
+                    &CancelToken::new(),
+                )
+                .expect("Failed to weigh scale")
+                .expect("Scale read cancelled");
+            (scale, weight)
         })
         .await
         .unwrap()
     }
 
-    pub async fn read_scale_median(
+    // Settled read that gives up early, returning no weight, once the node is aborted
+    async fn read_settled<S: WeightSource>(
+        &self,
+        source: S,
+        time: Duration,
+        sample_rate: usize,
+        estimator: WeightEstimator,
+    ) -> (S, Option<f64>) {
+        let (source, weight) = self
+            .try_read_settled(source, time, sample_rate, estimator)
+            .await;
+        (source, weight.expect("Failed to weigh scale"))
+    }
+
+    async fn try_read_settled<S: WeightSource>(
+        &self,
+        mut source: S,
+        time: Duration,
+        sample_rate: usize,
+        estimator: WeightEstimator,
+    ) -> (S, Result<Option<f64>, String>) {
+        let abort = self.abort.clone().unwrap_or_default();
+        tokio::task::spawn_blocking(move || {
+            let weight = source
+                .settled_weight(time, sample_rate, estimator, &abort)
+                .map_err(|e| e.to_string());
+            (source, weight)
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn read_scale_estimate(
         &self,
         scale: Scale,
         time: Duration,
         sample_rate: usize,
+        estimator: WeightEstimator,
     ) -> (Scale, f64) {
         tokio::task::spawn_blocking(move || {
-            Scale::weight_by_median(scale, time, sample_rate).expect("Failed to weigh scale")
+            Scale::weight_by_estimator(scale, time, sample_rate, estimator)
+                .expect("Failed to weigh scale")
         })
         .await
         .unwrap()
     }
 
-    pub async fn dispense(
+    pub async fn dispense<S: WeightSource>(
         &self,
-        scale: Scale,
+        scale: S,
+        parameters: DispensingParameters,
+    ) -> (S, DispenseReport) {
+        if self.dry_cycle.as_ref().is_some_and(DryCycle::is_running) {
+            println!("Dry cycle running, dispensing by time");
+            return self.timed_dispense(scale, parameters).await;
+        }
+        let id = DispenseId::generate();
+        println!("[{id}] Starting weighed dispense");
+        let busy = self.status.busy();
+        let (scale, mut report) = self
+            .agitated(&id, self.run_dispense(id.clone(), scale, parameters))
+            .await;
+        report.scale = scale.info();
+        self.finish(busy, &id, &report);
+        (scale, report)
+    }
+
+    async fn run_dispense<S: WeightSource>(
+        &self,
+        id: DispenseId,
+        scale: S,
         parameters: DispensingParameters, // serving: f64,
                                           // sample_rate: f64,
                                           // cutoff_frequency: f64,
                                           // motor_speed: f64,
-    ) -> (Scale, Vec<Duration>, Vec<f64>) {
-        // Prime conveyor
-        self.motor
-            .set_velocity(2. * parameters.motor_speed)
-            .await
-            .unwrap();
-        self.motor.relative_move(-10000.).await.unwrap();
+    ) -> (S, DispenseReport) {
+        self.warm_up(&id, &parameters).await;
+        if parameters.low_hopper_prime.is_none() {
+            self.prime(&parameters).await;
+        }
 
         // Set LP filter values
         let filter_period = 1. / parameters.sample_rate;
@@ -115,154 +1004,409 @@ impl Node {
         let filter_b = filter_rc / (filter_period + filter_rc);
 
         // Initialize dispense tracking variables
-        let init_time = Instant::now();
+        let mut init_time = Instant::now();
         let mut last_sent_motor = Instant::now();
 
+        let (settle_time, settle_rate) = parameters.settle_window(WEIGHED_SETTLE_RATE);
         let (mut scale, init_weight) = self
-            .read_scale_median(scale, Duration::from_secs(3), 50)
+            .try_read_settled(scale, settle_time, settle_rate, parameters.check_estimator)
             .await;
+        let init_weight = match init_weight {
+            Ok(Some(init_weight)) => init_weight,
+            Ok(None) => {
+                self.motor.abrupt_stop().await.expect("Failed to stop");
+                println!("[{id}] Dispense aborted before feeding");
+                return (scale, DispenseReport::aborted(id, parameters.timeout()));
+            }
+            Err(e) => {
+                let dispensed = self.fall_back(&id, &parameters, 0., e).await;
+                let report = DispenseReport {
+                    id,
+                    dispensed,
+                    timeout: parameters.timeout(),
+                    aborted: self.is_aborted(),
+                    scale_fallback: true,
+                    ..Default::default()
+                };
+                return (scale, report);
+            }
+        };
+        if let Some(low_hopper) = parameters.low_hopper_prime {
+            let prime_time = low_hopper.prime_time(init_weight);
+            if prime_time.is_zero() {
+                println!("[{id}] Hopper at {init_weight:.1} g, skipping prime");
+            } else {
+                self.prime(&parameters).await;
+                tokio::time::sleep(prime_time).await;
+            }
+        }
 
         let mut curr_weight = init_weight;
         let target_weight = init_weight - parameters.serving_weight.unwrap();
         let mut reading: f64;
         let mut final_weight: f64;
 
-        let timeout = Duration::from_secs(90);
+        let timeout = parameters.timeout();
+        let mut timed_out = false;
+        let mut aborted = false;
         let send_command_delay = Duration::from_millis(500);
 
         let mut times: Vec<Duration> = Vec::new();
         let mut weights: Vec<f64> = Vec::new();
+        let mut latency = LatencyRecorder::new();
+        let mut trace = Vec::new();
 
+        self.retract(&parameters, parameters.retract_before).await;
+        // The conveyor runs continuously at `speed`; `running` is false while it is stopped
+        let mut speed = parameters.motor_speed;
         self.motor
-            .set_velocity(parameters.motor_speed)
-            .await
-            .expect("Failed to change velocity");
-        self.motor
-            .relative_move(10000.)
+            .velocity_move(parameters.feed_direction.velocity(RevsPerSec(speed)))
             .await
             .expect("Failed to send move command");
+        let mut running = true;
+        let mut feed_started = Instant::now();
+        let start_position = self.motor.get_position().await.ok();
+        let mut last_motor_event = Instant::now();
+        let mut motor_stopped = false;
+        let mut scale_fallback = false;
+        let mut nudges = 0;
+        let mut feed_rate = 0.;
+        let mut last_filtered = Instant::now();
         let (scale, dispensed) = loop {
-            if curr_weight < target_weight - parameters.check_offset {
+            let held = self.hold_while_paused(&id).await;
+            if held > Duration::ZERO {
+                init_time += held;
+                feed_started += held;
+                self.motor
+                    .velocity_move(parameters.feed_direction.velocity(RevsPerSec(speed)))
+                    .await
+                    .expect("Failed to resume");
+                last_motor_event = Instant::now();
+            }
+            let stop = parameters.completion.should_stop(
+                curr_weight,
+                feed_rate,
+                target_weight,
+                parameters.check_offset,
+            );
+            if stop {
                 self.motor.abrupt_stop().await.expect("Failed to stop");
-                (scale, final_weight) = self
-                    .read_scale_median(scale, Duration::from_secs(2), 50)
+                last_motor_event = Instant::now();
+                motor_stopped = true;
+                running = false;
+                if let Some(settle_delay) = parameters.settle_delay {
+                    tokio::time::sleep(settle_delay).await;
+                }
+                let settled;
+                (scale, settled) = self
+                    .try_read_settled(
+                        scale,
+                        parameters.check_window(),
+                        CHECK_SAMPLE_RATE,
+                        parameters.check_estimator,
+                    )
                     .await;
-                if final_weight <= target_weight - parameters.stop_offset {
+                let settled = match settled {
+                    Ok(settled) => settled,
+                    Err(e) => {
+                        let dispensed = init_weight - curr_weight;
+                        scale_fallback = true;
+                        break (scale, self.fall_back(&id, &parameters, dispensed, e).await);
+                    }
+                };
+                let Some(settled) = settled else {
+                    println!("[{id}] Dispense aborted");
+                    aborted = true;
+                    break (scale, init_weight - curr_weight);
+                };
+                final_weight = settled;
+                let complete = parameters.completion.is_complete(
+                    final_weight,
+                    target_weight,
+                    parameters.stop_offset,
+                );
+                if complete {
                     break (scale, init_weight - final_weight);
                 }
+                if let Some(nudge) = parameters.catch_up_nudge {
+                    if nudges < nudge.max_nudges {
+                        nudges += 1;
+                        self.nudge(&parameters, &nudge).await;
+                        last_motor_event = Instant::now();
+                        // Straight back to the check, the filtered weight is still past the stop
+                        continue;
+                    }
+                }
+            }
+            if self.is_aborted() {
+                self.motor.abrupt_stop().await.expect("Failed to stop");
+                println!("[{id}] Dispense aborted");
+                aborted = true;
+                break (scale, init_weight - curr_weight);
             }
             let curr_time = Instant::now();
             if curr_time - init_time > timeout {
                 // TODO: maybe violently run in reverse for a couple seconds and let it keep running?
                 self.motor.abrupt_stop().await.expect("Failed to stop");
-                println!("WARNING: Dispense timed out!");
+                println!("[{id}] WARNING: Dispense timed out!");
+                timed_out = true;
                 break (scale, init_weight - curr_weight);
             }
-            (scale, reading) = self.read_scale(scale).await;
-            curr_weight = filter_a * reading + filter_b * curr_weight;
+            let sample_start = Instant::now();
+            let weighed;
+            (scale, weighed) = self.try_weigh(scale).await;
+            reading = match weighed {
+                Ok(reading) => reading,
+                Err(e) => {
+                    let dispensed = init_weight - curr_weight;
+                    scale_fallback = true;
+                    break (scale, self.fall_back(&id, &parameters, dispensed, e).await);
+                }
+            };
+            let sampled_at = latency.lap(LatencyStage::ScaleSample, sample_start);
+            let suspect = parameters
+                .vibration_blanking
+                .is_some_and(|blanking| curr_time - last_motor_event < blanking);
+            if !suspect {
+                let previous = curr_weight;
+                curr_weight = filter_a * reading + filter_b * curr_weight;
+                let elapsed = (sampled_at - last_filtered).as_secs_f64();
+                if elapsed > 0. {
+                    feed_rate =
+                        filter_a * (curr_weight - previous) / elapsed + filter_b * feed_rate;
+                }
+                last_filtered = sampled_at;
+            }
+            let filtered_at = latency.lap(LatencyStage::Filter, sampled_at);
 
             times.push(curr_time - init_time);
             weights.push(reading);
+            let mut sample = DispenseSample {
+                time: curr_time - init_time,
+                raw: reading,
+                filtered: curr_weight,
+                feed_rate,
+                ..Default::default()
+            };
 
             if curr_time - last_sent_motor > send_command_delay {
                 last_sent_motor = Instant::now();
+                if motor_stopped {
+                    last_motor_event = Instant::now();
+                    motor_stopped = false;
+                }
                 let err = (curr_weight - target_weight) / parameters.serving_weight.unwrap();
-                let new_motor_speed = err * parameters.motor_speed;
-                if new_motor_speed >= 0.1 {
-                    self.motor
-                        .set_velocity(new_motor_speed)
-                        .await
-                        .expect("Failed to change speed");
+                let new_motor_speed = parameters.feed_speed(err);
+                let decided_at = latency.lap(LatencyStage::Decision, filtered_at);
+                sample.error = Some(err);
+                if new_motor_speed >= 0.1 && new_motor_speed != speed {
+                    speed = new_motor_speed;
+                    running = false;
+                    sample.speed = Some(new_motor_speed);
                 }
-                self.motor
-                    .relative_move(10000.0)
-                    .await
-                    .expect("Failed to update");
+                // Only a new speed, or a restart after a check, needs a command
+                if !running {
+                    let velocity = parameters.feed_direction.velocity(RevsPerSec(speed));
+                    let set = match parameters.speed_readback {
+                        true => self.motor.velocity_move_verified(velocity).await,
+                        false => self.motor.velocity_move(velocity).await,
+                    };
+                    set.expect("Failed to change speed");
+                    running = true;
+                }
+                latency.lap(LatencyStage::MotorCommand, decided_at);
+                latency.lap(LatencyStage::EndToEnd, sample_start);
+            }
+            if parameters.trace {
+                trace.push(sample);
             }
         };
-        println!("Dispensed: {:.1} g", dispensed);
-        (scale, times, weights)
+        let fed_for = Instant::now() - feed_started;
+        if let (false, false, false, Some(start_position)) =
+            (timed_out, aborted, scale_fallback, start_position)
+        {
+            self.record_flow_rate(dispensed, fed_for, start_position)
+                .await;
+        }
+        self.retract(&parameters, parameters.retract_after).await;
+        println!("[{id}] Dispensed: {:.1} g", dispensed);
+        let report = DispenseReport {
+            id,
+            times,
+            weights,
+            dispensed,
+            timeout,
+            timed_out,
+            aborted: aborted || (scale_fallback && self.is_aborted()),
+            nudges,
+            latency,
+            scale_fallback,
+            trace,
+            ..Default::default()
+        };
+        (scale, report)
     }
     //
-    pub async fn timed_dispense(&self, scale: Scale, parameters: DispensingParameters) -> Scale {
+    pub async fn timed_dispense<S: WeightSource>(
+        &self,
+        scale: S,
+        parameters: DispensingParameters,
+    ) -> (S, DispenseReport) {
+        let id = DispenseId::generate();
+        println!("[{id}] Starting timed dispense");
+        let busy = self.status.busy();
+        let (scale, mut report) = self
+            .agitated(&id, self.run_timed_dispense(id.clone(), scale, parameters))
+            .await;
+        report.scale = scale.info();
+        self.finish(busy, &id, &report);
+        (scale, report)
+    }
+
+    async fn run_timed_dispense<S: WeightSource>(
+        &self,
+        id: DispenseId,
+        scale: S,
+        parameters: DispensingParameters,
+    ) -> (S, DispenseReport) {
         // Set LP filter values
         let filter_period = 1. / parameters.sample_rate;
         let filter_rc = 1. / (parameters.cutoff_frequency * 2. * std::f64::consts::PI);
         let filter_a = filter_period / (filter_period + filter_rc);
         let filter_b = filter_rc / (filter_period + filter_rc);
 
-        // Initialize dispense tracking variables
-        let init_time = Instant::now();
-        let mut last_sent_motor = Instant::now();
-
+        self.warm_up(&id, &parameters).await;
+        let (settle_time, settle_rate) = parameters.settle_window(TIMED_SETTLE_RATE);
         let (mut scale, init_weight) = self
-            .read_scale_median(scale, Duration::from_secs(3), 200)
+            .read_settled(scale, settle_time, settle_rate, parameters.check_estimator)
             .await;
+        let Some(init_weight) = init_weight else {
+            println!("[{id}] Dispense aborted before feeding");
+            return (scale, DispenseReport::aborted(id, parameters.timeout()));
+        };
+
+        // Initialize dispense tracking variables, the feed time starts after the warm-up
+        let mut init_time = Instant::now();
 
         let mut curr_weight = init_weight;
         let mut reading: f64;
-        let send_command_delay = Duration::from_millis(250);
 
         // Data tracking
         let mut times = Vec::new();
         let mut weights = Vec::new();
+        self.retract(&parameters, parameters.retract_before).await;
+        let velocity = parameters
+            .feed_direction
+            .velocity(RevsPerSec(parameters.motor_speed));
         self.motor
-            .set_velocity(parameters.motor_speed)
-            .await
-            .expect("TODO: panic message");
-        self.motor
-            .relative_move(10000.0)
+            .velocity_move(velocity)
             .await
             .expect("Failed to update");
         loop {
+            let held = self.hold_while_paused(&id).await;
+            if held > Duration::ZERO {
+                init_time += held;
+                self.motor
+                    .velocity_move(velocity)
+                    .await
+                    .expect("Failed to resume");
+            }
+            if self.is_aborted() {
+                self.motor.abrupt_stop().await.expect("Failed to stop");
+                println!("[{id}] Dispense aborted");
+                break;
+            }
             let curr_time = Instant::now();
-            if curr_time - init_time > parameters.timeout.unwrap() {
+            if curr_time - init_time > parameters.timeout() {
                 self.motor.abrupt_stop().await.expect("Failed to stop");
                 break;
             }
-            (scale, reading) = self.read_scale(scale).await;
+            (scale, reading) = self.weigh(scale).await;
             curr_weight = filter_a * reading + filter_b * curr_weight;
 
             times.push(curr_time - init_time);
             weights.push(curr_weight);
-
-            if curr_time - last_sent_motor > send_command_delay {
-                last_sent_motor = Instant::now();
-                self.motor
-                    .relative_move(10000.0)
-                    .await
-                    .expect("Failed to update");
-            }
         }
+        self.retract(&parameters, parameters.retract_after).await;
 
         let (scale, final_weight) = self
-            .read_scale_median(scale, Duration::from_secs(3), 200)
+            .read_settled(scale, settle_time, settle_rate, parameters.check_estimator)
             .await;
-        println!("Dispensed: {:.1} g", init_weight - final_weight);
-        scale
+        // An abort during the final read falls back to the last filtered weight
+        let final_weight = final_weight.unwrap_or(curr_weight);
+        println!("[{id}] Dispensed: {:.1} g", init_weight - final_weight);
+        let report = DispenseReport {
+            id,
+            times,
+            weights,
+            dispensed: init_weight - final_weight,
+            timeout: parameters.timeout(),
+            aborted: self.is_aborted(),
+            ..Default::default()
+        };
+        (scale, report)
     }
     pub async fn actor(
         &self,
         phidget_id: i32,
         mut rx: Receiver<NodeCommand>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.serve(phidget_id, &mut rx).await
+    }
+
+    // Same as `actor` for a scale that isn't a local Phidget, e.g. one served from another host
+    pub async fn actor_with_source(
+        &self,
+        source: Box<dyn WeightSource>,
+        mut rx: Receiver<NodeCommand>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.serve_with(source, &mut rx).await
+    }
+
+    async fn serve(
+        &self,
+        phidget_id: i32,
+        rx: &mut Receiver<NodeCommand>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let mut scale = self.connect_scale(Scale::new(phidget_id)).await;
         scale = Scale::change_coefficients(scale, vec![-5897877.72181665, 5263019.161459, -4005678.071311, 4000763.38549006]);
+        self.serve_with(scale, rx).await
+    }
+
+    async fn serve_with<S: WeightSource>(
+        &self,
+        mut scale: S,
+        rx: &mut Receiver<NodeCommand>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
         self.motor.enable().await.unwrap();
         while let Some(cmd) = rx.recv().await {
             match cmd {
                 NodeCommand::Dispense(p) => {
                     if p.serving_weight.is_some() {
-                        (scale, _, _) = self.dispense(scale, p).await;
+                        (scale, _) = self.dispense(scale, *p).await;
                     } else {
-                        scale = self.timed_dispense(scale, p).await;
+                        (scale, _) = self.timed_dispense(scale, *p).await;
                     }
                 }
                 NodeCommand::ReadScale(sender) => {
                     let weight: f64;
-                    (scale, weight) = self.read_scale(scale).await;
+                    (scale, weight) = self.weigh(scale).await;
                     sender.send(weight).unwrap();
                 }
+                NodeCommand::ReadRawReadings(sender) => {
+                    let readings: Result<Vec<f64>, String>;
+                    (scale, readings) = tokio::task::spawn_blocking(move || {
+                        let readings = scale.raw_readings().map_err(|e| e.to_string());
+                        (scale, readings)
+                    })
+                    .await
+                    .unwrap();
+                    // Dropping the sender tells the caller there are no readings
+                    match readings {
+                        Ok(readings) => sender.send(readings).unwrap(),
+                        Err(e) => println!("WARNING: {e}"),
+                    }
+                }
                 NodeCommand::ReadScaleMedian(sender) => {
                     let weight: f64;
                     (scale, weight) = self
@@ -270,6 +1414,9 @@ impl Node {
                         .await;
                     sender.send(weight).unwrap();
                 }
+                NodeCommand::ReadMotionStats(sender) => {
+                    sender.send(self.motor.motion_stats()).unwrap();
+                }
             }
         }
         Ok(())
@@ -277,9 +1424,438 @@ impl Node {
 }
 
 pub enum NodeCommand {
-    Dispense(DispensingParameters),
+    // Boxed so the other commands don't pay for the recipe's size in the channel
+    Dispense(Box<DispensingParameters>),
     ReadScale(oneshot::Sender<f64>),
     ReadScaleMedian(oneshot::Sender<f64>),
+    ReadRawReadings(oneshot::Sender<Vec<f64>>),
+    ReadMotionStats(oneshot::Sender<MotionStats>),
+}
+
+// Semi-automatic stations without a start button: queues `parameters` on the node actor each
+// time a container lands on the receiving scale. Feed it from a BagPresenceMonitor watching
+// that scale, its step range and window decide what counts as a stable container
+pub async fn dispense_on_placement(
+    mut placements: Receiver<BagEvent>,
+    node: Sender<NodeCommand>,
+    parameters: DispensingParameters,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    while let Some(event) = placements.recv().await {
+        if let BagEvent::Placed(step) = event {
+            println!("Container placed ({step:.1} g), starting dispense");
+            node.send(NodeCommand::Dispense(Box::new(parameters.clone())))
+                .await
+                .map_err(|_| "Node actor stopped")?;
+        }
+    }
+    Ok(())
+}
+
+impl TrackedSubsystem for Node {
+    fn status_tracker(&self) -> &StatusTracker {
+        &self.status
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    // None keeps restarting forever
+    pub max_restarts: Option<u32>,
+    pub backoff: Duration,
+}
+
+pub struct NodeSupervisor {
+    policy: RestartPolicy,
+    restarts: watch::Sender<u32>,
+}
+
+impl NodeSupervisor {
+    pub fn new(policy: RestartPolicy) -> Self {
+        let (restarts, _) = watch::channel(0);
+        Self { policy, restarts }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<u32> {
+        self.restarts.subscribe()
+    }
+
+    // Runs the node actor in its own task and respawns it if it panics or errors. Each restart
+    // builds a fresh node, so the actor's startup (scale connect, motor enable) runs again and
+    // queued commands are picked up where the failed task left off
+    pub async fn run<F>(
+        self,
+        make_node: F,
+        phidget_id: i32,
+        rx: Receiver<NodeCommand>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>
+    where
+        F: Fn() -> Node,
+    {
+        let rx = Arc::new(tokio::sync::Mutex::new(rx));
+        loop {
+            let node = make_node();
+            let rx = rx.clone();
+            let task = tokio::spawn(async move {
+                let mut rx = rx.lock_owned().await;
+                node.serve(phidget_id, &mut rx)
+                    .await
+                    .map_err(|e| e.to_string())
+            });
+            let cause = match task.await {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(e)) => e,
+                Err(e) if e.is_panic() => panic_message(e.into_panic()),
+                Err(e) => e.to_string(),
+            };
+            let restarts = *self.restarts.borrow() + 1;
+            println!("WARNING: Node actor failed ({cause}), restart {restarts}");
+            if self.policy.max_restarts.is_some_and(|max| restarts > max) {
+                let message = format!("Node actor gave up after {} restarts", restarts - 1);
+                return Err(format!("{message}: {cause}").into());
+            }
+            self.restarts.send_replace(restarts);
+            tokio::time::sleep(self.policy.backoff).await;
+        }
+    }
+}
+
+// Resolves once the receiver sees a new value, and never without one or once its sender is gone
+async fn changed<T>(receiver: &mut Option<watch::Receiver<T>>) {
+    if let Some(receiver) = receiver {
+        if receiver.changed().await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map_or("unknown panic".to_string(), |message| message.to_string()),
+    }
+}
+
+#[tokio::test]
+async fn test_panic_message() {
+    let task = tokio::spawn(async { panic!("Failed to weigh scale") });
+    let message = panic_message(task.await.unwrap_err().into_panic());
+    assert_eq!(message, "Failed to weigh scale");
+    let task = tokio::spawn(async { panic!("{} failed", "Motor") });
+    let message = panic_message(task.await.unwrap_err().into_panic());
+    assert_eq!(message, "Motor failed");
+}
+
+#[test]
+fn test_completion_criteria() {
+    let threshold = CompletionCriteria::ThresholdWithRecheck;
+    assert!(!threshold.should_stop(95., -20., 90., 2.));
+    assert!(threshold.should_stop(87., -20., 90., 2.));
+    assert!(threshold.is_complete(88., 90., 1.));
+    assert!(!threshold.is_complete(89.5, 90., 1.));
+
+    let stable = CompletionCriteria::StableWithinTolerance(3.);
+    assert!(stable.should_stop(92., -20., 90., 2.));
+    assert!(stable.is_complete(92.5, 90., 1.));
+    assert!(!stable.is_complete(94., 90., 1.));
+
+    let predicted = CompletionCriteria::PredictedCrossing(Duration::from_millis(500));
+    assert!(predicted.should_stop(95., -20., 90., 2.));
+    assert!(!predicted.should_stop(95., -5., 90., 2.));
+    assert!(!predicted.should_stop(95., 20., 90., 2.));
+}
+
+#[test]
+fn test_low_hopper_prime() {
+    let low_hopper = LowHopperPrime {
+        threshold: 200.,
+        fraction: 0.5,
+    };
+    assert_eq!(low_hopper.prime_time(500.), PRIME_TIME);
+    assert_eq!(low_hopper.prime_time(150.), Duration::from_millis(1500));
+    let skip = LowHopperPrime {
+        threshold: 200.,
+        fraction: 0.,
+    };
+    assert!(skip.prime_time(150.).is_zero());
+}
+
+#[test]
+fn test_feed_forward() {
+    let parameters =
+        DispensingParameters::with_weight(500., Duration::from_secs(10), 2., 50., 50., 0.5, 0.2);
+    assert_eq!(parameters.feed_speed(0.5), 1.);
+    assert_eq!(parameters.feed_speed(0.02), 0.04);
+
+    let parameters = parameters.with_feed_forward(FeedForward {
+        base_speed: 1.,
+        constant: 0.25,
+    });
+    assert_eq!(parameters.feed_speed(0.5), 1.75);
+    // The last grams still feed well above the 0.1 rev/s floor
+    assert!((parameters.feed_speed(0.02) - 0.31).abs() < 1e-9);
+    // Overshoot doesn't turn the base term negative
+    assert!((parameters.feed_speed(-0.01) - 0.23).abs() < 1e-9);
+}
+
+#[tokio::test]
+async fn test_catch_up_nudge() {
+    use crate::test_support::{dispense_parameters, TestBench};
+    let bench = TestBench::new();
+    // Lands just short on the first check and gains 0.4 g with every nudge
+    let hopper = bench.scale(100., 49., 50.5).with_feed(b"RM400", 0.4);
+    let nudge = CatchUpNudge {
+        distance: Revolutions(0.5),
+        speed: RevsPerSec(0.2),
+        max_nudges: 3,
+        settle: Duration::from_millis(10),
+    };
+    let parameters = dispense_parameters().with_catch_up_nudge(nudge);
+    let (_, report) = bench.node(0).dispense(hopper, parameters).await;
+    assert!(!report.timed_out);
+    assert_eq!(report.nudges, 2);
+    assert!((report.dispensed - 50.3).abs() < 1e-9);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_warm_up() {
+    use crate::test_support::{dispense_parameters, TestBench};
+    let bench = TestBench::new();
+    let warm_up = WarmUp {
+        speed: RevsPerSec(0.1),
+        duration: Duration::from_secs(2),
+    };
+    let parameters = dispense_parameters().with_warm_up(warm_up);
+    let start = Instant::now();
+    let hopper = bench.scale(100., 49., 49.5);
+    let (hopper, report) = bench.node(0).dispense(hopper, parameters).await;
+    assert!(!report.timed_out);
+    // What the motor had been told by the time the starting weight was read
+    let (read_at, seen) = hopper.settled_reads()[0];
+    assert!(read_at - start >= Duration::from_secs(2));
+    let commands = bench.controller().commands();
+    let sent: Vec<&[u8]> = commands[..seen].iter().map(|c| &c[1..]).collect();
+    let warm_up: [&[u8]; 3] = [b"M0JG80", b"M0ST", b"M0GS"];
+    assert_eq!(sent[..3], warm_up);
+    assert_eq!(sent[3..], [&b"M0JG-800"[..]]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dispense_trace() {
+    use crate::test_support::{dispense_parameters, TestBench};
+    let bench = TestBench::new();
+    let hopper = bench.scale(100., 49., 49.5);
+    let parameters = dispense_parameters().with_trace();
+    let (_, report) = bench.node(0).dispense(hopper, parameters).await;
+    assert_eq!(report.trace.len(), report.weights.len());
+    let first = report.trace[0];
+    assert_eq!(first.raw, 49.);
+    assert!(first.filtered < 100. && first.filtered > 49.);
+    assert_eq!(report.trace_csv().lines().count(), report.trace.len() + 1);
+
+    let report = DispenseReport {
+        trace: vec![DispenseSample {
+            time: Duration::from_millis(1500),
+            raw: 60.,
+            filtered: 61.,
+            feed_rate: -2.,
+            error: Some(0.25),
+            speed: None,
+        }],
+        ..Default::default()
+    };
+    assert_eq!(
+        report.trace_csv(),
+        "time_s,raw_g,filtered_g,feed_rate_gps,error,speed_rps\n1.5,60,61,-2,0.25,\n"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_scale_fallback() {
+    use crate::subsystems::status::SubsystemStatus;
+    use crate::test_support::{dispense_parameters, TestBench};
+    let bench = TestBench::new();
+    // Answers the starting read, then drops off the bus
+    let hopper = bench.scale(100., 49., 49.5).detached();
+    let node = bench
+        .node(0)
+        .with_scale_fallback(ScaleFallback {
+            max_feed_time: Duration::from_secs(30),
+        })
+        .with_flow_rate(FlowRate {
+            grams_per_sec: 10.,
+            motor_speed: RevsPerSec(0.5),
+        });
+    let start = Instant::now();
+    let (_, report) = node.dispense(hopper, dispense_parameters()).await;
+    assert!(report.scale_fallback);
+    assert!(!report.aborted);
+    assert!((report.dispensed - 50.).abs() < 0.5);
+    assert!(Instant::now() - start >= Duration::from_secs(5));
+    assert!(node.last_error().is_some());
+    let commands = bench.controller().commands();
+    assert!(commands.iter().any(|command| command.ends_with(b"JG400")));
+}
+
+#[tokio::test]
+async fn test_agitator_held_with_guard() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let controller = bench.controller().clone();
+    let (guard, guard_rx) = watch::channel(GuardState::Closed);
+    let agitator = Agitator::new(
+        AgitatorOutput::Digital(Output::new(5, bench.sender())),
+        Duration::from_millis(50),
+        Duration::ZERO,
+    );
+    let node = bench.node(0).with_guard(guard_rx).with_agitator(agitator);
+    // Never reaches the serving, so the dispense runs until its timeout. Live reads don't wait
+    // on the clock, so this one runs in real time
+    let hopper = bench.scale(100., 100., 100.);
+    let parameters =
+        DispensingParameters::with_weight(50., Duration::from_secs(1), 0.5, 50., 50., 0.5, 0.2);
+    let operator = async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(controller.output(5), 32700);
+        guard.send_replace(GuardState::Open);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(controller.output(5), 0);
+        guard.send_replace(GuardState::Closed);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(controller.output(5), 32700);
+    };
+    let ((_, report), ()) = tokio::join!(node.dispense(hopper, parameters), operator);
+    assert!(report.timed_out);
+    assert_eq!(controller.output(5), 0);
+}
+
+#[tokio::test]
+async fn test_timed_warm_up() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let warm_up = WarmUp {
+        speed: RevsPerSec(0.1),
+        duration: Duration::from_millis(500),
+    };
+    // Live reads don't wait on the clock, so this runs in real time
+    let parameters =
+        DispensingParameters::only_timeout(Duration::from_secs(1), 0.5, 50., 50., 0.5, 0.2)
+            .with_warm_up(warm_up);
+    let start = Instant::now();
+    let hopper = bench.scale(100., 99., 99.);
+    let (_, report) = bench.node(0).timed_dispense(hopper, parameters).await;
+    assert!(!report.aborted);
+    // The warm-up doesn't eat into the timed feed
+    assert!(start.elapsed() >= Duration::from_millis(1500));
+}
+
+#[tokio::test]
+async fn test_agitator_off_after_panic() {
+    use crate::test_support::{dispense_parameters, TestBench};
+    let bench = TestBench::new();
+    let agitator = Agitator::new(
+        AgitatorOutput::Digital(Output::new(5, bench.sender())),
+        Duration::from_secs(10),
+        Duration::ZERO,
+    );
+    let node = bench.node(0).with_agitator(agitator);
+    // Without a scale fallback, losing the scale panics the dispense
+    let hopper = bench.scale(100., 49., 49.5).detached();
+    let dispense = tokio::spawn(async move {
+        node.dispense(hopper, dispense_parameters()).await;
+    });
+    assert!(dispense.await.unwrap_err().is_panic());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let commands = bench.controller().commands();
+    assert!(commands.iter().any(|command| command.ends_with(b"O532700")));
+    assert_eq!(bench.controller().output(5), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_report_scale_info() {
+    use crate::components::scale::WeightUnit;
+    use crate::test_support::{dispense_parameters, TestBench};
+    let info = ScaleInfo {
+        serial: "716620".to_string(),
+        resolution: 0.1,
+        unit: WeightUnit::Grams,
+        last_calibrated: Some(UNIX_EPOCH + Duration::from_secs(1_790_000_000)),
+    };
+    let bench = TestBench::new();
+    let hopper: Box<dyn WeightSource> =
+        Box::new(bench.scale(100., 49., 49.5).with_info(info.clone()));
+    let (_, report) = bench.node(0).dispense(hopper, dispense_parameters()).await;
+    assert!(!report.timed_out);
+    assert_eq!(report.scale, Some(info));
+}
+
+#[tokio::test]
+async fn test_dispense_on_placement() {
+    let (events, placements) = tokio::sync::mpsc::channel(10);
+    let (node, mut commands) = tokio::sync::mpsc::channel(10);
+    let parameters =
+        DispensingParameters::with_weight(50., Duration::from_secs(30), 0.5, 50., 0.5, 5., 2.);
+    let auto_start = tokio::spawn(dispense_on_placement(placements, node, parameters));
+    events.send(BagEvent::Placed(30.)).await.unwrap();
+    events.send(BagEvent::Removed(80.)).await.unwrap();
+    events.send(BagEvent::Placed(31.)).await.unwrap();
+    drop(events);
+    auto_start.await.unwrap().unwrap();
+    let mut dispenses = 0;
+    while let Some(command) = commands.recv().await {
+        if let NodeCommand::Dispense(parameters) = command {
+            assert_eq!(parameters.serving_weight, Some(50.));
+            dispenses += 1;
+        }
+    }
+    assert_eq!(dispenses, 2);
+}
+
+#[test]
+fn test_feed_direction() {
+    assert_eq!(FeedDirection::Forward.feed(), Revolutions(10000.));
+    assert_eq!(FeedDirection::Forward.retract(), Revolutions(-10000.));
+    assert_eq!(FeedDirection::Reverse.feed(), Revolutions(-10000.));
+    assert_eq!(FeedDirection::Reverse.retract(), Revolutions(10000.));
+    assert_eq!(
+        FeedDirection::Forward.retract_by(Revolutions(2.)),
+        Revolutions(-2.)
+    );
+    assert_eq!(
+        FeedDirection::Reverse.retract_by(Revolutions(-2.)),
+        Revolutions(2.)
+    );
+}
+
+#[test]
+fn test_check_window() {
+    let parameters =
+        DispensingParameters::only_timeout(Duration::from_secs(1), 1., 50., 1., 0., 0.);
+    assert_eq!(parameters.check_window(), Duration::from_secs(2));
+    assert_eq!(parameters.settle_delay, None);
+    // Blanking only masks samples, it doesn't hold off the stop check
+    let parameters = parameters.with_vibration_blanking(Duration::from_millis(300));
+    assert_eq!(parameters.settle_delay, None);
+    let parameters = parameters.with_check_window(25, Duration::from_millis(200));
+    assert_eq!(parameters.check_window(), Duration::from_millis(500));
+    assert_eq!(parameters.settle_delay, Some(Duration::from_millis(200)));
+}
+
+#[test]
+fn test_settle_window() {
+    let parameters =
+        DispensingParameters::only_timeout(Duration::from_secs(1), 1., 50., 1., 0., 0.);
+    assert_eq!(
+        parameters.settle_window(TIMED_SETTLE_RATE),
+        (Duration::from_secs(3), 200)
+    );
+    let parameters = parameters.with_settle_window(Duration::from_millis(500), 100);
+    assert_eq!(
+        parameters.settle_window(WEIGHED_SETTLE_RATE),
+        (Duration::from_millis(500), 100)
+    );
 }
 
 #[tokio::test]
@@ -0,0 +1,124 @@
+use crate::components::analog_source::AnalogSource;
+use crate::components::clear_core_io::AnalogInput;
+use std::error::Error;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PressureState {
+    Ok,
+    Low,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PneumaticsEvent {
+    LowPressure(f64),
+    Recovered(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PressureLimits {
+    // Pressure (after scaling) below which operations are inhibited
+    pub low: f64,
+    // Pressure that must be reached again before operations are allowed to resume
+    pub recovery: f64,
+}
+
+impl PressureLimits {
+    fn next_state(&self, state: PressureState, pressure: f64) -> PressureState {
+        match state {
+            PressureState::Ok if pressure < self.low => PressureState::Low,
+            PressureState::Low if pressure >= self.recovery => PressureState::Ok,
+            _ => state,
+        }
+    }
+}
+
+pub struct PneumaticsMonitor<F: AnalogSource = AnalogInput> {
+    sensor: F,
+    gain: f64,
+    offset: f64,
+    limits: PressureLimits,
+    interval: Duration,
+    state: watch::Sender<PressureState>,
+    events: Option<mpsc::Sender<PneumaticsEvent>>,
+}
+
+impl<F: AnalogSource + Sync> PneumaticsMonitor<F> {
+    pub fn new(
+        sensor: F,
+        gain: f64,
+        offset: f64,
+        limits: PressureLimits,
+        interval: Duration,
+    ) -> Self {
+        let (state, _) = watch::channel(PressureState::Low);
+        Self {
+            sensor,
+            gain,
+            offset,
+            limits,
+            interval,
+            state,
+            events: None,
+        }
+    }
+
+    pub fn with_events(mut self, events: mpsc::Sender<PneumaticsEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<PressureState> {
+        self.state.subscribe()
+    }
+
+    pub async fn get_pressure(&self) -> Result<f64, Box<dyn Error>> {
+        let raw = self.sensor.get_value().await?;
+        Ok(raw as f64 * self.gain + self.offset)
+    }
+
+    pub async fn run(self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            // A failed read inhibits operations just like low pressure would
+            let pressure = self.get_pressure().await.unwrap_or(f64::NEG_INFINITY);
+            let current = *self.state.borrow();
+            let next = self.limits.next_state(current, pressure);
+            if next != current {
+                let event = match next {
+                    PressureState::Low => {
+                        println!("WARNING: Air pressure low ({pressure:.1}), actuators inhibited");
+                        PneumaticsEvent::LowPressure(pressure)
+                    }
+                    PressureState::Ok => PneumaticsEvent::Recovered(pressure),
+                };
+                self.state.send_replace(next);
+                if let Some(events) = &self.events {
+                    events.send(event).await?;
+                }
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+#[test]
+fn test_pressure_hysteresis() {
+    let limits = PressureLimits {
+        low: 80.,
+        recovery: 90.,
+    };
+    assert_eq!(limits.next_state(PressureState::Ok, 85.), PressureState::Ok);
+    assert_eq!(
+        limits.next_state(PressureState::Ok, 79.),
+        PressureState::Low
+    );
+    assert_eq!(
+        limits.next_state(PressureState::Low, 85.),
+        PressureState::Low
+    );
+    assert_eq!(
+        limits.next_state(PressureState::Low, 90.),
+        PressureState::Ok
+    );
+}
@@ -0,0 +1,159 @@
+use crate::components::clear_core_io::{Output, OutputState};
+use crate::components::scale::CancelToken;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// How often an abort is checked while waiting for the next step
+const ABORT_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceStep {
+    // Offset from the start of the sequence
+    pub at: Duration,
+    pub output: String,
+    pub state: OutputState,
+}
+
+// Timed table of output changes run as one operation, e.g. blower on at 0 ms, air jet on at
+// 300 ms and off at 350 ms, blower off at 800 ms. Steps run in time order; steps at the same
+// time run in the order they were added
+pub struct Sequencer {
+    outputs: Vec<(String, Output)>,
+    steps: Vec<SequenceStep>,
+    abort: Option<CancelToken>,
+}
+
+impl Sequencer {
+    pub fn new() -> Self {
+        Self {
+            outputs: Vec::new(),
+            steps: Vec::new(),
+            abort: None,
+        }
+    }
+
+    pub fn with_output(mut self, name: &str, output: Output) -> Self {
+        self.outputs.push((name.to_string(), output));
+        self
+    }
+
+    pub fn with_step(mut self, at: Duration, output: &str, state: OutputState) -> Self {
+        self.steps.push(SequenceStep {
+            at,
+            output: output.to_string(),
+            state,
+        });
+        self.steps.sort_by_key(|step| step.at);
+        self
+    }
+
+    // Cancelling the token stops the sequence between steps and switches every output off
+    pub fn with_abort(mut self, abort: CancelToken) -> Self {
+        self.abort = Some(abort);
+        self
+    }
+
+    pub fn steps(&self) -> &[SequenceStep] {
+        self.steps.as_slice()
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.steps.last().map_or(Duration::ZERO, |step| step.at)
+    }
+
+    fn output(&self, name: &str) -> Result<&Output, Box<dyn Error>> {
+        self.outputs
+            .iter()
+            .find(|(output, _)| output == name)
+            .map(|(_, output)| output)
+            .ok_or_else(|| Box::from(format!("Sequence has no output named {name}")))
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.abort.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    // On an abort or a failed step every output is switched off before returning the error
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        for step in self.steps.iter() {
+            self.output(step.output.as_str())?;
+        }
+        if let Err(e) = self.run_steps().await.map_err(|e| e.to_string()) {
+            self.all_off().await;
+            return Err(Box::from(e));
+        }
+        Ok(())
+    }
+
+    async fn run_steps(&self) -> Result<(), Box<dyn Error>> {
+        let start = Instant::now();
+        for step in self.steps.iter() {
+            let due = start + step.at;
+            while Instant::now() < due {
+                if self.is_aborted() {
+                    return Err(Box::from("Sequence aborted"));
+                }
+                tokio::time::sleep_until(due.min(Instant::now() + ABORT_POLL)).await;
+            }
+            if self.is_aborted() {
+                return Err(Box::from("Sequence aborted"));
+            }
+            self.output(step.output.as_str())?
+                .set_state(step.state)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn all_off(&self) {
+        for (name, output) in self.outputs.iter() {
+            if let Err(e) = output.set_state(OutputState::Off).await {
+                println!("WARNING: Failed to switch off {name}: {e}");
+            }
+        }
+    }
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_sequencer() {
+    use crate::test_support::TestBench;
+    let bench = TestBench::new();
+    let ms = Duration::from_millis;
+    let sequence = |abort: CancelToken| {
+        Sequencer::new()
+            .with_output("blower", Output::new(2, bench.sender()))
+            .with_output("air jet", Output::new(3, bench.sender()))
+            .with_step(ms(800), "blower", OutputState::Off)
+            .with_step(ms(0), "blower", OutputState::On)
+            .with_step(ms(300), "air jet", OutputState::On)
+            .with_step(ms(350), "air jet", OutputState::Off)
+            .with_abort(abort)
+    };
+
+    let abort = CancelToken::new();
+    let sequencer = sequence(abort.clone());
+    assert_eq!(sequencer.duration(), ms(800));
+    let start = Instant::now();
+    sequencer.run().await.unwrap();
+    assert_eq!(Instant::now() - start, ms(800));
+    let commands = bench.controller().commands();
+    let sent: Vec<&[u8]> = commands.iter().map(|command| &command[1..]).collect();
+    assert_eq!(sent, [&b"O232700"[..], b"O332700", b"O30", b"O20"]);
+
+    let sequencer = sequence(abort.clone());
+    let (result, ()) = tokio::join!(sequencer.run(), async {
+        tokio::time::sleep(ms(320)).await;
+        assert_eq!(bench.controller().output(3), 32700);
+        abort.cancel();
+    });
+    assert_eq!(result.unwrap_err().to_string(), "Sequence aborted");
+    assert_eq!(bench.controller().output(2), 0);
+    assert_eq!(bench.controller().output(3), 0);
+}
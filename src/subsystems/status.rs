@@ -0,0 +1,246 @@
+use std::error::Error;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubsystemState {
+    Idle,
+    Busy,
+    Faulted,
+    Unknown,
+}
+
+pub trait SubsystemStatus {
+    fn name(&self) -> String;
+    fn state(&self) -> SubsystemState;
+    fn last_error(&self) -> Option<String>;
+    fn is_busy(&self) -> bool {
+        self.state() == SubsystemState::Busy
+    }
+}
+
+// Subsystems that report through a StatusTracker get SubsystemStatus from it
+pub trait TrackedSubsystem {
+    fn status_tracker(&self) -> &StatusTracker;
+}
+
+impl<T: TrackedSubsystem> SubsystemStatus for T {
+    fn name(&self) -> String {
+        self.status_tracker().name.clone()
+    }
+
+    fn state(&self) -> SubsystemState {
+        self.status_tracker().tracked.lock().unwrap().state
+    }
+
+    fn last_error(&self) -> Option<String> {
+        self.status_tracker()
+            .tracked
+            .lock()
+            .unwrap()
+            .last_error
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Tracked {
+    state: SubsystemState,
+    last_error: Option<String>,
+}
+
+// Shared status cell for subsystems that are moved into tasks or actors. Keep a clone for
+// the status page and hand the other to the subsystem
+#[derive(Debug, Clone)]
+pub struct StatusTracker {
+    name: String,
+    tracked: Arc<Mutex<Tracked>>,
+}
+
+impl StatusTracker {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            tracked: Arc::new(Mutex::new(Tracked {
+                state: SubsystemState::Idle,
+                last_error: None,
+            })),
+        }
+    }
+
+    pub fn set_state(&self, state: SubsystemState) {
+        self.tracked.lock().unwrap().state = state;
+    }
+
+    // The error sticks around after the subsystem recovers so the HMI can still show it
+    pub fn fault(&self, error: &str) {
+        let mut tracked = self.tracked.lock().unwrap();
+        tracked.state = SubsystemState::Faulted;
+        tracked.last_error = Some(error.to_string());
+    }
+
+    // Records a problem without faulting, for degraded running the line should carry on through
+    pub fn warn(&self, error: &str) {
+        self.tracked.lock().unwrap().last_error = Some(error.to_string());
+    }
+
+    pub fn clear_error(&self) {
+        self.tracked.lock().unwrap().last_error = None;
+    }
+
+    // Busy until the guard is finished. A guard dropped without finishing, by a panic or a
+    // cancelled future, faults the subsystem instead of leaving it Busy for good
+    pub fn busy(&self) -> BusyGuard {
+        self.set_state(SubsystemState::Busy);
+        BusyGuard {
+            status: self.clone(),
+            finished: false,
+        }
+    }
+
+    // Busy while the operation runs, then Idle or Faulted depending on how it ended
+    pub async fn run<T>(
+        &self,
+        operation: impl Future<Output = Result<T, Box<dyn Error>>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let busy = self.busy();
+        let result = operation.await;
+        match &result {
+            Ok(_) => busy.idle(),
+            Err(e) => busy.fault(&e.to_string()),
+        }
+        result
+    }
+}
+
+impl TrackedSubsystem for StatusTracker {
+    fn status_tracker(&self) -> &StatusTracker {
+        self
+    }
+}
+
+#[must_use]
+pub struct BusyGuard {
+    status: StatusTracker,
+    finished: bool,
+}
+
+impl BusyGuard {
+    pub fn idle(mut self) {
+        self.status.set_state(SubsystemState::Idle);
+        self.finished = true;
+    }
+
+    pub fn fault(mut self, error: &str) {
+        self.status.fault(error);
+        self.finished = true;
+    }
+
+    pub fn warn(&self, error: &str) {
+        self.status.warn(error);
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if std::thread::panicking() {
+            self.status.fault("Panicked while busy");
+        } else {
+            self.status.fault("Stopped while busy");
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusEntry {
+    pub name: String,
+    pub state: SubsystemState,
+    pub last_error: Option<String>,
+    pub busy: bool,
+}
+
+// One snapshot of every subsystem, backing the HMI status page
+#[derive(Debug, Clone)]
+pub struct MachineStatus {
+    pub collected_at: Instant,
+    pub subsystems: Vec<StatusEntry>,
+}
+
+impl MachineStatus {
+    pub fn collect(subsystems: &[&dyn SubsystemStatus]) -> Self {
+        Self {
+            collected_at: Instant::now(),
+            subsystems: subsystems
+                .iter()
+                .map(|subsystem| StatusEntry {
+                    name: subsystem.name(),
+                    state: subsystem.state(),
+                    last_error: subsystem.last_error(),
+                    busy: subsystem.is_busy(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.subsystems.iter().any(|entry| entry.busy)
+    }
+
+    pub fn faulted(&self) -> Vec<&StatusEntry> {
+        self.subsystems
+            .iter()
+            .filter(|entry| entry.state == SubsystemState::Faulted)
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn test_machine_status() {
+    let gantry = StatusTracker::new("gantry");
+    let sealer = StatusTracker::new("sealer");
+    let _ = sealer
+        .run(async { Err::<(), _>(Box::from("Heater timed out")) })
+        .await;
+    gantry.set_state(SubsystemState::Busy);
+    let status = MachineStatus::collect(&[&gantry, &sealer]);
+    assert!(status.is_busy());
+    assert_eq!(status.subsystems[0].state, SubsystemState::Busy);
+    let faulted = status.faulted();
+    assert_eq!(faulted.len(), 1);
+    assert_eq!(faulted[0].name, "sealer");
+    assert_eq!(faulted[0].last_error.as_deref(), Some("Heater timed out"));
+
+    sealer.run(async { Ok(()) }).await.unwrap();
+    assert_eq!(sealer.state(), SubsystemState::Idle);
+    assert!(sealer.last_error().is_some());
+}
+
+#[tokio::test]
+async fn test_busy_guard() {
+    let status = StatusTracker::new("dispenser");
+    let tracker = status.clone();
+    let panicked = tokio::spawn(async move {
+        let _busy = tracker.busy();
+        panic!("Motor reply missing");
+    })
+    .await;
+    assert!(panicked.is_err());
+    assert_eq!(status.state(), SubsystemState::Faulted);
+    assert_eq!(status.last_error().as_deref(), Some("Panicked while busy"));
+
+    let cancelled = tokio::time::timeout(
+        std::time::Duration::from_millis(10),
+        status.run(std::future::pending::<Result<(), Box<dyn Error>>>()),
+    )
+    .await;
+    assert!(cancelled.is_err());
+    assert_eq!(status.state(), SubsystemState::Faulted);
+    assert_eq!(status.last_error().as_deref(), Some("Stopped while busy"));
+
+    status.busy().idle();
+    assert_eq!(status.state(), SubsystemState::Idle);
+}
@@ -0,0 +1,785 @@
+use crate::components::clear_core_motor::ClearCoreMotor;
+use crate::components::scale::{CancelToken, ScaleInfo, WeightEstimator, WeightSource};
+use crate::controllers::clear_core::{Message, CR, STX};
+use crate::interface::command_log::{self, Exchange};
+use crate::subsystems::gantry::{tracked_gantry, GantryCommand};
+use crate::subsystems::hatch::Hatch;
+use crate::subsystems::linear_actuator::RelayHBridge;
+use crate::subsystems::node::{DispensingParameters, Node};
+use crate::subsystems::status::StatusTracker;
+use crate::util::utils::{ascii_to_int, num_to_bytes};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+#[derive(Debug, Default)]
+struct SimulatedMotor {
+    enabled: bool,
+    jogging: bool,
+    position: isize,
+    velocity: isize,
+    // Fastest velocity the drive accepts, faster requests are clamped to it
+    max_velocity: Option<isize>,
+    // HLFB torque in tenths of a percent, unset drives report ASG
+    torque: Option<isize>,
+    // Alert register; CA clears it, except a drive fault, which needs enable cycled
+    alerts: isize,
+}
+
+// Feedback input that follows a relay pair: it moves by `step` counts on every read while the
+// first output is on, and by -`step` while the second one is
+#[derive(Debug, Clone, Copy)]
+struct ActuatorLink {
+    outputs: (u8, u8),
+    feedback: u8,
+    step: isize,
+}
+
+// Feedback input that follows a relay pair over time: it moves at `rate` counts/s while the
+// first output is on, and at -`rate` while the second one is
+#[derive(Debug, Clone, Copy)]
+struct TimedActuatorLink {
+    outputs: (u8, u8),
+    feedback: u8,
+    rate: f64,
+    // Travel not yet reflected in the whole-count input
+    travel: f64,
+}
+
+// 1 while only the first output of the pair is on, -1 while only the second one is
+fn drive(outputs: &HashMap<u8, isize>, pair: (u8, u8)) -> isize {
+    let output = |id| outputs.get(&id).copied().unwrap_or(0);
+    match (output(pair.0), output(pair.1)) {
+        (0, 0) => 0,
+        (_, 0) => 1,
+        (0, _) => -1,
+        _ => 0,
+    }
+}
+
+// MotorFaulted bit of the alert register
+const MOTOR_FAULTED: isize = 1 << 5;
+
+// Rejection frame for injected NAKs. It is shorter than any command prefix, so every request
+// fails to parse it
+const NAK: &[u8] = &[2, b'?', CR];
+
+// Link degradation for a SimulatedController. Rules match the frame without its STX by
+// prefix, e.g. b"M0GS" for status reads of motor 0, b"M" for every motor command or b"" for
+// everything; the last matching rule wins
+#[derive(Debug)]
+struct Faults {
+    latency: Vec<(Vec<u8>, (Duration, Duration))>,
+    naks: Vec<(Vec<u8>, f64)>,
+    outages: Vec<(Instant, Instant)>,
+    // xorshift64 state, seeded so a failing test replays the same way
+    rng: u64,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            latency: Vec::new(),
+            naks: Vec::new(),
+            outages: Vec::new(),
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl Faults {
+    fn matching<'a, T>(rules: &'a [(Vec<u8>, T)], frame: &[u8]) -> Option<&'a T> {
+        rules
+            .iter()
+            .rev()
+            .find(|(command, _)| frame.starts_with(command))
+            .map(|(_, rule)| rule)
+    }
+
+    // Uniform in [0, 1)
+    fn next_unit(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn latency(&mut self, frame: &[u8]) -> Duration {
+        match Self::matching(&self.latency, frame).copied() {
+            Some((min, max)) => min + (max - min).mul_f64(self.next_unit()),
+            None => Duration::ZERO,
+        }
+    }
+
+    fn nak(&mut self, frame: &[u8]) -> bool {
+        let probability = Self::matching(&self.naks, frame).copied().unwrap_or(0.);
+        probability > 0. && self.next_unit() < probability
+    }
+
+    fn link_down(&self, now: Instant) -> bool {
+        self.outages
+            .iter()
+            .any(|&(start, end)| start <= now && now < end)
+    }
+}
+
+enum Answer {
+    Reply(Vec<u8>, Duration),
+    // Link down, the message is dropped unanswered
+    Dropped,
+}
+
+#[derive(Debug, Default)]
+struct SimulatedState {
+    motors: HashMap<u8, SimulatedMotor>,
+    inputs: HashMap<u8, isize>,
+    outputs: HashMap<u8, isize>,
+    analog_outputs: HashMap<u8, isize>,
+    links: Vec<ActuatorLink>,
+    timed_links: Vec<TimedActuatorLink>,
+    // When the timed links were last moved on
+    links_advanced: Option<Instant>,
+    commands: Vec<Vec<u8>>,
+    faults: Faults,
+}
+
+impl SimulatedState {
+    // Moves timed links on by the time since the last message, under the outputs set until now
+    fn advance_links(&mut self, now: Instant) {
+        let elapsed = self
+            .links_advanced
+            .map_or(0., |advanced| (now - advanced).as_secs_f64());
+        self.links_advanced = Some(now);
+        for link in self.timed_links.iter_mut() {
+            link.travel += drive(&self.outputs, link.outputs) as f64 * link.rate * elapsed;
+            let whole = link.travel.trunc();
+            link.travel -= whole;
+            *self.inputs.entry(link.feedback).or_default() += whole as isize;
+        }
+    }
+
+    fn respond(&mut self, buffer: &[u8]) -> Answer {
+        if self.faults.link_down(Instant::now()) {
+            return Answer::Dropped;
+        }
+        self.advance_links(Instant::now());
+        let frame = buffer.strip_prefix(&[STX]).unwrap_or(buffer);
+        let latency = self.faults.latency(frame);
+        // A rejected command is never applied
+        if self.faults.nak(frame) {
+            let mut reply = NAK.to_vec();
+            reply.resize(100, 0);
+            return Answer::Reply(reply, latency);
+        }
+        Answer::Reply(self.answer(buffer), latency)
+    }
+
+    fn answer(&mut self, buffer: &[u8]) -> Vec<u8> {
+        let end = buffer.iter().position(|&b| b == CR).unwrap_or(buffer.len());
+        self.commands.push(buffer[..end].to_vec());
+        let (prefix, body) = buffer[..end].split_at(3.min(end));
+        let id = prefix.get(2).map_or(0, |id| id.wrapping_sub(48));
+        let value = |bytes: &[u8]| {
+            if bytes.iter().any(u8::is_ascii_digit) {
+                ascii_to_int(bytes)
+            } else {
+                0
+            }
+        };
+        let payload = match prefix.get(1) {
+            Some(b'M') => {
+                let motor = self.motors.entry(id).or_default();
+                let (mnemonic, arg) = body.split_at(2.min(body.len()));
+                match mnemonic {
+                    b"EN" => motor.enabled = true,
+                    b"DE" => {
+                        motor.enabled = false;
+                        motor.alerts &= !MOTOR_FAULTED;
+                    }
+                    b"CA" => motor.alerts &= MOTOR_FAULTED,
+                    // A positional move takes over from a jog, as on the drive
+                    b"AM" => {
+                        motor.position = value(arg);
+                        motor.jogging = false;
+                    }
+                    b"SP" => motor.position = value(arg),
+                    b"RM" => {
+                        motor.position += value(arg);
+                        motor.jogging = false;
+                    }
+                    b"SV" => {
+                        let max = motor.max_velocity.unwrap_or(isize::MAX);
+                        motor.velocity = value(arg).min(max);
+                    }
+                    b"JG" => {
+                        let max = motor.max_velocity.unwrap_or(isize::MAX);
+                        motor.velocity = value(arg).clamp(-max, max);
+                        motor.jogging = true;
+                    }
+                    b"ST" | b"AS" => motor.jogging = false,
+                    _ => {}
+                }
+                match mnemonic {
+                    b"GS" if !motor.enabled => b"0".to_vec(),
+                    b"GS" if motor.alerts != 0 => b"2".to_vec(),
+                    b"GS" if motor.jogging => b"4".to_vec(),
+                    b"GS" => b"3".to_vec(),
+                    b"GP" => num_to_bytes(motor.position),
+                    b"GV" => num_to_bytes(motor.velocity),
+                    b"GA" => num_to_bytes(motor.alerts),
+                    b"GH" => match motor.torque {
+                        Some(tenths) => [&b"2"[..], &num_to_bytes(tenths)].concat(),
+                        None if motor.enabled => b"1".to_vec(),
+                        None => b"0".to_vec(),
+                    },
+                    _ => arg.to_vec(),
+                }
+            }
+            Some(b'I') => {
+                for link in self.links.clone() {
+                    if link.feedback != id {
+                        continue;
+                    }
+                    let step = drive(&self.outputs, link.outputs) * link.step;
+                    *self.inputs.entry(id).or_default() += step;
+                }
+                num_to_bytes(self.inputs.get(&id).copied().unwrap_or(0))
+            }
+            Some(b'O') if body == b"GS" => {
+                num_to_bytes(self.outputs.get(&id).copied().unwrap_or(0))
+            }
+            Some(b'O') => {
+                self.outputs.insert(id, value(body));
+                body.to_vec()
+            }
+            Some(b'A') => {
+                self.analog_outputs.insert(id, value(body));
+                body.to_vec()
+            }
+            _ => b"0".to_vec(),
+        };
+        let mut reply = prefix.to_vec();
+        reply.extend_from_slice(payload.as_slice());
+        reply.push(CR);
+        reply.resize(100, 0);
+        reply
+    }
+}
+
+// A replayed exchange the simulator answered differently from the recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayMismatch {
+    pub exchange: Exchange,
+    // Cut after the CR like the recorded reply; None if the link was down and it was dropped
+    pub reply: Option<Vec<u8>>,
+}
+
+// Answers ClearCore messages from memory. Motor moves complete instantly, jogs run until
+// stopped, and inputs hold whatever the test sets
+#[derive(Clone, Default)]
+pub struct SimulatedController {
+    state: Arc<Mutex<SimulatedState>>,
+}
+
+impl SimulatedController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Spawns the task answering the returned sender, it ends once every sender is dropped
+    pub fn spawn(&self) -> mpsc::Sender<Message> {
+        let (tx, mut rx) = mpsc::channel::<Message>(100);
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                let answer = state.lock().unwrap().respond(message.buffer.as_slice());
+                // Replies go out in order, so a slow one holds up those queued behind it
+                if let Answer::Reply(reply, latency) = answer {
+                    tokio::time::sleep(latency).await;
+                    let _ = message.response.send(reply);
+                }
+            }
+        });
+        tx
+    }
+
+    // Sends the commands of a recorded session, see `command_log::recorder`, through the
+    // simulated state and fault model on their recorded timeline, and returns the exchanges
+    // whose replies came out different
+    pub async fn replay_from<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Vec<ReplayMismatch>, Box<dyn Error + Send + Sync>> {
+        Ok(self.replay(command_log::load(path).await?).await)
+    }
+
+    pub async fn replay(&self, exchanges: Vec<Exchange>) -> Vec<ReplayMismatch> {
+        let start = Instant::now();
+        let mut mismatches = Vec::new();
+        for exchange in exchanges {
+            tokio::time::sleep_until(start + exchange.elapsed).await;
+            let answer = self.state.lock().unwrap().respond(&exchange.command);
+            let reply = match answer {
+                Answer::Reply(mut reply, latency) => {
+                    tokio::time::sleep(latency).await;
+                    let end = reply
+                        .iter()
+                        .position(|&b| b == CR)
+                        .map_or(reply.len(), |cr| cr + 1);
+                    reply.truncate(end);
+                    Some(reply)
+                }
+                Answer::Dropped => None,
+            };
+            if reply.as_ref() != Some(&exchange.reply) {
+                mismatches.push(ReplayMismatch { exchange, reply });
+            }
+        }
+        mismatches
+    }
+
+    pub fn set_input(&self, id: u8, value: isize) {
+        self.state.lock().unwrap().inputs.insert(id, value);
+    }
+
+    pub fn input(&self, id: u8) -> isize {
+        let state = self.state.lock().unwrap();
+        state.inputs.get(&id).copied().unwrap_or(0)
+    }
+
+    pub fn output(&self, id: u8) -> isize {
+        let state = self.state.lock().unwrap();
+        state.outputs.get(&id).copied().unwrap_or(0)
+    }
+
+    pub fn analog_output(&self, id: u8) -> isize {
+        let state = self.state.lock().unwrap();
+        state.analog_outputs.get(&id).copied().unwrap_or(0)
+    }
+
+    // Raw position in counts, divide by the motor scale for revs
+    pub fn motor_position(&self, id: u8) -> isize {
+        let state = self.state.lock().unwrap();
+        state.motors.get(&id).map_or(0, |motor| motor.position)
+    }
+
+    // Velocity in counts/s above which the motor silently clamps, like a drive at its limit
+    pub fn clamp_velocity(&self, id: u8, max: isize) {
+        let mut state = self.state.lock().unwrap();
+        state.motors.entry(id).or_default().max_velocity = Some(max);
+    }
+
+    // Raises bits of the motor's alert register, which also reports it faulted
+    pub fn set_alerts(&self, id: u8, register: isize) {
+        let mut state = self.state.lock().unwrap();
+        state.motors.entry(id).or_default().alerts |= register;
+    }
+
+    // Switches the motor's HLFB to reporting torque, in percent of peak
+    pub fn set_torque(&self, id: u8, percent: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.motors.entry(id).or_default().torque = Some((percent * 10.).round() as isize);
+    }
+
+    pub fn link_actuator(&self, outputs: (u8, u8), feedback: u8, step: isize) {
+        self.state.lock().unwrap().links.push(ActuatorLink {
+            outputs,
+            feedback,
+            step,
+        });
+    }
+
+    // Like `link_actuator`, but the feedback moves with the time the relays are on rather than
+    // with each read, for tests of how long things take
+    pub fn link_actuator_at(&self, outputs: (u8, u8), feedback: u8, rate: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.advance_links(Instant::now());
+        state.timed_links.push(TimedActuatorLink {
+            outputs,
+            feedback,
+            rate,
+            travel: 0.,
+        });
+    }
+
+    // Each reply to a matching command is held back by a uniformly drawn delay in [min, max]
+    pub fn set_latency(&self, command: &[u8], min: Duration, max: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .faults
+            .latency
+            .push((command.to_vec(), (min, max.max(min))));
+    }
+
+    // Matching commands are rejected with a NAK, and not applied, with this probability
+    pub fn set_nak_probability(&self, command: &[u8], probability: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.faults.naks.push((command.to_vec(), probability));
+    }
+
+    pub fn seed_faults(&self, seed: u64) {
+        // xorshift never leaves zero
+        self.state.lock().unwrap().faults.rng = seed.max(1);
+    }
+
+    // Drops the link `after` from now for `down_for`. Messages sent meanwhile go unanswered,
+    // so their senders see the reply channel close
+    pub fn schedule_disconnect(&self, after: Duration, down_for: Duration) {
+        let start = Instant::now() + after;
+        let mut state = self.state.lock().unwrap();
+        state.faults.outages.push((start, start + down_for));
+    }
+
+    // Every command received so far, without the trailing CR
+    pub fn commands(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().commands.clone()
+    }
+}
+
+// Disturbance scheduled on a SimulatedScale, see `SimulatedScale::with_fault`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleFault {
+    // Grams added from then on, e.g. a tool left on the hopper
+    Step(f64),
+    // Grams added for a while, e.g. someone leaning on the frame
+    Spike { grams: f64, duration: Duration },
+    // Grams per second added from then on, like load cell creep
+    Drift(f64),
+    // Reads fail for a while, like the Phidget dropping off the bus and coming back
+    Dropout(Duration),
+}
+
+// Loss-in-weight hopper for dispenses against a TestBench. The first settled read returns the
+// starting weight and later ones the finishing weight, less `feed` grams for every matching
+// command the controller has seen by then; live reads return `live`
+pub struct SimulatedScale {
+    controller: SimulatedController,
+    start: f64,
+    live: Result<f64, String>,
+    settled: f64,
+    feed: Option<(Vec<u8>, f64)>,
+    info: Option<ScaleInfo>,
+    // When each settled read was taken and how many commands the controller had seen by then
+    reads: Vec<(Instant, usize)>,
+    created: Instant,
+    faults: Vec<(Instant, ScaleFault)>,
+}
+
+impl SimulatedScale {
+    // Live reads fail from the start, like a Phidget dropping off the bus
+    pub fn detached(mut self) -> Self {
+        self.live = Err("Phidget detached".to_string());
+        self
+    }
+
+    pub fn with_feed(mut self, command: &[u8], grams: f64) -> Self {
+        self.feed = Some((command.to_vec(), grams));
+        self
+    }
+
+    pub fn with_info(mut self, info: ScaleInfo) -> Self {
+        self.info = Some(info);
+        self
+    }
+
+    // Applies `fault` to live and settled reads from `after` the scale was created on. Under a
+    // paused clock the timing is exact, so tests stay deterministic
+    pub fn with_fault(mut self, after: Duration, fault: ScaleFault) -> Self {
+        self.faults.push((self.created + after, fault));
+        self
+    }
+
+    pub fn settled_reads(&self) -> &[(Instant, usize)] {
+        &self.reads
+    }
+
+    fn disturb(&self, mut weight: f64) -> Result<f64, Box<dyn Error>> {
+        let now = Instant::now();
+        for (start, fault) in self.faults.iter().filter(|(start, _)| now >= *start) {
+            let elapsed = now - *start;
+            match *fault {
+                ScaleFault::Step(grams) => weight += grams,
+                ScaleFault::Spike { grams, duration } if elapsed < duration => weight += grams,
+                ScaleFault::Drift(rate) => weight += rate * elapsed.as_secs_f64(),
+                ScaleFault::Dropout(duration) if elapsed < duration => {
+                    return Err(Box::from("Phidget dropped out"));
+                }
+                _ => {}
+            }
+        }
+        Ok(weight)
+    }
+}
+
+impl WeightSource for SimulatedScale {
+    fn live_weight(&mut self) -> Result<f64, Box<dyn Error>> {
+        let live = self.live.clone()?;
+        self.disturb(live)
+    }
+
+    fn settled_weight(
+        &mut self,
+        _: Duration,
+        _: usize,
+        _: WeightEstimator,
+        _: &CancelToken,
+    ) -> Result<Option<f64>, Box<dyn Error>> {
+        let commands = self.controller.commands();
+        self.reads.push((Instant::now(), commands.len()));
+        if self.reads.len() == 1 {
+            return Ok(Some(self.disturb(self.start)?));
+        }
+        let fed = self.feed.as_ref().map_or(0., |(command, grams)| {
+            let matching = commands.iter().filter(|c| c.ends_with(command)).count();
+            grams * matching as f64
+        });
+        Ok(Some(self.disturb(self.settled - fed)?))
+    }
+
+    fn info(&self) -> Option<ScaleInfo> {
+        self.info.clone()
+    }
+}
+
+// 50 g serving at half a rev/s with single sample checks, so a SimulatedScale settles at once
+pub fn dispense_parameters() -> DispensingParameters {
+    DispensingParameters::with_weight(50., Duration::from_secs(10), 0.5, 50., 50., 0.5, 0.2)
+        .with_check_window(1, Duration::ZERO)
+}
+
+// Hatch feedback falls while opening, matching `Hatch::open` stopping below its set point
+const HATCH_STEP: isize = -100;
+
+// Wires subsystems to one simulated controller in a single call
+pub struct TestBench {
+    controller: SimulatedController,
+    sender: mpsc::Sender<Message>,
+}
+
+impl TestBench {
+    pub fn new() -> Self {
+        let controller = SimulatedController::new();
+        let sender = controller.spawn();
+        Self { controller, sender }
+    }
+
+    pub fn controller(&self) -> &SimulatedController {
+        &self.controller
+    }
+
+    pub fn sender(&self) -> mpsc::Sender<Message> {
+        self.sender.clone()
+    }
+
+    pub fn motor(&self, id: u8, scale: isize) -> ClearCoreMotor {
+        ClearCoreMotor::new(id, scale, self.sender.clone())
+    }
+
+    pub fn node(&self, motor_id: u8) -> Node {
+        Node::new(self.motor(motor_id, 800))
+    }
+
+    // Settles at `settled` g after starting at `start`, with live reads at `live`
+    pub fn scale(&self, start: f64, live: f64, settled: f64) -> SimulatedScale {
+        SimulatedScale {
+            controller: self.controller.clone(),
+            start,
+            live: Ok(live),
+            settled,
+            feed: None,
+            info: None,
+            reads: Vec::new(),
+            created: Instant::now(),
+            faults: Vec::new(),
+        }
+    }
+
+    // Starts closed at `closed` counts; each feedback read while driven moves it 100 counts
+    pub fn hatch(
+        &self,
+        outputs: (u8, u8),
+        feedback: u8,
+        closed: isize,
+        timeout: Duration,
+    ) -> Hatch<RelayHBridge> {
+        self.controller.set_input(feedback, closed);
+        self.controller.link_actuator(outputs, feedback, HATCH_STEP);
+        let actuator = RelayHBridge::new(self.sender.clone(), outputs, feedback)
+            .with_dead_time(Duration::ZERO);
+        Hatch::new(actuator, timeout)
+    }
+
+    pub fn gantry(&self, motor_id: u8) -> (mpsc::Sender<GantryCommand>, StatusTracker) {
+        let (tx, rx) = mpsc::channel(10);
+        let status = StatusTracker::new("gantry");
+        tokio::spawn(tracked_gantry(
+            self.motor(motor_id, 800),
+            rx,
+            status.clone(),
+        ));
+        (tx, status)
+    }
+}
+
+impl Default for TestBench {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[tokio::test]
+async fn test_bench_gantry_and_hatch() {
+    use crate::subsystems::hatch::HatchState;
+    use crate::subsystems::status::{SubsystemState, SubsystemStatus};
+    use crate::util::units::{Revolutions, RevsPerSec};
+    use tokio::sync::oneshot;
+    let bench = TestBench::new();
+    let (gantry, status) = bench.gantry(0);
+    gantry
+        .send(GantryCommand::GoTo(Revolutions(24.5)))
+        .await
+        .unwrap();
+    let (tx, rx) = oneshot::channel();
+    gantry.send(GantryCommand::GetPosition(tx)).await.unwrap();
+    assert_eq!(rx.await.unwrap(), Revolutions(24.5));
+    assert_eq!(bench.controller().motor_position(0), 19600);
+    assert_eq!(status.state(), SubsystemState::Idle);
+    let home = GantryCommand::Home {
+        sensor: None,
+        velocity: RevsPerSec(10.),
+        distance: Revolutions(-100.),
+    };
+    gantry.send(home).await.unwrap();
+    let (tx, rx) = oneshot::channel();
+    gantry.send(GantryCommand::GetPosition(tx)).await.unwrap();
+    assert_eq!(rx.await.unwrap(), Revolutions(0.));
+
+    let hatch = bench.hatch((2, 3), 4, 3000, Duration::from_secs(5));
+    hatch.open(1000).await.unwrap();
+    assert_eq!(hatch.subscribe().borrow().state, HatchState::Open);
+    assert!(bench.controller().input(4) < 1000);
+    assert_eq!(bench.controller().output(2), 0);
+    hatch.close(2500).await.unwrap();
+    assert_eq!(hatch.subscribe().borrow().state, HatchState::Closed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_fault_injection() {
+    use crate::util::units::Revolutions;
+    let bench = TestBench::new();
+    let controller = bench.controller();
+    let motor = bench.motor(0, 800);
+    controller.set_latency(
+        b"M0GS",
+        Duration::from_millis(10),
+        Duration::from_millis(20),
+    );
+    let start = Instant::now();
+    motor.get_status().await.unwrap();
+    let latency = Instant::now() - start;
+    assert!(latency >= Duration::from_millis(10) && latency <= Duration::from_millis(20));
+    let start = Instant::now();
+    motor.get_position().await.unwrap();
+    assert_eq!(Instant::now() - start, Duration::ZERO);
+
+    controller.set_nak_probability(b"M0", 1.);
+    assert!(motor.relative_move(Revolutions(1.)).await.is_err());
+    controller.set_nak_probability(b"M0", 0.);
+    assert_eq!(controller.motor_position(0), 0);
+
+    controller.seed_faults(7);
+    controller.set_nak_probability(b"", 0.5);
+    let mut naks = 0;
+    for _ in 0..100 {
+        naks += usize::from(motor.get_position().await.is_err());
+    }
+    assert!((30..70).contains(&naks));
+    controller.set_nak_probability(b"", 0.);
+
+    controller.schedule_disconnect(Duration::from_millis(100), Duration::from_secs(1));
+    motor.get_position().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(motor.get_position().await.is_err());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    motor.get_position().await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_scale_faults() {
+    let bench = TestBench::new();
+    let window = Duration::ZERO;
+    let cancel = CancelToken::new();
+    let mut scale = bench
+        .scale(100., 50., 49.)
+        .with_fault(Duration::from_secs(1), ScaleFault::Step(2.))
+        .with_fault(
+            Duration::from_secs(2),
+            ScaleFault::Spike {
+                grams: 5.,
+                duration: Duration::from_millis(500),
+            },
+        )
+        .with_fault(
+            Duration::from_secs(3),
+            ScaleFault::Dropout(Duration::from_secs(1)),
+        )
+        .with_fault(Duration::from_secs(5), ScaleFault::Drift(0.5));
+    let settled = |scale: &mut SimulatedScale| {
+        scale
+            .settled_weight(window, 1, WeightEstimator::Median, &cancel)
+            .map(Option::unwrap)
+    };
+    assert_eq!(scale.live_weight().unwrap(), 50.);
+    assert_eq!(settled(&mut scale).unwrap(), 100.);
+
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert_eq!(scale.live_weight().unwrap(), 52.);
+    assert_eq!(settled(&mut scale).unwrap(), 51.);
+
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert_eq!(scale.live_weight().unwrap(), 57.);
+    tokio::time::advance(Duration::from_millis(500)).await;
+    assert_eq!(scale.live_weight().unwrap(), 52.);
+
+    tokio::time::advance(Duration::from_millis(500)).await;
+    assert!(scale.live_weight().is_err());
+    assert!(settled(&mut scale).is_err());
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert_eq!(scale.live_weight().unwrap(), 52.);
+
+    tokio::time::advance(Duration::from_secs(3)).await;
+    assert_eq!(scale.live_weight().unwrap(), 53.);
+    assert_eq!(settled(&mut scale).unwrap(), 52.);
+}
+
+#[tokio::test]
+async fn test_replay_recorded_session() {
+    use crate::util::units::Revolutions;
+    let path = std::env::temp_dir().join(format!("replay_{}.bin", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let recorded = SimulatedController::new();
+    let (tx, rx) = mpsc::channel::<Message>(10);
+    let recording = tokio::spawn(command_log::recorder(path.clone(), rx, recorded.spawn()));
+    let motor = ClearCoreMotor::new(0, 800, tx);
+    motor.relative_move(Revolutions(1.)).await.unwrap();
+    assert_eq!(motor.get_position().await.unwrap(), Revolutions(1.));
+    drop(motor);
+    recording.await.unwrap().unwrap();
+
+    let replayed = SimulatedController::new();
+    assert!(replayed.replay_from(&path).await.unwrap().is_empty());
+    assert_eq!(replayed.motor_position(0), 800);
+
+    // The same session on a link that rejects every motor command
+    let faulty = SimulatedController::new();
+    faulty.set_nak_probability(b"M0", 1.);
+    let mismatches = faulty.replay_from(&path).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(mismatches.len(), replayed.commands().len());
+    assert!(mismatches.iter().all(|m| m.reply.as_deref() == Some(NAK)));
+    assert_eq!(faulty.motor_position(0), 0);
+}
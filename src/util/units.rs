@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+use std::time::Duration;
+
+// Motor units. Motion APIs take these instead of bare f64s so a position can't be passed where
+// a speed is expected; wrap raw values explicitly, e.g. `Revolutions(2.)`
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Revolutions(pub f64);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RevsPerSec(pub f64);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RevsPerSec2(pub f64);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RevsPerSec3(pub f64);
+
+impl Revolutions {
+    // `scale` is the motor's counts per rev, as given to ClearCoreMotor::new
+    pub fn from_counts(counts: isize, scale: isize) -> Self {
+        Self(counts as f64 / scale as f64)
+    }
+
+    pub fn to_counts(self, scale: isize) -> isize {
+        (self.0 * scale as f64).trunc() as isize
+    }
+
+    pub fn from_degrees(degrees: f64) -> Self {
+        Self(degrees / 360.)
+    }
+
+    pub fn degrees(self) -> f64 {
+        self.0 * 360.
+    }
+
+    // Time to cover this distance at a constant speed, ignoring the ramps
+    pub fn at(self, speed: RevsPerSec) -> Duration {
+        Duration::from_secs_f64((self.0 / speed.0).abs())
+    }
+}
+
+impl RevsPerSec {
+    pub fn from_rpm(rpm: f64) -> Self {
+        Self(rpm / 60.)
+    }
+
+    pub fn rpm(self) -> f64 {
+        self.0 * 60.
+    }
+}
+
+macro_rules! unit {
+    ($unit:ident, $symbol:literal) => {
+        impl From<$unit> for f64 {
+            fn from(value: $unit) -> Self {
+                value.0
+            }
+        }
+
+        impl Add for $unit {
+            type Output = Self;
+
+            fn add(self, other: Self) -> Self {
+                Self(self.0 + other.0)
+            }
+        }
+
+        impl Sub for $unit {
+            type Output = Self;
+
+            fn sub(self, other: Self) -> Self {
+                Self(self.0 - other.0)
+            }
+        }
+
+        impl Neg for $unit {
+            type Output = Self;
+
+            fn neg(self) -> Self {
+                Self(-self.0)
+            }
+        }
+
+        impl Mul<f64> for $unit {
+            type Output = Self;
+
+            fn mul(self, factor: f64) -> Self {
+                Self(self.0 * factor)
+            }
+        }
+
+        impl fmt::Display for $unit {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)?;
+                f.write_str($symbol)
+            }
+        }
+    };
+}
+
+unit!(Revolutions, " rev");
+unit!(RevsPerSec, " rev/s");
+unit!(RevsPerSec2, " rev/s²");
+unit!(RevsPerSec3, " rev/s³");
+
+#[test]
+fn test_unit_conversions() {
+    assert_eq!(Revolutions::from_counts(1200, 800), Revolutions(1.5));
+    assert_eq!(Revolutions(1.5).to_counts(800), 1200);
+    assert_eq!(Revolutions::from_degrees(90.), Revolutions(0.25));
+    assert_eq!(RevsPerSec::from_rpm(120.).rpm(), 120.);
+    assert_eq!(
+        Revolutions(3.).at(RevsPerSec(2.)),
+        Duration::from_millis(1500)
+    );
+    assert_eq!(Revolutions(1.) - Revolutions(0.25) * 2., Revolutions(0.5));
+    assert_eq!(format!("{:.1}", RevsPerSec(2.)), "2.0 rev/s");
+    let speed = RevsPerSec(3.5);
+    assert_eq!(f64::from(speed), 3.5);
+}
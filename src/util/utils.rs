@@ -2,6 +2,10 @@ pub const fn make_prefix(device_type: u8, device_id: u8) -> [u8; 3] {
     [2, device_type, device_id + 48]
 }
 
+pub const fn make_ccio_prefix(board: u8, pin: u8) -> [u8; 4] {
+    [2, b'X', board + 48, pin + 48]
+}
+
 pub fn num_to_bytes<T: ToString>(number: T) -> Vec<u8> {
     number.to_string().chars().map(|c| c as u8).collect()
 }
@@ -31,6 +35,12 @@ fn test_make_prefix() {
     assert_eq!(prefix, [2, 77, 50]);
 }
 
+#[test]
+fn test_make_ccio_prefix() {
+    let prefix = make_ccio_prefix(1, 7);
+    assert_eq!(prefix, [2, b'X', 49, 55]);
+}
+
 #[test]
 fn test_int_to_bytes() {
     let bytes = num_to_bytes(2300);